@@ -0,0 +1,176 @@
+// compares our ThreadPool with the one from the book, where every worker takes
+// its jobs from a single Arc<Mutex<mpsc::Receiver>>
+//
+// run it with "cargo bench --bench pool"
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hello_server::ThreadPool;
+
+const THREADS: usize = 4;
+
+fn main() {
+    println!("{} threads, jobs per second (higher is better)\n", THREADS);
+
+    bench("tiny jobs", 200_000, || {});
+    bench("long-running jobs", 400, || spin(Duration::from_millis(2)));
+
+    // a job that queues more jobs, like a handler fanning out work.
+    // the pools are leaked so that jobs can hold on to them, they live until we exit anyway
+    let nested = 2_000;
+    let pool: &'static ThreadPool = Box::leak(Box::new(ThreadPool::build(THREADS).unwrap()));
+    let old: &'static ChannelPool = Box::leak(Box::new(ChannelPool::new(THREADS)));
+    report(
+        "jobs queued by jobs",
+        nested * 100,
+        run(nested * 100, |done| {
+            for _ in 0..nested {
+                let done = Arc::clone(&done);
+                old.execute(move || {
+                    for _ in 0..100 {
+                        let done = Arc::clone(&done);
+                        old.execute(move || done.finish());
+                    }
+                });
+            }
+        }),
+        run(nested * 100, |done| {
+            for _ in 0..nested {
+                let done = Arc::clone(&done);
+                pool.execute(move || {
+                    for _ in 0..100 {
+                        let done = Arc::clone(&done);
+                        pool.execute(move || done.finish()).unwrap();
+                    }
+                })
+                .unwrap();
+            }
+        }),
+    );
+}
+
+/// runs `jobs` copies of `job` on both pools
+fn bench(name: &str, jobs: usize, job: fn()) {
+    let old = ChannelPool::new(THREADS);
+    let pool = ThreadPool::build(THREADS).unwrap();
+
+    let old_time = run(jobs, |done| {
+        for _ in 0..jobs {
+            let done = Arc::clone(&done);
+            old.execute(move || {
+                job();
+                done.finish();
+            });
+        }
+    });
+    let new_time = run(jobs, |done| {
+        for _ in 0..jobs {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                job();
+                done.finish();
+            })
+            .unwrap();
+        }
+    });
+
+    report(name, jobs, old_time, new_time);
+}
+
+/// times `queue` from the first job queued until the last one finished
+fn run(jobs: usize, queue: impl FnOnce(Arc<Countdown>)) -> Duration {
+    let (sender, receiver) = mpsc::channel();
+    let done = Arc::new(Countdown {
+        left: AtomicUsize::new(jobs),
+        sender: Mutex::new(sender),
+    });
+
+    let start = Instant::now();
+    queue(done);
+    receiver.recv().unwrap();
+    start.elapsed()
+}
+
+fn report(name: &str, jobs: usize, old: Duration, new: Duration) {
+    let per_second = |time: Duration| jobs as f64 / time.as_secs_f64();
+    println!("{}:", name);
+    println!("  single receiver: {:>12.0}", per_second(old));
+    println!("  work stealing:   {:>12.0}", per_second(new));
+    println!("  speedup:         {:>12.2}x\n", old.as_secs_f64() / new.as_secs_f64());
+}
+
+/// tells the benchmark when the last job is over
+struct Countdown {
+    left: AtomicUsize,
+    sender: Mutex<mpsc::Sender<()>>,
+}
+
+impl Countdown {
+    fn finish(&self) {
+        if self.left.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.sender.lock().unwrap().send(()).unwrap();
+        }
+    }
+}
+
+/// keeps the CPU busy, like a job doing real work would
+fn spin(time: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < time {
+        std::hint::spin_loop();
+    }
+}
+
+// the pool as the book builds it, minus the printing
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+enum Message {
+    NewJob(Job),
+    Terminate,
+}
+
+struct ChannelPool {
+    sender: mpsc::Sender<Message>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl ChannelPool {
+    fn new(size: usize) -> ChannelPool {
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver: Arc<Mutex<mpsc::Receiver<Message>>> = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let message = receiver.lock().unwrap().recv().unwrap();
+                    match message {
+                        Message::NewJob(job) => job(),
+                        Message::Terminate => break,
+                    }
+                })
+            })
+            .collect();
+
+        ChannelPool { sender, workers }
+    }
+
+    fn execute(&self, f: impl FnOnce() + Send + 'static) {
+        self.sender.send(Message::NewJob(Box::new(f))).unwrap();
+    }
+}
+
+impl Drop for ChannelPool {
+    fn drop(&mut self) {
+        for _ in &self.workers {
+            self.sender.send(Message::Terminate).unwrap();
+        }
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
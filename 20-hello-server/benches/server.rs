@@ -0,0 +1,111 @@
+// compares the two ways to run a Server: every connection keeps a worker while it's
+// open, or a couple of event loops wait on all of them and hand the workers requests
+//
+// run it with "cargo bench --bench server"
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hello_server::{
+    set_logger, ConnectionConfig, Handler, Level, Request, Response, Router, Server, StatusCode, StderrLogger,
+    ThreadPool,
+};
+
+const WORKERS: usize = 8;
+const CLIENTS: usize = 8;
+
+fn main() {
+    // every request would be an access log line otherwise
+    set_logger(StderrLogger::new(Level::Warn));
+    println!(
+        "{} workers, {} clients, requests per second (higher is better)\n",
+        WORKERS, CLIENTS
+    );
+
+    bench("keep-alive connections", 0, 2_000, false);
+    bench("a connection per request", 0, 200, true);
+    // the threads mode gives the idle connections the workers, until they time out
+    bench("with 64 idle connections open", 64, 500, false);
+}
+
+/// `CLIENTS` clients send `requests` each, while `idle` connections just stay open
+fn bench(name: &str, idle: usize, requests: usize, close: bool) {
+    let threads = run(false, idle, requests, close);
+    let event_loop = run(true, idle, requests, close);
+
+    let per_second = |time: Duration| (CLIENTS * requests) as f64 / time.as_secs_f64();
+    println!("{}:", name);
+    println!("  threads:    {:>12.0}", per_second(threads));
+    println!("  event loop: {:>12.0}", per_second(event_loop));
+    println!(
+        "  speedup:    {:>12.2}x\n",
+        threads.as_secs_f64() / event_loop.as_secs_f64()
+    );
+}
+
+fn run(event_loop: bool, idle: usize, requests: usize, close: bool) -> Duration {
+    let mut router: Router<Handler> = Router::new();
+    router.get("/", |_: &Request| Response::text(StatusCode::Ok, "hello"));
+
+    let config = ConnectionConfig {
+        idle_timeout: Duration::from_millis(500),
+        max_requests: usize::MAX,
+        ..ConnectionConfig::default()
+    };
+    let mut server = Server::bind("127.0.0.1:0", router).unwrap().config(config);
+    if event_loop {
+        server = server.event_loop(2);
+    }
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(&ThreadPool::new(WORKERS)));
+
+    let idle: Vec<TcpStream> = (0..idle).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    // give the server a moment to take them in before the clients show up
+    thread::sleep(Duration::from_millis(50));
+
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| thread::spawn(move || client(addr, requests, close)))
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    let time = start.elapsed();
+
+    drop(idle);
+    shutdown.shutdown();
+    running.join().unwrap().unwrap();
+    time
+}
+
+/// sends `requests` one after the other, on one connection or on a new one each time
+fn client(addr: SocketAddr, requests: usize, close: bool) {
+    let request: &[u8] = if close {
+        b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n"
+    } else {
+        b"GET / HTTP/1.1\r\n\r\n"
+    };
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    for i in 0..requests {
+        if close && i > 0 {
+            stream = TcpStream::connect(addr).unwrap();
+        }
+        stream.write_all(request).unwrap();
+        read_response(&mut stream);
+    }
+}
+
+/// reads a response up to the end of its "hello" body
+fn read_response(stream: &mut TcpStream) {
+    let mut out = Vec::new();
+    let mut buf = [0; 512];
+    while !out.ends_with(b"\r\n\r\nhello") {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "the server closed the connection");
+        out.extend_from_slice(&buf[..n]);
+    }
+}
@@ -0,0 +1,80 @@
+//! Base64 with the standard alphabet and `=` padding (RFC 4648, section 4).
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    // every 3 bytes become 4 characters of 6 bits each
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Returns `None` unless the input is padded base64 and nothing else.
+pub fn decode(input: &str) -> Option<Vec<u8>> {
+    let input = input.as_bytes();
+    if !input.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+
+    for (n, chunk) in input.chunks(4).enumerate() {
+        let last = n == input.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+
+        let mut bits = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let value = ALPHABET.iter().position(|&a| a == c)?;
+            bits = bits << 6 | value as u32;
+        }
+        bits <<= 6 * padding;
+
+        let bytes = bits.to_be_bytes();
+        out.extend_from_slice(&bytes[1..4 - padding]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (plain, encoded) in vectors {
+            assert_eq!(encoded, encode(plain.as_bytes()));
+            assert_eq!(Some(plain.as_bytes().to_vec()), decode(encoded));
+        }
+        assert_eq!("//79", encode(&[0xff, 0xfe, 0xfd]));
+    }
+
+    #[test]
+    fn rejects_what_is_not_base64() {
+        for input in ["Zg", "Zg=a", "Zg==Zm9v", "Z===", "Zm9v!A==", "Zm 9v"] {
+            assert_eq!(None, decode(input), "{}", input);
+        }
+    }
+}
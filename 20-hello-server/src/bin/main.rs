@@ -2,52 +2,255 @@
 // note this file is under src/bin/main.rs and we also have src/lib.rs
 // that means the primary create in our dir is the library
 
+use std::env;
 use std::fs;
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use std::io;
+use std::path::Path;
+use std::process;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use hello_server::ThreadPool;
+#[cfg(feature = "tls")]
+use hello_server::TlsAcceptor;
+use hello_server::{
+    log_message, set_logger, shutdown_on_signal, BodyError, CommandLine, Compression, Event, Form, FromForm, FromJson,
+    Handler, Hub, Json, JsonLogger, Level, LogFormat, Metrics, Next, PoolHandle, Request, Response, Router, Server,
+    ServerMode, StaticFiles, StatusCode, StderrLogger, ThreadPool, WebSocket,
+};
+
+const USAGE: &str = "\
+Usage: hello_server [options]
+
+Options:
+  --config <file>            read the settings from a file (or set HELLO_CONFIG)
+  --bind <addr>              the address to listen on [127.0.0.1:7878]
+  --threads <n>              the most worker threads [32]
+  --min-threads <n>          the worker threads kept when idle [4]
+  --queue-capacity <n>       the connections waiting for a worker [100]
+  --max-connections <n>      the open connections, more get a 503 [256]
+  --mode <mode>              threads, or event-loop to wait on idle connections without a worker [threads]
+  --root <dir>               the directory with the files we serve [.]
+  --log-level <level>        error, warn, info, debug or trace [info]
+  --log-format <format>      text or json [text]
+  --tls-cert <file>          serve HTTPS with this PEM certificate chain
+  --tls-key <file>           and this PEM private key (needs --features tls)
+  --check-config             print the settings we'd use, then exit
+  -h, --help                 print this, then exit
+
+Every setting also comes from a variable, like HELLO_BIND or HELLO_LOG_LEVEL.
+Flags win over variables, and variables win over the config file.
+";
+
+/// the threads waiting on connections in the event loop mode, they only read and hand out requests
+const EVENT_LOOPS: usize = 2;
 
 fn main() {
-    let listener =  TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+    let command_line = match CommandLine::parse(env::args().skip(1), |name| env::var(name).ok()) {
+        Ok(command_line) => command_line,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if command_line.help {
+        print!("{}", USAGE);
+        return;
+    }
+    let config = command_line.config;
+    if command_line.check_config {
+        print!("{}", config);
+        return;
+    }
 
-    for stream in listener.incoming() { // .take(4) {
-        let stream = stream.unwrap();
+    match config.log_format {
+        LogFormat::Text => set_logger(StderrLogger::new(config.log_level)),
+        LogFormat::Json => set_logger(JsonLogger::new(config.log_level, io::stderr())),
+    }
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    // each connection keeps a worker for as long as it's open, so when they
+    // come in bursts we grow up to `threads`, and shrink back when it's over
+    //
+    // when the queue is full, the accept loop waits too,
+    // and new clients pile up in the OS backlog instead of in our memory
+    let pool = ThreadPool::builder()
+        .min_threads(config.min_threads())
+        .max_threads(config.threads)
+        .keep_alive(Duration::from_secs(30))
+        .queue_capacity(config.queue_capacity)
+        .build()
+        .unwrap();
+    // the pool and the server both count what they do, and /metrics shows it
+    let metrics = Metrics::new();
+    metrics.watch(&pool);
+    // the async handlers hand their blocking work to a pool of its own, if they used the one
+    // above, a burst of them could take every worker and wait on jobs that never get one
+    let blocking = ThreadPool::new(4);
+    let server = Server::bind(&config.bind, routes(&config.root, &metrics, blocking.handle()))
+        .unwrap()
+        .max_connections(config.max_connections)
+        .metrics(metrics);
+    // in the event loop mode, a couple of threads wait on the open connections, and the
+    // workers above only get the requests
+    let server = match config.mode {
+        ServerMode::Threads => server,
+        ServerMode::EventLoop => server.event_loop(EVENT_LOOPS),
+    };
+    #[cfg(feature = "tls")]
+    let server = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => match TlsAcceptor::from_pem_files(cert, key) {
+            Ok(acceptor) => server.tls(acceptor),
+            Err(e) => {
+                eprintln!("tls: {}", e);
+                process::exit(2);
+            }
+        },
+        _ => server,
+    };
+
+    // ctrl+c stops accepting new connections, then we wait for the workers below
+    shutdown_on_signal(server.shutdown_handle()).unwrap();
+
+    server.run(&pool).unwrap();
+
+    let report = pool.shutdown(Duration::from_secs(10));
+    if !report.is_clean() {
+        log_message(
+            Level::Warn,
+            module_path!(),
+            format_args!("Workers {:?} were still busy, exiting anyway.", report.busy),
+        );
     }
+    // nobody is waiting on these anymore
+    blocking.shutdown(Duration::from_secs(1));
 
     // now execute "cargo run" and browse to http://127.0.0.1:7878 to get a page quickly
     // now execute "cargo run" and browse to http://127.0.0.1:7878/sleep to get a page after some seconds
+    // while "curl -N http://127.0.0.1:7878/events" tells us how far along it is
+    // a WebSocket client like "websocat ws://127.0.0.1:7878/echo" gets back whatever it sends
+    // "cargo run --features tls -- --tls-cert cert.pem --tls-key key.pem" serves it all over HTTPS
+    // "curl -d name=Ferris http://127.0.0.1:7878/hello" greets whoever is in the form, or in JSON
+    // with "curl -H 'Content-Type: application/json' -d '{\"name\": \"Ferris\"}' http://127.0.0.1:7878/hello"
+    // "curl -F file=@Cargo.toml http://127.0.0.1:7878/upload" tells us what it got
+    // "curl http://127.0.0.1:7878/metrics" shows how busy the workers are, in Prometheus' format
+    // "cargo run -- --mode event-loop" keeps thousands of idle connections open with a few workers
+    // and "cargo run -- --check-config" shows the settings we'd run with
+}
+
+/// `root` is where we look for the pages and files we serve, and `blocking` runs what the async handlers wait on
+fn routes(root: &Path, metrics: &Metrics, blocking: PoolHandle) -> Router<Handler> {
+    let pages = StaticFiles::new(root);
+    let hello_pages = pages.clone();
+    let sleep_pages = pages.clone();
+    // whoever listens on /events hears how the sleepers are doing
+    let progress = Hub::new(100);
+    let sleep_progress = progress.clone();
+    let not_found_page = root.join("400.html");
+
+    let mut router = Router::new();
+    router
+        .get("/", move |request: &Request| hello(&hello_pages, request))
+        .get(
+            "/sleep",
+            Handler::from_async(move |request| {
+                sleep(sleep_pages.clone(), sleep_progress.clone(), blocking.clone(), request)
+            }),
+        )
+        .post("/hello", greet)
+        // files go to disk as they come in, so they can be bigger than what we keep in memory
+        .post("/upload", Handler::new(upload).stream_body())
+        .get("/events", progress.handler())
+        .get("/echo", WebSocket::handler(echo))
+        .get("/metrics", metrics.clone().handler())
+        .get("/static/*path", pages.handler("path"))
+        .not_found(move |request: &Request| not_found(&not_found_page, request))
+        .wrap(response_time)
+        // text bodies go out compressed to the clients that can take it
+        .wrap(Compression::new());
+    router
+}
+
+/// middleware that tells the client how long we took, so the handlers don't have to
+fn response_time(request: &mut Request, next: Next<'_>) -> Response {
+    let start = Instant::now();
+    let response = next.run(request);
+    response.with_header("X-Response-Time", format!("{}us", start.elapsed().as_micros()))
+}
+
+fn hello(pages: &StaticFiles, request: &Request) -> Response {
+    pages.serve(request, "hello.html")
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 512];
-    stream.read(&mut buffer).unwrap();
+// the worker sleeps while the blocking pool does, see Handler::from_async
+async fn sleep(pages: StaticFiles, progress: Hub, blocking: PoolHandle, request: Request) -> Response {
+    for second in 1..=5 {
+        let nap = match blocking.spawn_blocking(|| thread::sleep(Duration::from_secs(1))) {
+            Ok(nap) => nap,
+            Err(_) => return Response::text(StatusCode::ServiceUnavailable, "shutting down, try again later"),
+        };
+        // a thread::sleep can't panic, so it's done either way
+        let _ = nap.await;
+        progress.publish(Event::new(format!("{}/5 seconds", second)).with_event("progress"));
+    }
+    pages.serve(&request, "hello.html")
+}
+
+/// what POST /hello needs, from a form or from JSON
+struct Greeting {
+    name: String,
+}
 
-    // to debug request details
-    // println!("Request: {}", String::from_utf8_lossy(&buffer[..]));
+impl FromForm for Greeting {
+    fn from_form(form: &Form) -> Result<Greeting, BodyError> {
+        Ok(Greeting {
+            name: form.value("name")?,
+        })
+    }
+}
 
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
+impl FromJson for Greeting {
+    fn from_json(json: &Json) -> Result<Greeting, BodyError> {
+        Ok(Greeting {
+            name: json.field("name")?,
+        })
+    }
+}
 
-    let (status, filename) = if buffer.starts_with(get) {
-        ("200 OK", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        ("200 OK", "hello.html")
-    } else {
-        ("400 BAD REQUEST", "400.html")
+fn greet(request: &Request) -> Response {
+    // JSON when it says so, and a form otherwise, so anything else gets a 415 from `form`
+    let is_json = request.header("Content-Type").is_some_and(|t| t.starts_with("application/json"));
+    let greeting = if is_json { request.json::<Greeting>() } else { request.form::<Greeting>() };
+    match greeting {
+        Ok(greeting) => Response::text(StatusCode::Ok, format!("Hello, {}!", greeting.name)),
+        Err(e) => e.into(),
+    }
+}
+
+/// lists the files it got, which are deleted again once we're done here.
+/// it streams the body, so the files can be up to `BodyLimits::max_file_bytes`
+fn upload(request: &Request) -> Response {
+    let multipart = match request.multipart() {
+        Ok(multipart) => multipart,
+        Err(e) => return e.into(),
     };
-    
-    let body = fs::read_to_string(filename).unwrap();
-    let response = format!("HTTP/1.1 {}\r\n\r\n{}", status, body);
-    
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    let mut out = String::new();
+    for file in &multipart.files {
+        out += &format!("{}: {} ({} bytes)\n", file.name, file.file_name, file.size);
+    }
+    Response::text(StatusCode::Ok, out)
+}
+
+/// sends every message back, until the client closes the connection
+fn echo(mut ws: WebSocket) {
+    while let Ok(Some(message)) = ws.recv() {
+        if ws.send(message).is_err() {
+            break;
+        }
+    }
+}
+
+fn not_found(page: &Path, _: &Request) -> Response {
+    match fs::read(page) {
+        Ok(body) => Response::html(StatusCode::NotFound, body),
+        Err(_) => Response::text(StatusCode::NotFound, "Not Found"),
+    }
 }
@@ -0,0 +1,300 @@
+//! Decoding request bodies into the types handlers want: forms, file uploads and JSON.
+//!
+//! Usually the server has read the whole body (up to `Limits::max_body_bytes`) by the time a
+//! handler runs. On routes that stream it (see `Handler::stream_body`) these read it from the
+//! client instead, which is how a multipart upload goes to disk without ever being all in memory.
+//! Whatever is wrong with a body comes back as a `BodyError`, which turns into the response to
+//! answer with.
+
+use std::borrow::Cow;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use crate::request::Request;
+use crate::response::{Response, StatusCode};
+
+mod form;
+mod json;
+mod multipart;
+
+pub use form::{Form, FromForm};
+pub use json::{FromJson, Json};
+pub use multipart::{Multipart, UploadedFile};
+
+/// How much of a body we're willing to decode, on top of the `Limits` the server reads it with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BodyLimits {
+    /// max size of a form or JSON body, or of all the text fields of a multipart one
+    pub max_bytes: usize,
+    /// max number of fields in a form, multipart or not
+    pub max_fields: usize,
+    /// how deep arrays and objects can nest in JSON
+    pub max_depth: usize,
+    /// max number of files in a multipart body
+    pub max_files: usize,
+    /// max size of each of those files
+    pub max_file_bytes: u64,
+    /// where uploaded files are written to
+    pub upload_dir: PathBuf,
+}
+
+impl Default for BodyLimits {
+    fn default() -> BodyLimits {
+        BodyLimits {
+            max_bytes: 64 * 1024,
+            max_fields: 100,
+            max_depth: 32,
+            max_files: 10,
+            max_file_bytes: 10 * 1024 * 1024,
+            upload_dir: env::temp_dir(),
+        }
+    }
+}
+
+impl BodyLimits {
+    /// Decodes an `application/x-www-form-urlencoded` body.
+    pub fn form<T: FromForm>(&self, request: &Request) -> Result<T, BodyError> {
+        expect_type(request, |media_type| media_type == "application/x-www-form-urlencoded")?;
+        let body = self.read_body(request, "the form is too large")?;
+        T::from_form(&form::decode(&body, self)?)
+    }
+
+    /// Decodes an `application/json` body, or one of the `+json` types.
+    pub fn json<T: FromJson>(&self, request: &Request) -> Result<T, BodyError> {
+        expect_type(request, |media_type| {
+            media_type == "application/json" || media_type.ends_with("+json")
+        })?;
+        let body = self.read_body(request, "the JSON is too large")?;
+        T::from_json(&json::decode(&body, self)?)
+    }
+
+    /// Decodes a `multipart/form-data` body, writing its files to `upload_dir` as they come.
+    ///
+    /// They only come from the client as we write them on routes that stream the body, see
+    /// `Handler::stream_body`. On the others, the body was in memory all along.
+    pub fn multipart(&self, request: &Request) -> Result<Multipart, BodyError> {
+        let content_type = expect_type(request, |media_type| media_type == "multipart/form-data")?;
+        let boundary = match parameter(content_type, "boundary") {
+            Some(boundary) if !boundary.is_empty() && boundary.len() <= 70 => boundary,
+            _ => {
+                return Err(BodyError::Invalid(
+                    "multipart body without a valid boundary".to_string(),
+                ))
+            }
+        };
+        multipart::decode(request.body_reader(), &boundary, self)
+    }
+
+    /// the whole body, unless it's over `max_bytes`
+    fn read_body<'a>(&self, request: &'a Request, too_large: &'static str) -> Result<Cow<'a, [u8]>, BodyError> {
+        if request.stream.is_none() {
+            if request.body.len() > self.max_bytes {
+                return Err(BodyError::TooLarge(too_large));
+            }
+            return Ok(Cow::Borrowed(&request.body));
+        }
+
+        let mut body = Vec::new();
+        request
+            .body_reader()
+            .take(self.max_bytes as u64 + 1)
+            .read_to_end(&mut body)
+            .map_err(client_error)?;
+        if body.len() > self.max_bytes {
+            return Err(BodyError::TooLarge(too_large));
+        }
+        Ok(Cow::Owned(body))
+    }
+}
+
+/// Everything that can be wrong with a body we were asked to decode.
+#[derive(Debug)]
+pub enum BodyError {
+    /// we don't decode bodies of this Content-Type here, it's empty when there was none
+    UnsupportedType(String),
+    /// the body goes over one of the `BodyLimits`
+    TooLarge(&'static str),
+    /// the body doesn't say what its Content-Type says, or lacks what the handler needs
+    Invalid(String),
+    /// the client took longer than `ConnectionConfig::body_timeout` to send the body
+    TimedOut,
+    /// we couldn't write an uploaded file
+    Io(io::Error),
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyError::UnsupportedType(media_type) if media_type.is_empty() => {
+                f.write_str("the body has no Content-Type")
+            }
+            BodyError::UnsupportedType(media_type) => write!(f, "unsupported Content-Type: {}", media_type),
+            BodyError::TooLarge(what) => f.write_str(what),
+            BodyError::Invalid(why) => f.write_str(why),
+            BodyError::TimedOut => f.write_str("the body took too long to come in"),
+            BodyError::Io(e) => write!(f, "i/o error: {}", e),
+        }
+    }
+}
+
+impl BodyError {
+    /// The status we should answer with: 415, 413, 400 or, when it's our fault, 500.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            BodyError::UnsupportedType(_) => StatusCode::UnsupportedMediaType,
+            BodyError::TooLarge(_) => StatusCode::PayloadTooLarge,
+            BodyError::Invalid(_) => StatusCode::BadRequest,
+            BodyError::TimedOut => StatusCode::RequestTimeout,
+            BodyError::Io(_) => StatusCode::InternalServerError,
+        }
+    }
+}
+
+impl Error for BodyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BodyError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BodyError {
+    fn from(e: io::Error) -> BodyError {
+        BodyError::Io(e)
+    }
+}
+
+/// tells the client what was wrong, except for our own i/o errors
+impl From<BodyError> for Response {
+    fn from(e: BodyError) -> Response {
+        match e {
+            BodyError::Io(_) => Response::text(e.status(), e.status().reason()),
+            e => Response::text(e.status(), e.to_string()),
+        }
+    }
+}
+
+/// a streamed body fails to read when the client sends less than it said, or something broken,
+/// or takes too long; none of that is our fault
+fn client_error(e: io::Error) -> BodyError {
+    match e.kind() {
+        io::ErrorKind::TimedOut => BodyError::TimedOut,
+        _ => BodyError::Invalid(format!("couldn't read the body: {}", e)),
+    }
+}
+
+/// the whole Content-Type, once its media type passes `accepts`
+fn expect_type(request: &Request, accepts: impl Fn(&str) -> bool) -> Result<&str, BodyError> {
+    let content_type = request.header("Content-Type").unwrap_or("");
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    if accepts(&media_type) {
+        Ok(content_type)
+    } else {
+        Err(BodyError::UnsupportedType(media_type))
+    }
+}
+
+/// a parameter of a header like `multipart/form-data; boundary=xyz` or
+/// `form-data; name="file"; filename="a.txt"`, without its quotes
+fn parameter(header: &str, name: &str) -> Option<String> {
+    let mut rest = header.split_once(';')?.1;
+    loop {
+        let (key, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        // a quoted value goes up to the next quote, ';' and all; browsers send
+        // quotes in file names as %22, so there's nothing to unescape
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let (value, after) = quoted.split_once('"')?;
+                (value, after.split_once(';').map_or("", |(_, next)| next))
+            }
+            None => after.split_once(';').unwrap_or((after, "")),
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(value.trim().to_string());
+        }
+        rest = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{Method, StreamedBody};
+    use std::sync::{Arc, Mutex};
+
+    fn post(content_type: &str, body: &str) -> Request {
+        let mut request = Request::new(Method::Post, "/");
+        request.headers.insert("Content-Type", content_type);
+        request.body = body.as_bytes().to_vec();
+        request
+    }
+
+    #[test]
+    fn parameters() {
+        let header = "form-data; name=\"file\"; filename=\"a; b.txt\"";
+        assert_eq!(Some("file".to_string()), parameter(header, "name"));
+        assert_eq!(Some("a; b.txt".to_string()), parameter(header, "filename"));
+        assert_eq!(None, parameter(header, "size"));
+        assert_eq!(
+            Some("xyz".to_string()),
+            parameter("multipart/form-data; charset=utf-8; Boundary=xyz", "boundary")
+        );
+        assert_eq!(None, parameter("multipart/form-data", "boundary"));
+    }
+
+    #[test]
+    fn content_types_and_statuses() {
+        let limits = BodyLimits::default();
+
+        let e = limits.json::<Json>(&post("text/plain", "{}")).unwrap_err();
+        assert_eq!(StatusCode::UnsupportedMediaType, e.status());
+        assert_eq!("unsupported Content-Type: text/plain", e.to_string());
+        let e = limits.form::<Form>(&Request::new(Method::Post, "/")).unwrap_err();
+        assert_eq!("the body has no Content-Type", e.to_string());
+
+        // parameters and case don't matter
+        assert!(limits
+            .json::<Json>(&post("Application/JSON; charset=utf-8", "{}"))
+            .is_ok());
+        assert!(limits.json::<Json>(&post("application/merge-patch+json", "{}")).is_ok());
+
+        let small = BodyLimits {
+            max_bytes: 4,
+            ..BodyLimits::default()
+        };
+        let e = small
+            .form::<Form>(&post("application/x-www-form-urlencoded", "a=123"))
+            .unwrap_err();
+        assert_eq!(StatusCode::PayloadTooLarge, e.status());
+
+        let e = limits.multipart(&post("multipart/form-data", "")).unwrap_err();
+        assert_eq!(StatusCode::BadRequest, e.status());
+
+        let response = Response::from(BodyError::Io(io::Error::other("disk full")));
+        assert_eq!(StatusCode::InternalServerError, response.status);
+        assert_eq!(b"Internal Server Error".to_vec(), response.body);
+    }
+
+    #[test]
+    fn streamed_bodies() {
+        let streamed = |body: &'static str| {
+            let mut request = post("application/x-www-form-urlencoded", "");
+            request.stream = Some(StreamedBody::new(Arc::new(Mutex::new(body.as_bytes()))));
+            request
+        };
+        let small = BodyLimits {
+            max_bytes: 4,
+            ..BodyLimits::default()
+        };
+
+        let form: Form = small.form(&streamed("a=12")).unwrap();
+        assert_eq!(Some("12"), form.get("a"));
+        let e = small.form::<Form>(&streamed("a=123")).unwrap_err();
+        assert_eq!(StatusCode::PayloadTooLarge, e.status());
+    }
+}
@@ -0,0 +1,150 @@
+use std::str::{self, FromStr};
+
+use super::{BodyError, BodyLimits};
+use crate::url::percent_decode;
+
+/// The fields of a form, in the order they were sent.
+///
+/// A name can show up more than once, like with checkboxes or `<select multiple>`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+}
+
+impl Form {
+    pub fn new() -> Form {
+        Form::default()
+    }
+
+    /// The first value sent for `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.iter().filter(move |(n, _)| *n == name).map(|(_, value)| value)
+    }
+
+    /// The first value sent for `name` as a `T`, and a 400 when it's missing or doesn't parse.
+    pub fn value<T: FromStr>(&self, name: &str) -> Result<T, BodyError> {
+        self.optional(name)?
+            .ok_or_else(|| BodyError::Invalid(format!("the form has no {} field", name)))
+    }
+
+    /// Like `value`, for fields that may be left out.
+    pub fn optional<T: FromStr>(&self, name: &str) -> Result<Option<T>, BodyError> {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| BodyError::Invalid(format!("the {} field is not valid", name))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn push(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.fields.push((name.into(), value.into()));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// Types a form decodes into, see `Request::form`.
+///
+/// ```
+/// use hello_server::{BodyError, Form, FromForm};
+///
+/// struct Login {
+///     user: String,
+///     remember: bool,
+/// }
+///
+/// impl FromForm for Login {
+///     fn from_form(form: &Form) -> Result<Login, BodyError> {
+///         Ok(Login {
+///             user: form.value("user")?,
+///             // checkboxes are only sent when they're checked
+///             remember: form.get("remember").is_some(),
+///         })
+///     }
+/// }
+/// ```
+pub trait FromForm: Sized {
+    fn from_form(form: &Form) -> Result<Self, BodyError>;
+}
+
+impl FromForm for Form {
+    fn from_form(form: &Form) -> Result<Form, BodyError> {
+        Ok(form.clone())
+    }
+}
+
+/// decodes "name=Ferris&lang=en+us", where spaces can be '+' and everything else can be %XX
+pub(super) fn decode(body: &[u8], limits: &BodyLimits) -> Result<Form, BodyError> {
+    let body = str::from_utf8(body).map_err(|_| BodyError::Invalid("the form is not valid UTF-8".to_string()))?;
+    let invalid = || BodyError::Invalid("the form has a broken %-escape".to_string());
+
+    let mut form = Form::new();
+    for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+        if form.len() == limits.max_fields {
+            return Err(BodyError::TooLarge("the form has too many fields"));
+        }
+        // a field without '=' is there, but empty
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let name = percent_decode(&name.replace('+', " ")).ok_or_else(invalid)?;
+        let value = percent_decode(&value.replace('+', " ")).ok_or_else(invalid)?;
+        form.push(name, value);
+    }
+    Ok(form)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields() {
+        let form = decode(
+            b"name=Ferris+the+crab&tags=a%26b&tags=c&&empty&n=42",
+            &BodyLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(Some("Ferris the crab"), form.get("name"));
+        assert_eq!(vec!["a&b", "c"], form.get_all("tags").collect::<Vec<_>>());
+        assert_eq!(Some(""), form.get("empty"));
+        // the "&&" doesn't count
+        assert_eq!(5, form.len());
+
+        assert_eq!(42, form.value::<u32>("n").unwrap());
+        assert_eq!(None, form.optional::<u32>("age").unwrap());
+        assert_eq!(
+            "the form has no age field",
+            form.value::<u32>("age").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "the name field is not valid",
+            form.value::<u32>("name").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn bad_forms() {
+        let limits = BodyLimits {
+            max_fields: 2,
+            ..BodyLimits::default()
+        };
+        assert!(matches!(decode(b"a=1&b=2&c=3", &limits), Err(BodyError::TooLarge(_))));
+        assert!(matches!(decode(b"a=100%", &limits), Err(BodyError::Invalid(_))));
+        assert!(matches!(decode(b"a=\xff", &limits), Err(BodyError::Invalid(_))));
+    }
+}
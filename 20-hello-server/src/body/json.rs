@@ -0,0 +1,493 @@
+use std::str;
+
+use super::{BodyError, BodyLimits};
+
+/// A JSON value (RFC 8259).
+///
+/// Numbers are all `f64`, like in JavaScript, and objects keep their members in the order
+/// they were sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// The value of `key`, when this is an object that has it.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// The value of `key` as a `T`, and a 400 saying which key when it doesn't fit.
+    ///
+    /// A missing key reads as `null`, so it's fine for an `Option`.
+    pub fn field<T: FromJson>(&self, key: &str) -> Result<T, BodyError> {
+        if !matches!(self, Json::Object(_)) {
+            return Err(expected("an object", self));
+        }
+        T::from_json(self.get(key).unwrap_or(&Json::Null)).map_err(|e| match e {
+            BodyError::Invalid(why) => BodyError::Invalid(format!("{}: {}", key, why)),
+            e => e,
+        })
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Json::Null
+    }
+
+    /// what we call this value in error messages
+    fn describe(&self) -> String {
+        match self {
+            Json::Null => "null".to_string(),
+            Json::Bool(b) => b.to_string(),
+            Json::Number(n) => n.to_string(),
+            Json::String(_) => "a string".to_string(),
+            Json::Array(_) => "an array".to_string(),
+            Json::Object(_) => "an object".to_string(),
+        }
+    }
+}
+
+/// Types a JSON body decodes into, see `Request::json`.
+///
+/// ```
+/// use hello_server::{BodyError, FromJson, Json};
+///
+/// struct NewUser {
+///     name: String,
+///     age: Option<u8>,
+///     tags: Vec<String>,
+/// }
+///
+/// impl FromJson for NewUser {
+///     fn from_json(json: &Json) -> Result<NewUser, BodyError> {
+///         Ok(NewUser {
+///             name: json.field("name")?,
+///             age: json.field("age")?,
+///             tags: json.field::<Option<_>>("tags")?.unwrap_or_default(),
+///         })
+///     }
+/// }
+/// ```
+pub trait FromJson: Sized {
+    fn from_json(json: &Json) -> Result<Self, BodyError>;
+}
+
+fn expected(what: &str, json: &Json) -> BodyError {
+    BodyError::Invalid(format!("expected {}, not {}", what, json.describe()))
+}
+
+impl FromJson for Json {
+    fn from_json(json: &Json) -> Result<Json, BodyError> {
+        Ok(json.clone())
+    }
+}
+
+impl FromJson for bool {
+    fn from_json(json: &Json) -> Result<bool, BodyError> {
+        json.as_bool().ok_or_else(|| expected("a boolean", json))
+    }
+}
+
+impl FromJson for f64 {
+    fn from_json(json: &Json) -> Result<f64, BodyError> {
+        json.as_f64().ok_or_else(|| expected("a number", json))
+    }
+}
+
+impl FromJson for String {
+    fn from_json(json: &Json) -> Result<String, BodyError> {
+        json.as_str()
+            .map(str::to_string)
+            .ok_or_else(|| expected("a string", json))
+    }
+}
+
+/// whole numbers that fit the type, so 1.5 or 300 aren't a u8
+macro_rules! integers {
+    ($($t:ty),*) => {
+        $(
+            impl FromJson for $t {
+                fn from_json(json: &Json) -> Result<$t, BodyError> {
+                    match json {
+                        Json::Number(n) if n.fract() == 0.0 && *n >= <$t>::MIN as f64 && *n <= <$t>::MAX as f64 => {
+                            Ok(*n as $t)
+                        }
+                        _ => Err(expected(concat!("an integer (", stringify!($t), ")"), json)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+integers!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl<T: FromJson> FromJson for Option<T> {
+    fn from_json(json: &Json) -> Result<Option<T>, BodyError> {
+        match json {
+            Json::Null => Ok(None),
+            json => T::from_json(json).map(Some),
+        }
+    }
+}
+
+impl<T: FromJson> FromJson for Vec<T> {
+    fn from_json(json: &Json) -> Result<Vec<T>, BodyError> {
+        let items = json.as_array().ok_or_else(|| expected("an array", json))?;
+        items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| {
+                T::from_json(item).map_err(|e| match e {
+                    BodyError::Invalid(why) => BodyError::Invalid(format!("[{}]: {}", i, why)),
+                    e => e,
+                })
+            })
+            .collect()
+    }
+}
+
+pub(super) fn decode(body: &[u8], limits: &BodyLimits) -> Result<Json, BodyError> {
+    let text = str::from_utf8(body).map_err(|_| BodyError::Invalid("the JSON is not valid UTF-8".to_string()))?;
+    let mut parser = Parser {
+        input: text.as_bytes(),
+        pos: 0,
+        max_depth: limits.max_depth,
+    };
+    let json = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.pos < parser.input.len() {
+        return Err(parser.error("more after the value"));
+    }
+    Ok(json)
+}
+
+/// a recursive descent parser, one method per kind of value
+struct Parser<'a> {
+    /// valid UTF-8, so copying bytes out of strings keeps them valid
+    input: &'a [u8],
+    pos: usize,
+    max_depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, what: &str) -> BodyError {
+        BodyError::Invalid(format!("invalid JSON at byte {}: {}", self.pos, what))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, BodyError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => self.array(depth + 1),
+            Some(b'{') => self.object(depth + 1),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+
+    fn literal(&mut self, literal: &str, json: Json) -> Result<Json, BodyError> {
+        if self.input[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            Ok(json)
+        } else {
+            Err(self.error("unexpected character"))
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Json, BodyError> {
+        if depth > self.max_depth {
+            return Err(BodyError::Invalid("the JSON nests too deep".to_string()));
+        }
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value(depth)?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => continue,
+                Some(b']') => return Ok(Json::Array(items)),
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Json, BodyError> {
+        if depth > self.max_depth {
+            return Err(BodyError::Invalid("the JSON nests too deep".to_string()));
+        }
+        self.pos += 1;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if self.next() != Some(b':') {
+                return Err(self.error("expected ':'"));
+            }
+            members.push((key, self.value(depth)?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => continue,
+                Some(b'}') => return Ok(Json::Object(members)),
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, BodyError> {
+        // the opening quote
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            match self.next() {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let c = match self.next() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode()?,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                Some(b) if b < 0x20 => return Err(self.error("control character in a string")),
+                Some(b) => out.push(b),
+            }
+        }
+        String::from_utf8(out).map_err(|_| self.error("invalid UTF-8"))
+    }
+
+    /// the XXXX of a \uXXXX escape, and its other half when it's a surrogate pair
+    fn unicode(&mut self) -> Result<char, BodyError> {
+        let first = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&first) {
+            if !self.input[self.pos..].starts_with(b"\\u") {
+                return Err(self.error("unpaired surrogate"));
+            }
+            self.pos += 2;
+            let second = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&second) {
+                return Err(self.error("unpaired surrogate"));
+            }
+            0x10000 + ((first - 0xd800) << 10) + (second - 0xdc00)
+        } else {
+            first
+        };
+        char::from_u32(code).ok_or_else(|| self.error("unpaired surrogate"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, BodyError> {
+        let hex = match self.input.get(self.pos..self.pos + 4) {
+            Some(hex) if hex.iter().all(u8::is_ascii_hexdigit) => hex,
+            _ => return Err(self.error("invalid \\u escape")),
+        };
+        self.pos += 4;
+        // ascii hex digits, so neither can fail
+        Ok(u32::from_str_radix(str::from_utf8(hex).unwrap(), 16).unwrap())
+    }
+
+    /// -?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?
+    fn number(&mut self) -> Result<Json, BodyError> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => self.digits(),
+            _ => return Err(self.error("invalid number")),
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if !matches!(self.peek(), Some(b'0'..=b'9')) {
+                return Err(self.error("invalid number"));
+            }
+            self.digits();
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            if !matches!(self.peek(), Some(b'0'..=b'9')) {
+                return Err(self.error("invalid number"));
+            }
+            self.digits();
+        }
+        // only ascii made it this far
+        let text = str::from_utf8(&self.input[start..self.pos]).unwrap();
+        text.parse().map(Json::Number).map_err(|_| self.error("invalid number"))
+    }
+
+    fn digits(&mut self) {
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Json, BodyError> {
+        decode(text.as_bytes(), &BodyLimits::default())
+    }
+
+    #[test]
+    fn values() {
+        let json = parse(
+            r#" {"name": "Ferris", "age": 8, "temp": -2.5e1, "crab": true, "home": null,
+                "tags": ["rust", "\u00e7\"\n", "\ud83e\udd80"], "empty": {}} "#,
+        )
+        .unwrap();
+        assert_eq!(Some("Ferris"), json.get("name").and_then(Json::as_str));
+        assert_eq!(Some(8.0), json.get("age").and_then(Json::as_f64));
+        assert_eq!(Some(-25.0), json.get("temp").and_then(Json::as_f64));
+        assert_eq!(Some(true), json.get("crab").and_then(Json::as_bool));
+        assert!(json.get("home").unwrap().is_null());
+        assert_eq!(
+            vec!["rust".to_string(), "ç\"\n".to_string(), "🦀".to_string()],
+            json.field::<Vec<String>>("tags").unwrap()
+        );
+        assert_eq!(Some(&Json::Object(Vec::new())), json.get("empty"));
+        assert_eq!(Json::Array(Vec::new()), parse("[]").unwrap());
+    }
+
+    #[test]
+    fn typed_fields() {
+        let json = parse(r#"{"age": 8, "big": 300, "half": 1.5, "name": "Ferris", "ids": [1, "2"]}"#).unwrap();
+        assert_eq!(8u8, json.field::<u8>("age").unwrap());
+        assert_eq!(None, json.field::<Option<u8>>("missing").unwrap());
+        assert_eq!(
+            "big: expected an integer (u8), not 300",
+            json.field::<u8>("big").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "half: expected an integer (i64), not 1.5",
+            json.field::<i64>("half").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "age: expected a string, not 8",
+            json.field::<String>("age").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "missing: expected a string, not null",
+            json.field::<String>("missing").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "ids: [1]: expected an integer (u32), not a string",
+            json.field::<Vec<u32>>("ids").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "expected an object, not an array",
+            Json::Array(vec![]).field::<u8>("a").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn broken_json() {
+        for text in [
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "{a: 1}",
+            "01",
+            "1.",
+            "-",
+            "tru",
+            "\"\\x\"",
+            "\"\\ud83e\"",
+            "\"a\nb\"",
+            "[] []",
+        ] {
+            assert!(matches!(parse(text), Err(BodyError::Invalid(_))), "{:?}", text);
+        }
+        assert_eq!(
+            "invalid JSON at byte 5: expected ',' or ']'",
+            parse("[1, 2").unwrap_err().to_string()
+        );
+
+        let deep = "[".repeat(33) + &"]".repeat(33);
+        assert_eq!("the JSON nests too deep", parse(&deep).unwrap_err().to_string());
+        assert!(parse(&deep[1..deep.len() - 1]).is_ok());
+    }
+}
@@ -0,0 +1,370 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{client_error, parameter, BodyError, BodyLimits, Form};
+use crate::request::find;
+
+/// max size of the headers of each part
+const MAX_PART_HEAD: usize = 8 * 1024;
+
+const READ_CHUNK: usize = 4096;
+
+/// so that uploads from the same process never get the same file name
+static NEXT_UPLOAD: AtomicU64 = AtomicU64::new(0);
+
+/// A `multipart/form-data` body, see `Request::multipart`.
+#[derive(Debug, Default)]
+pub struct Multipart {
+    /// the parts that aren't files, `FromForm` takes them like any other form
+    pub fields: Form,
+    pub files: Vec<UploadedFile>,
+}
+
+impl Multipart {
+    /// The first file sent as `name`.
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|file| file.name == name)
+    }
+}
+
+/// A file from a multipart body, already written to `BodyLimits::upload_dir`.
+///
+/// The file is deleted when this is dropped, unless we `persist` it somewhere first.
+#[derive(Debug)]
+pub struct UploadedFile {
+    /// the name of the form field it came in
+    pub name: String,
+    /// the name the client gave it, without any directories
+    pub file_name: String,
+    pub content_type: Option<String>,
+    pub size: u64,
+    /// `None` once it's persisted, so there's nothing to clean up
+    path: Option<PathBuf>,
+}
+
+impl UploadedFile {
+    /// creates an empty file with a name nobody else uses
+    fn create(dir: &Path, head: PartHead, file_name: &str) -> io::Result<(UploadedFile, File)> {
+        let path = dir.join(format!(
+            "hello-upload-{}-{}",
+            process::id(),
+            NEXT_UPLOAD.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        let upload = UploadedFile {
+            name: head.name,
+            // like "C:\fakepath\photo.jpg", which some browsers send
+            file_name: file_name.rsplit(['/', '\\']).next().unwrap_or("").to_string(),
+            content_type: head.content_type,
+            size: 0,
+            path: Some(path),
+        };
+        Ok((upload, file))
+    }
+
+    /// Where it is for now.
+    pub fn path(&self) -> &Path {
+        self.path.as_deref().unwrap_or_else(|| Path::new(""))
+    }
+
+    /// Moves the file to `to`, so it stays around after this is dropped.
+    pub fn persist(mut self, to: impl AsRef<Path>) -> io::Result<()> {
+        let from = self.path();
+        // a rename can't go to another file system, so then we copy
+        if fs::rename(from, to.as_ref()).is_err() {
+            fs::copy(from, to.as_ref())?;
+            fs::remove_file(from)?;
+        }
+        self.path = None;
+        Ok(())
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// what we care about in the headers of a part
+struct PartHead {
+    name: String,
+    file_name: Option<String>,
+    content_type: Option<String>,
+}
+
+/// Reads the parts from `reader` as they come: the text fields into memory, and the files
+/// straight to disk, a chunk at a time.
+pub(super) fn decode(reader: impl Read, boundary: &str, limits: &BodyLimits) -> Result<Multipart, BodyError> {
+    // starting with a line break, the first boundary looks like all the others
+    let mut parts = Parts {
+        reader,
+        buf: b"\r\n".to_vec(),
+        delimiter: format!("\r\n--{}", boundary).into_bytes(),
+    };
+    let mut multipart = Multipart::default();
+    let mut field_bytes = 0;
+
+    // whatever comes before the first part is to be ignored, but there's only so much we skip
+    let mut preamble_bytes = 0;
+    parts.read_part(&mut |chunk| {
+        preamble_bytes += chunk.len();
+        if preamble_bytes > limits.max_bytes {
+            return Err(BodyError::TooLarge("the form is too large"));
+        }
+        Ok(())
+    })?;
+    while parts.next_part()? {
+        let head = parse_head(&parts.read_head()?)?;
+        match head.file_name.clone() {
+            Some(file_name) => {
+                if multipart.files.len() == limits.max_files {
+                    return Err(BodyError::TooLarge("the form has too many files"));
+                }
+                // the file is removed if we fail before the end, since `upload` is dropped then
+                let (mut upload, file) = UploadedFile::create(&limits.upload_dir, head, &file_name)?;
+                let mut out = BufWriter::new(file);
+                parts.read_part(&mut |chunk| {
+                    upload.size += chunk.len() as u64;
+                    if upload.size > limits.max_file_bytes {
+                        return Err(BodyError::TooLarge("a file is too large"));
+                    }
+                    out.write_all(chunk)?;
+                    Ok(())
+                })?;
+                out.flush()?;
+                multipart.files.push(upload);
+            }
+            None => {
+                if multipart.fields.len() == limits.max_fields {
+                    return Err(BodyError::TooLarge("the form has too many fields"));
+                }
+                let mut value = Vec::new();
+                parts.read_part(&mut |chunk| {
+                    field_bytes += chunk.len();
+                    if field_bytes > limits.max_bytes {
+                        return Err(BodyError::TooLarge("the form is too large"));
+                    }
+                    value.extend_from_slice(chunk);
+                    Ok(())
+                })?;
+                let value = String::from_utf8(value)
+                    .map_err(|_| BodyError::Invalid(format!("the {} field is not valid UTF-8", head.name)))?;
+                multipart.fields.push(head.name, value);
+            }
+        }
+    }
+    Ok(multipart)
+}
+
+/// parses "Content-Disposition: form-data; name=..." and the part's Content-Type
+fn parse_head(head: &[u8]) -> Result<PartHead, BodyError> {
+    let invalid = |why: &str| BodyError::Invalid(format!("invalid multipart body: {}", why));
+    let head = str::from_utf8(head).map_err(|_| invalid("part headers are not valid UTF-8"))?;
+
+    let mut disposition = None;
+    let mut content_type = None;
+    for line in head.split("\r\n").filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("broken part header"))?;
+        if name.trim().eq_ignore_ascii_case("Content-Disposition") {
+            disposition = Some(value.trim());
+        } else if name.trim().eq_ignore_ascii_case("Content-Type") {
+            content_type = Some(value.trim().to_string());
+        }
+    }
+
+    let disposition = disposition.ok_or_else(|| invalid("a part has no Content-Disposition"))?;
+    let name = parameter(disposition, "name").ok_or_else(|| invalid("a part has no name"))?;
+    Ok(PartHead {
+        name,
+        file_name: parameter(disposition, "filename"),
+        content_type,
+    })
+}
+
+/// the parts of a body, split on the delimiter as we read it
+struct Parts<R> {
+    reader: R,
+    /// read, but not handed out yet
+    buf: Vec<u8>,
+    /// "\r\n--" and the boundary
+    delimiter: Vec<u8>,
+}
+
+impl<R: Read> Parts<R> {
+    /// reads some more, it's an error when the body ends before the last delimiter
+    fn fill(&mut self) -> Result<(), BodyError> {
+        let mut chunk = [0; READ_CHUNK];
+        let n = self.reader.read(&mut chunk).map_err(client_error)?;
+        if n == 0 {
+            return Err(BodyError::Invalid(
+                "invalid multipart body: it ends in the middle of a part".to_string(),
+            ));
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    /// after a delimiter: "--" ends the body, and a line break starts another part
+    fn next_part(&mut self) -> Result<bool, BodyError> {
+        while self.buf.len() < 2 {
+            self.fill()?;
+        }
+        let more = match &self.buf[..2] {
+            b"--" => false,
+            b"\r\n" => true,
+            _ => {
+                return Err(BodyError::Invalid(
+                    "invalid multipart body: broken delimiter".to_string(),
+                ))
+            }
+        };
+        self.buf.drain(..2);
+        Ok(more)
+    }
+
+    /// the headers of a part, up to the empty line after them
+    fn read_head(&mut self) -> Result<Vec<u8>, BodyError> {
+        loop {
+            // a part may have no headers at all, and start with the empty line
+            if self.buf.starts_with(b"\r\n") {
+                self.buf.drain(..2);
+                return Ok(Vec::new());
+            }
+            if let Some(end) = find(&self.buf, b"\r\n\r\n") {
+                let head = self.buf[..end].to_vec();
+                self.buf.drain(..end + 4);
+                return Ok(head);
+            }
+            if self.buf.len() > MAX_PART_HEAD {
+                return Err(BodyError::TooLarge("the headers of a part are too large"));
+            }
+            self.fill()?;
+        }
+    }
+
+    /// hands `sink` everything up to the next delimiter, and skips the delimiter
+    fn read_part(&mut self, sink: &mut dyn FnMut(&[u8]) -> Result<(), BodyError>) -> Result<(), BodyError> {
+        loop {
+            if let Some(end) = find(&self.buf, &self.delimiter) {
+                sink(&self.buf[..end])?;
+                self.buf.drain(..end + self.delimiter.len());
+                return Ok(());
+            }
+            // the end of the buffer could be the start of a delimiter, so it waits for more
+            let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            sink(&self.buf[..safe])?;
+            self.buf.drain(..safe);
+            self.fill()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &str = "preamble, ignored\r\n\
+        --xyz\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        Hello, multipart\r\n\
+        --xyz\r\n\
+        Content-Disposition: form-data; name=\"photo\"; filename=\"C:\\fakepath\\crab.txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        Ferris\r\n--xy almost a delimiter\r\n\
+        --xyz--\r\n";
+
+    /// gives out a few bytes at a time, so delimiters get split between reads
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn fields_and_files() {
+        let multipart = decode(Trickle(BODY.as_bytes()), "xyz", &BodyLimits::default()).unwrap();
+        assert_eq!(Some("Hello, multipart"), multipart.fields.get("title"));
+
+        let photo = multipart.file("photo").unwrap();
+        assert_eq!("crab.txt", photo.file_name);
+        assert_eq!(Some("text/plain"), photo.content_type.as_deref());
+        let content = "Ferris\r\n--xy almost a delimiter";
+        assert_eq!(content.len() as u64, photo.size);
+        assert_eq!(content, fs::read_to_string(photo.path()).unwrap());
+
+        // dropping it cleans up
+        let path = photo.path().to_path_buf();
+        drop(multipart);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn persisted_files_stay() {
+        let mut multipart = decode(BODY.as_bytes(), "xyz", &BodyLimits::default()).unwrap();
+        let photo = multipart.files.remove(0);
+        let from = photo.path().to_path_buf();
+        let to = BodyLimits::default()
+            .upload_dir
+            .join(format!("hello-persisted-{}", process::id()));
+
+        photo.persist(&to).unwrap();
+        assert!(!from.exists());
+        assert_eq!("Ferris\r\n--xy almost a delimiter", fs::read_to_string(&to).unwrap());
+        fs::remove_file(to).unwrap();
+    }
+
+    #[test]
+    fn limits_and_broken_bodies() {
+        let small = BodyLimits {
+            max_file_bytes: 4,
+            ..BodyLimits::default()
+        };
+        assert!(matches!(
+            decode(BODY.as_bytes(), "xyz", &small),
+            Err(BodyError::TooLarge(_))
+        ));
+        let no_files = BodyLimits {
+            max_files: 0,
+            ..BodyLimits::default()
+        };
+        assert!(matches!(
+            decode(BODY.as_bytes(), "xyz", &no_files),
+            Err(BodyError::TooLarge(_))
+        ));
+        let short_fields = BodyLimits {
+            max_bytes: 5,
+            ..BodyLimits::default()
+        };
+        assert!(matches!(
+            decode(BODY.as_bytes(), "xyz", &short_fields),
+            Err(BodyError::TooLarge(_))
+        ));
+
+        // cut short, in the middle of the file
+        let cut = &BODY[..BODY.find("Ferris").unwrap() + 3];
+        let e = decode(cut.as_bytes(), "xyz", &BodyLimits::default()).unwrap_err();
+        assert_eq!("invalid multipart body: it ends in the middle of a part", e.to_string());
+        assert!(matches!(
+            decode(BODY.as_bytes(), "abc", &BodyLimits::default()),
+            Err(BodyError::Invalid(_))
+        ));
+
+        let nameless = "--xyz\r\nContent-Disposition: form-data\r\n\r\nvalue\r\n--xyz--";
+        let e = decode(nameless.as_bytes(), "xyz", &BodyLimits::default()).unwrap_err();
+        assert_eq!("invalid multipart body: a part has no name", e.to_string());
+    }
+}
@@ -0,0 +1,318 @@
+use std::io::{self, Write};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::headers::Headers;
+use crate::log::warn;
+use crate::middleware::{Middleware, Next};
+use crate::request::Request;
+use crate::response::{Response, StatusCode};
+
+/// Middleware that compresses response bodies for the clients that ask for it
+/// with `Accept-Encoding: gzip` or `deflate`.
+///
+/// Only bodies of at least `min_size` bytes, with a content type from the
+/// allow-list, get compressed. Responses that are already encoded, and parts
+/// of a file (206 responses), are sent as they are.
+///
+/// ```
+/// use hello_server::{Compression, Handler, Router};
+///
+/// let mut router: Router<Handler> = Router::new();
+/// router.wrap(Compression::new().min_size(1024));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compression {
+    min_size: usize,
+    content_types: Vec<String>,
+    level: u32,
+}
+
+impl Compression {
+    /// Compresses text, JSON, JavaScript, XML and SVG bodies of 256 bytes or more.
+    pub fn new() -> Compression {
+        Compression {
+            min_size: 256,
+            content_types: [
+                "text/*",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .iter()
+            .map(|t| t.to_string())
+            .collect(),
+            level: 6,
+        }
+    }
+
+    /// Smaller bodies are not worth the trouble, compressing them may even make them bigger.
+    pub fn min_size(mut self, bytes: usize) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    /// The content types we compress, like "application/json", or "text/*" for every text type.
+    pub fn content_types(mut self, types: &[&str]) -> Compression {
+        self.content_types = types.iter().map(|t| t.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// From 0 (fastest, not compressed) to 9 (smallest, slowest), 6 by default.
+    pub fn level(mut self, level: u32) -> Compression {
+        self.level = level.min(9);
+        self
+    }
+
+    /// whether the body depends on Accept-Encoding at all
+    fn compressible(&self, response: &Response) -> bool {
+        let headers = &response.headers;
+        // streamed bodies go out as they are made, we'd have to compress them as they go
+        response.stream.is_none()
+            && response.status.allows_body()
+            && response.status != StatusCode::PartialContent
+            && !headers.contains("Content-Range")
+            && !headers.contains("Content-Encoding")
+            && !headers.has_token("Cache-Control", "no-transform")
+            && response.body.len() >= self.min_size
+            && headers.get("Content-Type").is_some_and(|t| self.allows(t))
+    }
+
+    fn allows(&self, content_type: &str) -> bool {
+        // "text/html; charset=utf-8" is "text/html" as far as we care
+        let content_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        self.content_types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(main_type) => content_type.split('/').next() == Some(main_type),
+                None => *allowed == content_type,
+            })
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let accepted = negotiate(&request.headers);
+        let mut response = next.run(request);
+        if !self.compressible(&response) {
+            return response;
+        }
+
+        // caches have to keep a copy per Accept-Encoding, even of the responses we don't compress
+        if !response.headers.has_token("Vary", "Accept-Encoding") && !response.headers.has_token("Vary", "*") {
+            response.headers.append("Vary", "Accept-Encoding");
+        }
+
+        let encoding = match accepted {
+            Some(encoding) => encoding,
+            None => return response,
+        };
+        let body = match encoding.encode(&response.body, self.level) {
+            Ok(body) if body.len() < response.body.len() => body,
+            Ok(_) => return response,
+            Err(e) => {
+                warn!("Failed to compress a response: {}", e);
+                return response;
+            }
+        };
+
+        response.body = body;
+        response.headers.insert("Content-Encoding", encoding.as_str());
+        // the length we had was for the body before compressing it
+        response.headers.remove("Content-Length");
+        // the bytes are different now, so the ETag can't say they're the same anymore
+        if let Some(etag) = response.headers.get("ETag").filter(|etag| !etag.starts_with("W/")) {
+            let weak = format!("W/{}", etag);
+            response.headers.insert("ETag", weak);
+        }
+        response
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, body: &[u8], level: u32) -> io::Result<Vec<u8>> {
+        let level = flate2::Compression::new(level);
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            // HTTP's "deflate" is the zlib format, not raw deflate
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), level);
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// picks the encoding the client likes best, gzip when it likes both the same
+///
+/// "gzip;q=0.5, deflate" prefers deflate, "*;q=0" or no header at all means neither.
+fn negotiate(headers: &Headers) -> Option<Encoding> {
+    let mut gzip = None;
+    let mut deflate = None;
+    let mut any = None;
+
+    for item in headers.get_all("Accept-Encoding").flat_map(|value| value.split(',')) {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match name.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(q),
+            "deflate" => deflate = Some(q),
+            "*" => any = Some(q),
+            _ => {}
+        }
+    }
+
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    let deflate = deflate.or(any).unwrap_or(0.0);
+    if gzip > 0.0 && gzip >= deflate {
+        Some(Encoding::Gzip)
+    } else if deflate > 0.0 {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Method;
+    use crate::router::{Handler, Router};
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use std::io::Read;
+
+    fn page() -> String {
+        "<p>hello, hello, hello</p>\n".repeat(20)
+    }
+
+    fn router(compression: Compression) -> Router<Handler> {
+        let mut router = Router::new();
+        router
+            .get("/page", |_: &Request| {
+                Response::html(StatusCode::Ok, page()).with_header("ETag", "\"abc\"")
+            })
+            .get("/tiny", |_: &Request| Response::html(StatusCode::Ok, "<p>hi</p>"))
+            .get("/png", |_: &Request| {
+                Response::new(StatusCode::Ok)
+                    .with_header("Content-Type", "image/png")
+                    .with_body(page())
+            })
+            .get("/gz", |_: &Request| {
+                Response::html(StatusCode::Ok, page()).with_header("Content-Encoding", "br")
+            })
+            .get("/part", |_: &Request| {
+                Response::html(StatusCode::PartialContent, page()).with_header("Content-Range", "bytes 0-539/1000")
+            })
+            .wrap(compression);
+        router
+    }
+
+    fn get(router: &Router<Handler>, path: &str, accept: Option<&str>) -> Response {
+        let mut request = Request::new(Method::Get, path);
+        if let Some(accept) = accept {
+            request.headers.insert("Accept-Encoding", accept);
+        }
+        router.handle(&mut request)
+    }
+
+    #[test]
+    fn gzip_and_deflate() {
+        let router = router(Compression::new());
+
+        let response = get(&router, "/page", Some("gzip, deflate"));
+        assert_eq!(Some("gzip"), response.headers.get("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.headers.get("Vary"));
+        assert_eq!(Some("W/\"abc\""), response.headers.get("ETag"));
+        let mut body = String::new();
+        GzDecoder::new(&response.body[..]).read_to_string(&mut body).unwrap();
+        assert_eq!(page(), body);
+
+        let response = get(&router, "/page", Some("deflate"));
+        assert_eq!(Some("deflate"), response.headers.get("Content-Encoding"));
+        let mut body = String::new();
+        ZlibDecoder::new(&response.body[..]).read_to_string(&mut body).unwrap();
+        assert_eq!(page(), body);
+    }
+
+    #[test]
+    fn uncompressed_but_still_varies() {
+        let router = router(Compression::new());
+
+        for accept in [None, Some("identity"), Some("gzip;q=0, br")] {
+            let response = get(&router, "/page", accept);
+            assert_eq!(None, response.headers.get("Content-Encoding"));
+            assert_eq!(Some("Accept-Encoding"), response.headers.get("Vary"));
+            assert_eq!(page().into_bytes(), response.body);
+        }
+    }
+
+    #[test]
+    fn left_alone() {
+        let default = router(Compression::new());
+
+        // too small, not in the allow-list, already encoded, and a range
+        for path in ["/tiny", "/png", "/gz", "/part"] {
+            let response = get(&default, path, Some("gzip"));
+            assert_ne!(Some("gzip"), response.headers.get("Content-Encoding"), "{}", path);
+            assert_eq!(None, response.headers.get("Vary"), "{}", path);
+        }
+
+        let custom = router(Compression::new().min_size(4).content_types(&["image/png"]));
+        assert_eq!(
+            Some("gzip"),
+            get(&custom, "/png", Some("gzip")).headers.get("Content-Encoding")
+        );
+        assert_eq!(
+            None,
+            get(&custom, "/page", Some("gzip")).headers.get("Content-Encoding")
+        );
+    }
+
+    #[test]
+    fn negotiation() {
+        let accept = |value: &str| {
+            let mut headers = Headers::new();
+            headers.append("Accept-Encoding", value);
+            negotiate(&headers)
+        };
+
+        assert_eq!(Some(Encoding::Gzip), accept("deflate, gzip"));
+        assert_eq!(Some(Encoding::Deflate), accept("gzip;q=0.5, deflate"));
+        assert_eq!(Some(Encoding::Deflate), accept("DEFLATE;q=0.8, gzip;q=0"));
+        assert_eq!(Some(Encoding::Gzip), accept("*"));
+        assert_eq!(Some(Encoding::Deflate), accept("gzip;q=0, *;q=0.1"));
+        assert_eq!(None, accept("*;q=0"));
+        assert_eq!(None, accept("br, identity"));
+        assert_eq!(None, negotiate(&Headers::new()));
+    }
+}
@@ -0,0 +1,641 @@
+//! Settings for the hello-server binary.
+//!
+//! Each setting starts with its default, then gets overridden by the config
+//! file, then by an environment variable, then by a command line flag.
+//! The config file is TOML-like:
+//!
+//! ```toml
+//! # anything after a # is a comment
+//! bind = "0.0.0.0:8080"
+//! threads = 16
+//!
+//! [log]
+//! level = "debug"
+//! ```
+//!
+//! Every setting in the file also has a flag and a variable: `max_connections`
+//! is `--max-connections` and `HELLO_MAX_CONNECTIONS`, `log.level` is
+//! `--log-level` and `HELLO_LOG_LEVEL`.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::log::Level;
+
+/// Everything the binary needs to know to start serving.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// the address we listen on, like "127.0.0.1:7878"
+    pub bind: String,
+    /// the most worker threads the pool grows to
+    pub threads: usize,
+    /// the worker threads we keep around when idle, up to 4 when not set
+    pub min_threads: Option<usize>,
+    /// how many connections wait for a worker before the accept loop waits too
+    pub queue_capacity: usize,
+    /// connections over this many get a 503 and are closed right away
+    pub max_connections: usize,
+    /// whether each connection gets a worker, or a few event loops wait on all of them
+    pub mode: ServerMode,
+    /// the directory with the pages and files we serve
+    pub root: PathBuf,
+    pub log_level: Level,
+    pub log_format: LogFormat,
+    /// the PEM certificate chain we serve HTTPS with, needs the `tls` feature
+    pub tls_cert: Option<PathBuf>,
+    /// the PEM private key of that certificate
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: "127.0.0.1:7878".to_string(),
+            threads: 32,
+            min_threads: None,
+            queue_capacity: 100,
+            max_connections: 256,
+            mode: ServerMode::Threads,
+            root: PathBuf::from("."),
+            log_level: Level::Info,
+            log_format: LogFormat::Text,
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
+
+/// every setting, by its name in the config file, in the order `Display` writes them
+const KEYS: &[&str] = &[
+    "bind",
+    "threads",
+    "min_threads",
+    "queue_capacity",
+    "max_connections",
+    "mode",
+    "root",
+    "log.level",
+    "log.format",
+    "tls.cert",
+    "tls.key",
+];
+
+/// the variable that names the config file, when there's no --config flag
+const CONFIG_VAR: &str = "HELLO_CONFIG";
+
+impl Config {
+    pub fn min_threads(&self) -> usize {
+        self.min_threads.unwrap_or_else(|| self.threads.min(4))
+    }
+
+    /// Changes the setting called `key` in the config file.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "bind" => self.bind = value.to_string(),
+            "threads" => self.threads = number(value)?,
+            "min_threads" => self.min_threads = Some(number(value)?),
+            "queue_capacity" => self.queue_capacity = number(value)?,
+            "max_connections" => self.max_connections = number(value)?,
+            "mode" => self.mode = value.parse()?,
+            "root" => self.root = PathBuf::from(value),
+            "log.level" => self.log_level = value.parse()?,
+            "log.format" => self.log_format = value.parse()?,
+            "tls.cert" => self.tls_cert = Some(PathBuf::from(value)),
+            "tls.key" => self.tls_key = Some(PathBuf::from(value)),
+            _ => return Err(format!("unknown setting {:?}", key)),
+        }
+        Ok(())
+    }
+
+    /// Checks the settings make sense together, and that we can use the address and the root.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, message: String| Err(ConfigError::new(key, message));
+
+        if let Err(e) = self.bind.to_socket_addrs() {
+            return invalid(
+                "bind",
+                format!("{:?} is not an address we can listen on: {}", self.bind, e),
+            );
+        }
+        if self.threads == 0 {
+            return invalid("threads", "we need at least one thread".to_string());
+        }
+        if self.min_threads() > self.threads {
+            let message = format!(
+                "{} is more than the {} threads we can have",
+                self.min_threads(),
+                self.threads
+            );
+            return invalid("min_threads", message);
+        }
+        if self.queue_capacity == 0 {
+            return invalid(
+                "queue_capacity",
+                "the queue needs room for at least one connection".to_string(),
+            );
+        }
+        if self.max_connections == 0 {
+            return invalid(
+                "max_connections",
+                "we need to accept at least one connection".to_string(),
+            );
+        }
+        if !self.root.is_dir() {
+            return invalid("root", format!("{} is not a directory", self.root.display()));
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (None, None) => {}
+            _ if self.mode == ServerMode::EventLoop => {
+                return invalid("mode", "the event loop doesn't serve TLS, use \"threads\"".to_string())
+            }
+            (Some(_), None) => return invalid("tls.key", "the certificate needs its private key".to_string()),
+            (None, Some(_)) => return invalid("tls.cert", "the private key needs its certificate".to_string()),
+            (Some(cert), Some(key)) => {
+                if !cfg!(feature = "tls") {
+                    return invalid("tls.cert", "built without TLS, build with --features tls".to_string());
+                }
+                for (name, path) in [("tls.cert", cert), ("tls.key", key)] {
+                    if !path.is_file() {
+                        return invalid(name, format!("{} is not a file", path.display()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies every line of a config file, `name` is where the errors say it comes from.
+    fn apply_file(&mut self, name: &str, text: &str) -> Result<(), ConfigError> {
+        let mut section = String::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let error = |message: String| ConfigError::new(format!("{}:{}", name, n + 1), message);
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(rest) = line.strip_prefix('[') {
+                let name = strip_comment(rest)
+                    .strip_suffix(']')
+                    .ok_or_else(|| error("a section needs a closing ]".to_string()))?
+                    .trim();
+                if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    return Err(error(format!("bad section name {:?}", name)));
+                }
+                section = format!("{}.", name);
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected a line like: key = value".to_string()))?;
+            let value = parse_value(value.trim()).map_err(error)?;
+            self.set(&format!("{}{}", section, key.trim()), &value).map_err(error)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes the settings as a config file that gives back the same settings.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "bind = {}", quote(&self.bind))?;
+        writeln!(f, "threads = {}", self.threads)?;
+        writeln!(f, "min_threads = {}", self.min_threads())?;
+        writeln!(f, "queue_capacity = {}", self.queue_capacity)?;
+        writeln!(f, "max_connections = {}", self.max_connections)?;
+        writeln!(f, "mode = {}", quote(self.mode.as_str()))?;
+        writeln!(f, "root = {}", quote(&self.root.to_string_lossy()))?;
+        writeln!(f)?;
+        writeln!(f, "[log]")?;
+        writeln!(f, "level = {}", quote(&self.log_level.as_str().to_ascii_lowercase()))?;
+        writeln!(f, "format = {}", quote(self.log_format.as_str()))?;
+
+        // there's no way to write "not set", so we leave those out
+        if self.tls_cert.is_some() || self.tls_key.is_some() {
+            writeln!(f)?;
+            writeln!(f, "[tls]")?;
+        }
+        if let Some(cert) = &self.tls_cert {
+            writeln!(f, "cert = {}", quote(&cert.to_string_lossy()))?;
+        }
+        if let Some(key) = &self.tls_key {
+            writeln!(f, "key = {}", quote(&key.to_string_lossy()))?;
+        }
+        Ok(())
+    }
+}
+
+/// How log lines are written, see `StderrLogger` and `JsonLogger`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}, expected \"text\" or \"json\"", s)),
+        }
+    }
+}
+
+/// How the binary serves its connections, see `Server::event_loop`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerMode {
+    /// every connection keeps a worker for as long as it's open
+    Threads,
+    /// a few threads wait on every connection, and only requests go to the workers
+    EventLoop,
+}
+
+impl ServerMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServerMode::Threads => "threads",
+            ServerMode::EventLoop => "event-loop",
+        }
+    }
+}
+
+impl FromStr for ServerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ServerMode, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "threads" => Ok(ServerMode::Threads),
+            "event-loop" => Ok(ServerMode::EventLoop),
+            _ => Err(format!("unknown mode {:?}, expected \"threads\" or \"event-loop\"", s)),
+        }
+    }
+}
+
+/// What the command line asks for, with the settings from every source put together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandLine {
+    pub config: Config,
+    /// --check-config: print the settings instead of serving
+    pub check_config: bool,
+    /// --help: print how to use it instead of serving
+    pub help: bool,
+}
+
+impl CommandLine {
+    /// Reads `args` (without the program name), the config file and the environment.
+    ///
+    /// `env` looks up environment variables, so tests don't have to change the real ones.
+    pub fn parse<I, E>(args: I, env: E) -> Result<CommandLine, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut check_config = false;
+        let mut help = false;
+        let mut config_file = None;
+        let mut flags = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => help = true,
+                "--check-config" => check_config = true,
+                _ if arg.starts_with("--") => {
+                    // both "--bind 0.0.0.0:80" and "--bind=0.0.0.0:80" work
+                    let (flag, value) = match arg.split_once('=') {
+                        Some((flag, value)) => (flag.to_string(), value.to_string()),
+                        None => match args.next() {
+                            Some(value) => (arg, value),
+                            None => return Err(ConfigError::new(arg, "expected a value after it")),
+                        },
+                    };
+
+                    if flag == "--config" {
+                        config_file = Some(value);
+                    } else {
+                        match KEYS.iter().find(|key| flag_name(key) == flag) {
+                            Some(key) => flags.push((*key, flag, value)),
+                            None => return Err(ConfigError::new(flag, "unknown flag")),
+                        }
+                    }
+                }
+                _ => return Err(ConfigError::new(arg, "unexpected argument")),
+            }
+        }
+
+        let mut config = Config::default();
+        if help {
+            return Ok(CommandLine {
+                config,
+                check_config,
+                help,
+            });
+        }
+
+        if let Some(path) = config_file.or_else(|| env(CONFIG_VAR)) {
+            let text = fs::read_to_string(&path).map_err(|e| ConfigError::new(&path, e.to_string()))?;
+            config.apply_file(&path, &text)?;
+        }
+
+        for key in KEYS {
+            let var = var_name(key);
+            if let Some(value) = env(&var) {
+                config.set(key, &value).map_err(|e| ConfigError::new(var, e))?;
+            }
+        }
+
+        for (key, flag, value) in flags {
+            config.set(key, &value).map_err(|e| ConfigError::new(flag, e))?;
+        }
+
+        config.validate()?;
+        Ok(CommandLine {
+            config,
+            check_config,
+            help,
+        })
+    }
+}
+
+/// A setting we can't use, and where it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// like "hello.toml:3", "HELLO_THREADS" or "--threads"
+    pub origin: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(origin: impl AsRef<str>, message: impl Into<String>) -> ConfigError {
+        ConfigError {
+            origin: origin.as_ref().to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.origin, self.message)
+    }
+}
+
+impl Error for ConfigError {}
+
+/// "max_connections" is set with --max-connections, and "log.level" with --log-level
+fn flag_name(key: &str) -> String {
+    format!("--{}", key.replace(['.', '_'], "-"))
+}
+
+/// "max_connections" is set with HELLO_MAX_CONNECTIONS, and "log.level" with HELLO_LOG_LEVEL
+fn var_name(key: &str) -> String {
+    format!("HELLO_{}", key.replace('.', "_").to_ascii_uppercase())
+}
+
+fn number(value: &str) -> Result<usize, String> {
+    value.parse().map_err(|_| format!("expected a number, got {:?}", value))
+}
+
+/// the part of a line before its comment
+fn strip_comment(s: &str) -> &str {
+    match s.find(['#', ';']) {
+        Some(i) => s[..i].trim_end(),
+        None => s,
+    }
+}
+
+/// a value is either a "quoted string" or a bare word/number, both may be followed by a comment
+fn parse_value(s: &str) -> Result<String, String> {
+    let rest = match s.strip_prefix('"') {
+        Some(rest) => rest,
+        None => {
+            let value = strip_comment(s);
+            if value.is_empty() {
+                return Err("expected a value after =".to_string());
+            }
+            return Ok(value.to_string());
+        }
+    };
+
+    let mut value = String::new();
+    let mut chars = rest.chars();
+    loop {
+        match chars.next() {
+            None => return Err("a string needs a closing \"".to_string()),
+            Some('"') => break,
+            Some('\\') => match chars.next() {
+                Some('"') => value.push('"'),
+                Some('\\') => value.push('\\'),
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('r') => value.push('\r'),
+                other => {
+                    return Err(format!(
+                        "unknown escape \\{}",
+                        other.map(String::from).unwrap_or_default()
+                    ))
+                }
+            },
+            Some(c) => value.push(c),
+        }
+    }
+
+    if !strip_comment(chars.as_str()).trim().is_empty() {
+        return Err("unexpected text after the string".to_string());
+    }
+    Ok(value)
+}
+
+/// the other way around from parse_value
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env;
+    use std::process;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    /// writes a config file that only this test uses
+    fn config_file(name: &str, text: &str) -> String {
+        let path = env::temp_dir().join(format!("hello-server-{}-{}.toml", process::id(), name));
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn defaults() {
+        let command_line = CommandLine::parse(Vec::new(), no_env).unwrap();
+        assert_eq!(Config::default(), command_line.config);
+        assert!(!command_line.check_config && !command_line.help);
+        assert_eq!(4, command_line.config.min_threads());
+    }
+
+    #[test]
+    fn flags() {
+        let command_line = CommandLine::parse(
+            args(
+                "--bind 0.0.0.0:8080 --threads=2 --max-connections 10 --mode event-loop --log-format json \
+                 --check-config",
+            ),
+            no_env,
+        )
+        .unwrap();
+
+        let config = command_line.config;
+        assert!(command_line.check_config);
+        assert_eq!("0.0.0.0:8080", config.bind);
+        assert_eq!(2, config.threads);
+        // not set, so it's capped by threads
+        assert_eq!(2, config.min_threads());
+        assert_eq!(10, config.max_connections);
+        assert_eq!(ServerMode::EventLoop, config.mode);
+        assert_eq!(LogFormat::Json, config.log_format);
+    }
+
+    #[test]
+    fn bad_flags() {
+        let error = |line| CommandLine::parse(args(line), no_env).unwrap_err().to_string();
+
+        assert_eq!("--threads: expected a number, got \"many\"", error("--threads many"));
+        assert_eq!("--port: unknown flag", error("--port 80"));
+        assert_eq!("--bind: expected a value after it", error("--bind"));
+        assert_eq!("serve: unexpected argument", error("serve"));
+        assert_eq!(
+            "min_threads: 8 is more than the 2 threads we can have",
+            error("--threads 2 --min-threads 8")
+        );
+        assert!(error("--root ./no/such/dir").starts_with("root: "));
+        assert_eq!(
+            "tls.key: the certificate needs its private key",
+            error("--tls-cert cert.pem")
+        );
+        assert!(error("--tls-cert ./no/cert.pem --tls-key ./no/key.pem").starts_with("tls.cert: "));
+        assert_eq!(
+            "mode: the event loop doesn't serve TLS, use \"threads\"",
+            error("--mode event-loop --tls-cert cert.pem --tls-key key.pem")
+        );
+        assert_eq!(
+            "--mode: unknown mode \"async\", expected \"threads\" or \"event-loop\"",
+            error("--mode async")
+        );
+    }
+
+    #[test]
+    fn file_then_env_then_flags() {
+        let path = config_file(
+            "layers",
+            "# where we listen\n\
+             bind = \"127.0.0.1:9000\" # a comment\n\
+             threads = 8\n\
+             max_connections = 50\n\
+             \n\
+             [log]\n\
+             level = debug\n",
+        );
+        let env: HashMap<_, _> = vec![
+            ("HELLO_CONFIG".to_string(), path),
+            ("HELLO_THREADS".to_string(), "6".to_string()),
+            ("HELLO_MAX_CONNECTIONS".to_string(), "40".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        let config = CommandLine::parse(args("--max-connections 30"), |name| env.get(name).cloned())
+            .unwrap()
+            .config;
+
+        assert_eq!("127.0.0.1:9000", config.bind);
+        assert_eq!(6, config.threads);
+        assert_eq!(30, config.max_connections);
+        assert_eq!(Level::Debug, config.log_level);
+    }
+
+    #[test]
+    fn file_errors_say_where() {
+        let error = |name, text| {
+            let path = config_file(name, text);
+            let error = CommandLine::parse(vec!["--config".to_string(), path.clone()], no_env).unwrap_err();
+            assert!(error.origin.starts_with(&path));
+            error.to_string()[path.len()..].to_string()
+        };
+
+        assert_eq!(
+            ":2: expected a line like: key = value",
+            error("no-equals", "threads = 2\nthreads\n")
+        );
+        assert_eq!(":1: unknown setting \"port\"", error("unknown", "port = 80\n"));
+        assert_eq!(
+            ":2: unknown setting \"server.bind\"",
+            error("section", "[server]\nbind = \"a:1\"\n")
+        );
+        assert_eq!(
+            ":1: a string needs a closing \"",
+            error("quote", "bind = \"127.0.0.1:80\n")
+        );
+        assert_eq!(":1: unknown log level \"loud\"", error("level", "log.level = loud\n"));
+    }
+
+    #[test]
+    fn printed_config_reads_back_the_same() {
+        let mut config = Config {
+            bind: "localhost:80".to_string(),
+            root: PathBuf::from("a \"quoted\" dir"),
+            mode: ServerMode::EventLoop,
+            log_level: Level::Warn,
+            tls_cert: Some(PathBuf::from("cert.pem")),
+            tls_key: Some(PathBuf::from("key.pem")),
+            ..Config::default()
+        };
+
+        let mut read = Config::default();
+        read.apply_file("printed", &config.to_string()).unwrap();
+
+        // min_threads gets printed with the value it ends up with
+        config.min_threads = Some(4);
+        assert_eq!(config, read);
+    }
+}
@@ -0,0 +1,198 @@
+//! Just enough of an async runtime to write handlers as `async fn`, see `Handler::from_async`.
+//!
+//! There's no event loop in here: a handler's future runs on the worker that got the
+//! request, and the worker sleeps whenever the future is waiting. What it waits for is
+//! blocking work running on another `ThreadPool`, see `PoolHandle::spawn_blocking`.
+
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use crate::pool::JobError;
+
+/// Runs `future` on this thread until it's done, and sleeps while it waits.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let unparker = Arc::new(Unparker {
+        thread: thread::current(),
+        woken: AtomicBool::new(false),
+    });
+    let waker = Waker::from(Arc::clone(&unparker));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        // a parked thread can wake up for no reason, so we wait until the future says so
+        while !unparker.woken.swap(false, Ordering::Acquire) {
+            thread::park();
+        }
+    }
+}
+
+/// wakes up the thread in `block_on`
+struct Unparker {
+    thread: Thread,
+    woken: AtomicBool,
+}
+
+impl Wake for Unparker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        self.thread.unpark();
+    }
+}
+
+/// A job from `PoolHandle::spawn_blocking`, awaiting it gives us what it returned, or its panic.
+pub struct BlockingTask<T> {
+    state: Arc<Mutex<TaskState<T>>>,
+}
+
+struct TaskState<T> {
+    /// how the job went, once it's over
+    result: Option<Result<T, JobError>>,
+    /// who to tell when it's over
+    waker: Option<Waker>,
+}
+
+impl<T> Future for BlockingTask<T> {
+    type Output = Result<T, JobError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T, JobError>> {
+        let mut state = lock(&self.state);
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// the job's end of a `BlockingTask`
+pub(crate) struct TaskDone<T> {
+    state: Option<Arc<Mutex<TaskState<T>>>>,
+}
+
+impl<T> TaskDone<T> {
+    pub(crate) fn complete(mut self, result: thread::Result<T>) {
+        self.finish(result.map_err(JobError::Panicked));
+    }
+
+    fn finish(&mut self, result: Result<T, JobError>) {
+        if let Some(state) = self.state.take() {
+            let waker = {
+                let mut state = lock(&state);
+                state.result = Some(result);
+                state.waker.take()
+            };
+            // not while we hold the lock, the task may be polled right away
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Drop for TaskDone<T> {
+    /// the job never ran, e.g. the pool shut down while it was queued
+    fn drop(&mut self) {
+        self.finish(Err(JobError::Dropped));
+    }
+}
+
+/// a task, and what the job uses to finish it
+pub(crate) fn blocking_task<T>() -> (BlockingTask<T>, TaskDone<T>) {
+    let state = Arc::new(Mutex::new(TaskState {
+        result: None,
+        waker: None,
+    }));
+    let done = TaskDone {
+        state: Some(Arc::clone(&state)),
+    };
+    (BlockingTask { state }, done)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::{ExecuteError, ThreadPool};
+    use crate::request::{Method, Request};
+    use crate::response::{Response, StatusCode};
+    use crate::router::Handler;
+    use std::sync::Barrier;
+    use std::time::Duration;
+
+    #[test]
+    fn waits_for_blocking_work() {
+        let pool = ThreadPool::new(2);
+        let blocking = pool.handle();
+
+        // each waits for the other, so this only ends if they run at the same time
+        let both = Arc::new(Barrier::new(2));
+        let sum = block_on(async {
+            let other = Arc::clone(&both);
+            let a = blocking.spawn_blocking(move || {
+                other.wait();
+                1
+            });
+            let other = Arc::clone(&both);
+            let b = blocking.spawn_blocking(move || {
+                other.wait();
+                2
+            });
+            a.unwrap().await.unwrap() + b.unwrap().await.unwrap()
+        });
+        assert_eq!(3, sum);
+        assert_eq!("ready", block_on(async { "ready" }));
+    }
+
+    #[test]
+    fn panics_and_jobs_that_never_ran() {
+        let pool = ThreadPool::new(1);
+        let blocking = pool.handle();
+
+        let panicked = block_on(blocking.spawn_blocking(|| panic!("boom")).unwrap());
+        assert_eq!("job panicked: boom", panicked.unwrap_err().to_string());
+
+        let (task, done) = blocking_task::<()>();
+        drop(done);
+        assert!(matches!(block_on(task), Err(JobError::Dropped)));
+
+        // the handle outlives the pool, but can't queue anything on it
+        pool.shutdown(Duration::from_secs(1));
+        assert!(matches!(
+            blocking.spawn_blocking(|| ()),
+            Err(ExecuteError::ShuttingDown)
+        ));
+    }
+
+    #[test]
+    fn async_handlers() {
+        let pool = ThreadPool::new(1);
+        let blocking = pool.handle();
+
+        let handler = Handler::from_async(move |request: Request| {
+            let blocking = blocking.clone();
+            async move {
+                let shout = blocking.spawn_blocking(move || request.path.to_uppercase()).unwrap();
+                Response::text(StatusCode::Ok, shout.await.unwrap())
+            }
+        });
+        let response = handler.call(&Request::new(Method::Get, "/hello"));
+        assert_eq!(b"/HELLO".to_vec(), response.body);
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt;
+
+/// An ordered list of HTTP header fields.
+///
+/// Header names are compared case-insensitively, as required by the HTTP spec,
+/// but we keep the original spelling so that we write back what we were given.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers { entries: Vec::new() }
+    }
+
+    /// Returns the value of the first field with the given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the values of every field with the given name, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Checks if a comma separated field (like `Connection` or `Transfer-Encoding`)
+    /// lists the given token, ignoring case.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Adds a field, keeping any other field with the same name.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Sets a field, replacing every other field with the same name.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    /// Removes every field with the given name and returns the first value, if any.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let mut removed = None;
+        let mut kept = Vec::with_capacity(self.entries.len());
+
+        for (n, v) in self.entries.drain(..) {
+            if n.eq_ignore_ascii_case(name) {
+                removed.get_or_insert(v);
+            } else {
+                kept.push((n, v));
+            }
+        }
+
+        self.entries = kept;
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// writes the fields the way they go on the wire, each one ending with CRLF
+impl fmt::Display for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in self.iter() {
+            write!(f, "{}: {}\r\n", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let mut headers = Headers::new();
+        headers.append("Content-Type", "text/html");
+
+        assert_eq!(Some("text/html"), headers.get("content-type"));
+        assert!(headers.contains("CONTENT-TYPE"));
+    }
+
+    #[test]
+    fn insert_replaces_and_append_keeps() {
+        let mut headers = Headers::new();
+        headers.append("Accept", "a");
+        headers.append("accept", "b");
+        assert_eq!(vec!["a", "b"], headers.get_all("Accept").collect::<Vec<_>>());
+
+        headers.insert("ACCEPT", "c");
+        assert_eq!(vec!["c"], headers.get_all("Accept").collect::<Vec<_>>());
+        assert_eq!(1, headers.len());
+    }
+
+    #[test]
+    fn tokens_in_list_fields() {
+        let mut headers = Headers::new();
+        headers.append("Connection", "Upgrade, Keep-Alive");
+
+        assert!(headers.has_token("connection", "keep-alive"));
+        assert!(headers.has_token("connection", "upgrade"));
+        assert!(!headers.has_token("connection", "close"));
+    }
+}
@@ -0,0 +1,117 @@
+//! The date format used by HTTP headers like `Date` and `Last-Modified` (RFC 7231, section 7.1.1.1).
+//!
+//! Example: "Sun, 06 Nov 1994 08:49:37 GMT"
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"]; // 1970-01-01 was a Thursday
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats a time as an IMF-fixdate. Times before 1970 are formatted as the epoch.
+pub fn format(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    let secs_of_day = secs % 86400;
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Formats a time as RFC 3339 in UTC, with milliseconds, like "1994-11-06T08:49:37.000Z".
+///
+/// Not an HTTP date, but it's what we write in the logs.
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Parses an IMF-fixdate, the only format we ever send.
+pub fn parse(date: &str) -> Option<SystemTime> {
+    // "Sun, 06 Nov 1994 08:49:37 GMT"
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    if parts.len() != 6 || !parts[0].ends_with(',') || parts[5] != "GMT" {
+        return None;
+    }
+
+    let day: u32 = parts[1].parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == parts[2])? as u32 + 1;
+    let year: i64 = parts[3].parse().ok()?;
+
+    let time: Vec<u64> = parts[4].split(':').map(|p| p.parse().ok()).collect::<Option<_>>()?;
+    if time.len() != 3 || time[0] > 23 || time[1] > 59 || time[2] > 60 || day == 0 || day > 31 || year < 1970 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day) as u64;
+    let secs = days * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// the two functions below come from Howard Hinnant's "chrono-Compatible Low-Level Date Algorithms"
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_and_parse() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", format(time));
+        assert_eq!(Some(time), parse("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert_eq!("Thu, 29 Feb 2024 00:00:00 GMT", format(parse("Thu, 29 Feb 2024 00:00:00 GMT").unwrap()));
+        assert_eq!("1994-11-06T08:49:37.250Z", rfc3339(time + Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn invalid_dates() {
+        assert_eq!(None, parse("Sunday, 06-Nov-94 08:49:37 GMT"));
+        assert_eq!(None, parse("Sun, 06 Nov 1994 25:49:37 GMT"));
+        assert_eq!(None, parse("yesterday"));
+    }
+}
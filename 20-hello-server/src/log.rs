@@ -0,0 +1,379 @@
+//! What the server and the pool have to say, and where it goes.
+//!
+//! Everything goes through one `Logger`, set with `set_logger`. Until then we
+//! write text lines to stderr, at `Level::Info` and above.
+
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::httpdate;
+
+/// How much a log line matters. The higher it is, the more lines we get.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // pad() so that "{:5}" lines them up
+        f.pad(self.as_str())
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    /// "info", "INFO" and "Info" are all fine.
+    fn from_str(s: &str) -> Result<Level, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("unknown log level {:?}", s)),
+        }
+    }
+}
+
+/// A field value, so that sinks like `JsonLogger` can tell numbers from text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Str(&'a str),
+    Num(u64),
+}
+
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) => f.write_str(s),
+            Value::Num(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// One log line.
+pub struct Record<'a> {
+    pub level: Level,
+    /// where it comes from, like "hello_server::pool", or "access" for the access log
+    pub target: &'a str,
+    pub message: fmt::Arguments<'a>,
+    /// the worker that was running when it was logged
+    pub worker: Option<usize>,
+    /// the request that was being served when it was logged
+    pub request: Option<u64>,
+    pub fields: &'a [(&'a str, Value<'a>)],
+}
+
+/// Where the log lines go.
+pub trait Logger: Send + Sync {
+    /// Lets us skip building a record nobody wants.
+    fn enabled(&self, level: Level) -> bool;
+
+    fn log(&self, record: &Record<'_>);
+}
+
+/// So we can keep a handle to the logger we give to `set_logger`.
+impl<L: Logger + ?Sized> Logger for Arc<L> {
+    fn enabled(&self, level: Level) -> bool {
+        (**self).enabled(level)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        (**self).log(record)
+    }
+}
+
+/// Makes `logger` the one that gets every log line from now on.
+pub fn set_logger(logger: impl Logger + 'static) {
+    *LOGGER.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(logger));
+}
+
+/// Puts `logger` in place of the one we have, and gives that one back, so a test can put it back.
+#[cfg(test)]
+pub(crate) fn replace_logger(logger: Option<Arc<dyn Logger>>) -> Option<Arc<dyn Logger>> {
+    std::mem::replace(&mut *LOGGER.write().unwrap_or_else(PoisonError::into_inner), logger)
+}
+
+static LOGGER: RwLock<Option<Arc<dyn Logger>>> = RwLock::new(None);
+static DEFAULT: StderrLogger = StderrLogger { level: Level::Info };
+
+/// Writes lines like "2024-02-29T10:00:00.000Z INFO  hello_server::pool [worker 3] Job panicked: boom".
+pub struct StderrLogger {
+    level: Level,
+}
+
+impl StderrLogger {
+    /// Logs `level` and everything more important.
+    pub fn new(level: Level) -> StderrLogger {
+        StderrLogger { level }
+    }
+}
+
+impl Logger for StderrLogger {
+    fn enabled(&self, level: Level) -> bool {
+        level <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        // a line we fail to log is not worth failing the request for
+        let _ = write_text(&mut io::stderr().lock(), record, SystemTime::now());
+    }
+}
+
+fn write_text(out: &mut impl Write, record: &Record<'_>, time: SystemTime) -> io::Result<()> {
+    write!(out, "{} {:5} {}", httpdate::rfc3339(time), record.level, record.target)?;
+    if let Some(worker) = record.worker {
+        write!(out, " [worker {}]", worker)?;
+    }
+    if let Some(request) = record.request {
+        write!(out, " [request {}]", request)?;
+    }
+    write!(out, " {}", record.message)?;
+    for (name, value) in record.fields {
+        write!(out, " {}={}", name, value)?;
+    }
+    writeln!(out)
+}
+
+/// Writes one JSON object per line, for log collectors rather than humans.
+///
+/// `{"time":"2024-02-29T10:00:00.000Z","level":"INFO","target":"access","request":7,"message":"GET /","status":200}`
+pub struct JsonLogger<W> {
+    level: Level,
+    out: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLogger<W> {
+    pub fn new(level: Level, out: W) -> JsonLogger<W> {
+        JsonLogger {
+            level,
+            out: Mutex::new(out),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<W: Write + Send> Logger for JsonLogger<W> {
+    fn enabled(&self, level: Level) -> bool {
+        level <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        let mut line = format!(
+            "{{\"time\":\"{}\",\"level\":\"{}\",\"target\":{}",
+            httpdate::rfc3339(SystemTime::now()),
+            record.level,
+            json_string(record.target)
+        );
+        if let Some(worker) = record.worker {
+            line += &format!(",\"worker\":{}", worker);
+        }
+        if let Some(request) = record.request {
+            line += &format!(",\"request\":{}", request);
+        }
+        line += &format!(",\"message\":{}", json_string(&record.message.to_string()));
+        for (name, value) in record.fields {
+            let value = match value {
+                Value::Str(s) => json_string(s),
+                Value::Num(n) => n.to_string(),
+            };
+            line += &format!(",{}:{}", json_string(name), value);
+        }
+        line += "}\n";
+
+        // one write per line, so lines from different threads don't get mixed up
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = out.write_all(line.as_bytes()).and_then(|_| out.flush());
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+thread_local! {
+    static WORKER: Cell<Option<usize>> = const { Cell::new(None) };
+    static REQUEST: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Every line logged from this thread from now on says which worker it came from.
+pub(crate) fn set_worker(id: usize) {
+    WORKER.with(|worker| worker.set(Some(id)));
+}
+
+/// Every line logged from this thread while `f` runs says which request it came from.
+pub(crate) fn with_request<T>(id: u64, f: impl FnOnce() -> T) -> T {
+    let previous = REQUEST.with(|request| request.replace(Some(id)));
+    let result = f();
+    REQUEST.with(|request| request.set(previous));
+    result
+}
+
+/// Logs a line through the logger set with `set_logger`, for code outside this crate,
+/// like the binary. `target` is usually `module_path!()`.
+pub fn log_message(level: Level, target: &str, message: fmt::Arguments<'_>) {
+    log(level, target, message, &[]);
+}
+
+pub(crate) fn log(level: Level, target: &str, message: fmt::Arguments<'_>, fields: &[(&str, Value<'_>)]) {
+    // we clone the logger so that we don't hold the lock while it logs
+    let logger = LOGGER.read().unwrap_or_else(PoisonError::into_inner).clone();
+    let logger: &dyn Logger = match &logger {
+        Some(logger) => logger.as_ref(),
+        None => &DEFAULT,
+    };
+    if !logger.enabled(level) {
+        return;
+    }
+
+    logger.log(&Record {
+        level,
+        target,
+        message,
+        worker: WORKER.with(Cell::get),
+        request: REQUEST.with(Cell::get),
+        fields,
+    });
+}
+
+/// Logs a request we answered, under the "access" target.
+pub(crate) fn access(method: &str, path: &str, status: u16, bytes: usize, latency: Duration) {
+    log(
+        Level::Info,
+        "access",
+        format_args!("{} {}", method, path),
+        &[
+            ("method", Value::Str(method)),
+            ("path", Value::Str(path)),
+            ("status", Value::Num(status.into())),
+            ("bytes", Value::Num(bytes as u64)),
+            ("latency_us", Value::Num(latency.as_micros() as u64)),
+        ],
+    );
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::log::log($crate::log::Level::Error, module_path!(), format_args!($($arg)+), &[])
+    };
+}
+
+// named warn_ because the builtin #[warn] attribute gets in the way of a plain `warn`
+macro_rules! warn_ {
+    ($($arg:tt)+) => {
+        $crate::log::log($crate::log::Level::Warn, module_path!(), format_args!($($arg)+), &[])
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::log::log($crate::log::Level::Info, module_path!(), format_args!($($arg)+), &[])
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::log::log($crate::log::Level::Debug, module_path!(), format_args!($($arg)+), &[])
+    };
+}
+
+pub(crate) use {debug, error, info, warn_ as warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn record<'a>(message: fmt::Arguments<'a>, fields: &'a [(&'a str, Value<'a>)]) -> Record<'a> {
+        Record {
+            level: Level::Warn,
+            target: "hello_server::pool",
+            message,
+            worker: Some(3),
+            request: None,
+            fields,
+        }
+    }
+
+    #[test]
+    fn text_lines() {
+        let mut out = Vec::new();
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        write_text(
+            &mut out,
+            &record(format_args!("Job panicked: {}", "boom"), &[("n", Value::Num(2))]),
+            time,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "1994-11-06T08:49:37.000Z WARN  hello_server::pool [worker 3] Job panicked: boom n=2\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn json_lines() {
+        let logger = JsonLogger::new(Level::Info, Vec::new());
+        assert!(logger.enabled(Level::Warn));
+        assert!(!logger.enabled(Level::Debug));
+
+        logger.log(&record(
+            format_args!("say \"hi\"\n"),
+            &[("path", Value::Str("/a")), ("status", Value::Num(200))],
+        ));
+
+        let line = String::from_utf8(logger.into_inner()).unwrap();
+        assert!(line.starts_with("{\"time\":\""));
+        assert!(line.ends_with(
+            "\"level\":\"WARN\",\"target\":\"hello_server::pool\",\"worker\":3,\
+             \"message\":\"say \\\"hi\\\"\\n\",\"path\":\"/a\",\"status\":200}\n"
+        ));
+    }
+
+    #[test]
+    fn levels() {
+        assert!(Level::Error < Level::Info);
+        assert_eq!(Ok(Level::Debug), "DEBUG".parse());
+        assert!("loud".parse::<Level>().is_err());
+    }
+}
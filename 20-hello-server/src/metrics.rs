@@ -0,0 +1,233 @@
+//! Numbers about how the server is doing, for Prometheus to scrape.
+//!
+//! See <https://prometheus.io/docs/instrumenting/exposition_formats/> for the format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::pool::{StatsHandle, ThreadPool};
+use crate::request::Request;
+use crate::response::{Response, StatusCode};
+use crate::router::Handler;
+
+/// the upper bounds of the latency buckets, in seconds
+const BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Counts the requests a `Server` answers, and keeps an eye on a `ThreadPool`.
+///
+/// Clones of it all share the same numbers, so one goes to the server (see
+/// `Server::metrics`) and another one serves them:
+///
+/// ```
+/// use hello_server::{Handler, Metrics, Router, ThreadPool};
+///
+/// let pool = ThreadPool::new(4);
+/// let metrics = Metrics::new();
+/// metrics.watch(&pool);
+///
+/// let mut router: Router<Handler> = Router::new();
+/// router.get("/metrics", metrics.clone().handler());
+/// ```
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    pool: Mutex<Option<StatsHandle>>,
+    /// connections open right now, `Server::run` keeps it up to date
+    open_connections: Arc<AtomicUsize>,
+    /// how many requests got each status code
+    requests: Mutex<BTreeMap<u16, u64>>,
+    /// requests that took up to each of the BUCKETS, and then the ones that took longer
+    latency: [AtomicU64; BUCKETS.len() + 1],
+    latency_sum_us: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            inner: Arc::new(Inner {
+                pool: Mutex::new(None),
+                open_connections: Arc::new(AtomicUsize::new(0)),
+                requests: Mutex::new(BTreeMap::new()),
+                latency: Default::default(),
+                latency_sum_us: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Reports the workers and the queue of `pool` too.
+    pub fn watch(&self, pool: &ThreadPool) {
+        *self.inner.pool.lock().unwrap_or_else(PoisonError::into_inner) = Some(pool.stats_handle());
+    }
+
+    /// Counts a request we answered with `status`, that took `latency` from being read to being sent.
+    pub fn record(&self, status: u16, latency: Duration) {
+        self.count(status);
+
+        let seconds = latency.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|&le| seconds <= le).unwrap_or(BUCKETS.len());
+        self.inner.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .latency_sum_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// counts a status without a latency, like the 400s for requests we couldn't even read
+    pub(crate) fn count(&self, status: u16) {
+        *self.lock_requests().entry(status).or_insert(0) += 1;
+    }
+
+    pub(crate) fn open_connections(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.inner.open_connections)
+    }
+
+    /// Every number, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let pool = self
+            .inner
+            .pool
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .map(StatsHandle::stats);
+
+        // writing to a String never fails
+        if let Some(pool) = pool {
+            let gauges = [
+                ("hello_pool_workers", "Worker threads alive.", pool.workers),
+                ("hello_pool_busy_workers", "Workers running a job.", pool.busy),
+                ("hello_pool_queue_depth", "Jobs waiting for a worker.", pool.queued),
+            ];
+            for (name, help, value) in gauges {
+                metric(&mut out, name, "gauge", help);
+                let _ = writeln!(out, "{} {}", name, value);
+            }
+            let counters = [
+                (
+                    "hello_pool_jobs_completed_total",
+                    "Jobs the workers are done with.",
+                    pool.completed,
+                ),
+                ("hello_pool_jobs_panicked_total", "Jobs that panicked.", pool.panicked),
+            ];
+            for (name, help, value) in counters {
+                metric(&mut out, name, "counter", help);
+                let _ = writeln!(out, "{} {}", name, value);
+            }
+        }
+
+        metric(&mut out, "hello_connections_open", "gauge", "Client connections open.");
+        let open = self.inner.open_connections.load(Ordering::Relaxed);
+        let _ = writeln!(out, "hello_connections_open {}", open);
+
+        metric(
+            &mut out,
+            "hello_requests_total",
+            "counter",
+            "Requests answered, by status.",
+        );
+        for (status, count) in self.lock_requests().iter() {
+            let _ = writeln!(out, "hello_requests_total{{status=\"{}\"}} {}", status, count);
+        }
+
+        let name = "hello_request_duration_seconds";
+        metric(
+            &mut out,
+            name,
+            "histogram",
+            "How long requests took, from read to sent.",
+        );
+        // each bucket counts the requests in the ones before it too
+        let mut count = 0;
+        for (i, bucket) in self.inner.latency.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = BUCKETS.get(i).map_or("+Inf".to_string(), f64::to_string);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count);
+        }
+        let sum = self.inner.latency_sum_us.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+
+        out
+    }
+
+    /// Turns this into a handler that answers with `render`.
+    pub fn handler(self) -> Handler {
+        Handler::new(move |_: &Request| {
+            Response::new(StatusCode::Ok)
+                .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+                .with_body(self.render())
+        })
+    }
+
+    fn lock_requests(&self) -> std::sync::MutexGuard<'_, BTreeMap<u16, u64>> {
+        self.inner.requests.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+/// the lines that say what a metric is, before its values
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Method;
+
+    #[test]
+    fn requests_by_status_and_latency() {
+        let metrics = Metrics::new();
+        metrics.record(200, Duration::from_micros(500));
+        metrics.record(200, Duration::from_millis(30));
+        metrics.record(404, Duration::from_secs(10));
+        metrics.count(400);
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE hello_requests_total counter\n"));
+        assert!(out.contains(
+            "hello_requests_total{status=\"200\"} 2\n\
+             hello_requests_total{status=\"400\"} 1\n\
+             hello_requests_total{status=\"404\"} 1\n"
+        ));
+        assert!(out.contains("hello_request_duration_seconds_bucket{le=\"0.001\"} 1\n"));
+        assert!(out.contains("hello_request_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(out.contains("hello_request_duration_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(out.contains("hello_request_duration_seconds_bucket{le=\"5\"} 2\n"));
+        assert!(out.contains("hello_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("hello_request_duration_seconds_sum 10.0305\n"));
+        assert!(out.contains("hello_request_duration_seconds_count 3\n"));
+        // no pool to report on
+        assert!(!out.contains("hello_pool"));
+    }
+
+    #[test]
+    fn handler_reports_the_pool() {
+        let pool = ThreadPool::new(3);
+        let metrics = Metrics::new();
+        metrics.watch(&pool);
+
+        let response = metrics.handler().call(&Request::new(Method::Get, "/metrics"));
+        assert_eq!(
+            Some("text/plain; version=0.0.4; charset=utf-8"),
+            response.headers.get("Content-Type")
+        );
+        let out = String::from_utf8(response.body).unwrap();
+        assert!(out.contains("# TYPE hello_pool_workers gauge\nhello_pool_workers 3\n"));
+        assert!(out.contains("hello_pool_jobs_panicked_total 0\n"));
+        assert!(out.contains("hello_connections_open 0\n"));
+    }
+}
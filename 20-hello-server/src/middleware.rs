@@ -0,0 +1,122 @@
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::{Handler, Router};
+
+/// Code that runs around every request a `Router` handles, see `Router::wrap`.
+///
+/// It gets the request first, and either passes it on with `next.run(request)`
+/// or answers on its own (e.g. a 401 when there's no `Authorization` header).
+/// Whatever `next.run` gives back can be changed before we return it (e.g. to add
+/// CORS headers).
+///
+/// Any `Fn(&mut Request, Next) -> Response` that can be shared between threads is a middleware.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut Request, Next<'_>) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of the chain: the middleware that come after this one, then the router.
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    router: &'a Router<Handler>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(middleware: &'a [Box<dyn Middleware>], router: &'a Router<Handler>) -> Next<'a> {
+        Next { middleware, router }
+    }
+
+    /// Passes the request on, and gives back the response the rest of the chain made.
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(request, Next::new(rest, self.router)),
+            None => self.router.dispatch(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Method;
+    use crate::response::StatusCode;
+    use std::sync::{Arc, Mutex};
+
+    fn router() -> Router<Handler> {
+        let mut router = Router::new();
+        router.get("/", |_: &Request| Response::text(StatusCode::Ok, "hi"));
+        router
+    }
+
+    #[test]
+    fn wraps_in_the_order_they_are_added() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let tracing = |name: &'static str| {
+            let calls = Arc::clone(&calls);
+            move |request: &mut Request, next: Next<'_>| {
+                calls.lock().unwrap().push(format!("{} in", name));
+                let response = next.run(request);
+                calls.lock().unwrap().push(format!("{} out", name));
+                response
+            }
+        };
+
+        let mut router = router();
+        router.wrap(tracing("outer")).wrap(tracing("inner"));
+
+        assert_eq!(
+            StatusCode::Ok,
+            router.handle(&mut Request::new(Method::Get, "/")).status
+        );
+        assert_eq!(
+            vec!["outer in", "inner in", "inner out", "outer out"],
+            *calls.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn can_answer_without_the_router() {
+        let mut router = router();
+        router.wrap(|request: &mut Request, next: Next<'_>| {
+            if request.header("Authorization") != Some("Bearer secret") {
+                return Response::text(StatusCode::Unauthorized, "Unauthorized");
+            }
+            next.run(request)
+        });
+
+        let mut request = Request::new(Method::Get, "/");
+        assert_eq!(StatusCode::Unauthorized, router.handle(&mut request).status);
+
+        request.headers.insert("Authorization", "Bearer secret");
+        assert_eq!(b"hi".to_vec(), router.handle(&mut request).body);
+    }
+
+    #[test]
+    fn can_change_the_request_and_the_response() {
+        let mut router: Router<Handler> = Router::new();
+        router.get("/users/:id", |request: &Request| {
+            Response::text(StatusCode::Ok, request.header("X-User").unwrap_or("nobody"))
+        });
+        router.wrap(|request: &mut Request, next: Next<'_>| {
+            request.headers.insert("X-User", "andre");
+            next.run(request).with_header("Access-Control-Allow-Origin", "*")
+        });
+
+        let response = router.handle(&mut Request::new(Method::Get, "/users/1"));
+        assert_eq!(b"andre".to_vec(), response.body);
+        assert_eq!(Some("*"), response.headers.get("Access-Control-Allow-Origin"));
+
+        // the router's own answers go through the middleware too
+        let response = router.handle(&mut Request::new(Method::Get, "/nope"));
+        assert_eq!(StatusCode::NotFound, response.status);
+        assert_eq!(Some("*"), response.headers.get("Access-Control-Allow-Origin"));
+    }
+}
@@ -0,0 +1,1300 @@
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::executor::{blocking_task, BlockingTask};
+use crate::log::{self, debug, error, info, warn};
+
+mod queue;
+mod timer;
+
+pub use queue::QueuePolicy;
+use queue::{JobQueue, Pop};
+pub use timer::TimerHandle;
+use timer::{Task, Timer};
+
+/// Called with the worker id and the panic message when a job panics.
+type PanicHook = Arc<dyn Fn(usize, &str) + Send + Sync + 'static>;
+
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    supervisor: Option<Supervisor>,
+    timer: Mutex<TimerSlot>,
+}
+
+/// the timer thread only starts when the first job is scheduled
+enum TimerSlot {
+    NotStarted,
+    Running(Timer),
+    Stopped,
+}
+
+impl ThreadPool {
+    /// Creates a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero, or if the OS can't give us threads.
+    /// Use `build` to get an error instead.
+    pub fn new(size: usize) -> ThreadPool {
+        match ThreadPool::build(size) {
+            Ok(pool) => pool,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Creates a new ThreadPool with `size` threads, or tells why it couldn't.
+    ///
+    /// Its queue has no limit, use `builder` to bound it.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::builder().size(size).build()
+    }
+
+    /// Lets us configure the pool before creating it.
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::default()
+    }
+
+    fn start(sizing: Sizing, queue: JobQueue) -> Result<ThreadPool, PoolCreationError> {
+        let (events, supervisor_events) = mpsc::channel();
+
+        // Arc: the pool, the supervisor and all the workers own it
+        let shared = Arc::new(Shared {
+            queue,
+            panic_hook: RwLock::new(None),
+            workers: Mutex::new(Vec::with_capacity(sizing.max)),
+            threads: AtomicUsize::new(sizing.min),
+            next_id: AtomicUsize::new(0),
+            busy: AtomicUsize::new(0),
+            completed: AtomicU64::new(0),
+            panicked: AtomicU64::new(0),
+            sizing,
+            events,
+        });
+
+        // if we fail halfway, closing the queue stops the workers we already started
+        let failed = |e| {
+            shared.queue.close();
+            PoolCreationError::Spawn(e)
+        };
+
+        for _ in 0..sizing.min {
+            shared.add_worker().map_err(failed)?;
+        }
+
+        let supervisor = Supervisor::new(Arc::clone(&shared), supervisor_events).map_err(failed)?;
+
+        Ok(ThreadPool {
+            shared,
+            supervisor: Some(supervisor),
+            timer: Mutex::new(TimerSlot::NotStarted),
+        })
+    }
+
+    /// Queues a job to run in one of the workers.
+    ///
+    /// Fails once the pool has started shutting down. When the queue is full,
+    /// what happens depends on the pool's `QueuePolicy`. With `CallerRuns`, the
+    /// job runs right here, so if it panics, the panic is ours.
+    pub fn execute<F>(&self, func: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // Send to transfer the given closure from one thread to another
+        // 'static because we don't know how long the thread will take to execute
+        self.shared.execute(Box::new(func))
+    }
+
+    /// Claims room in the queue for a job to come, without waiting for it or running anything
+    /// here, whatever the policy. Fails with `QueueFull` when there's none, so the caller still
+    /// has whatever the job would have taken, to deal with some other way.
+    pub(crate) fn reserve(&self) -> Result<Reserved<'_>, ExecuteError> {
+        match self.shared.queue.reserve()? {
+            Some(was_empty) => Ok(Reserved {
+                shared: &self.shared,
+                was_empty,
+                filled: false,
+            }),
+            None => Err(ExecuteError::QueueFull),
+        }
+    }
+
+    /// Like `execute`, but gives us a handle to wait for what the job returns.
+    pub fn spawn<F, T>(&self, func: F) -> Result<JobHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        self.execute(move || {
+            // the panic goes to the handle, so it is not reported to the panic hook
+            let result = panic::catch_unwind(AssertUnwindSafe(func));
+            let _ = sender.send(result);
+        })?;
+
+        Ok(JobHandle { receiver })
+    }
+
+    /// Runs a job once `delay` has passed.
+    ///
+    /// The job waits in a timer, not in the queue: when it's due, it's queued like
+    /// with `execute`, following the queue policy. Jobs not due yet when the pool
+    /// shuts down never run.
+    pub fn execute_after<F>(&self, delay: Duration, func: F) -> Result<TimerHandle, ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.schedule(Instant::now() + delay, Task::Once(Box::new(func)))
+    }
+
+    /// Runs a job every `period`, starting one period from now, until it's cancelled
+    /// or the pool shuts down.
+    ///
+    /// A run that takes longer than `period` may overlap with the next one.
+    ///
+    /// # Panics
+    ///
+    /// If `period` is zero.
+    pub fn execute_every<F>(&self, period: Duration, func: F) -> Result<TimerHandle, ExecuteError>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        assert!(!period.is_zero(), "execute_every needs a period above zero");
+        self.schedule(Instant::now() + period, Task::Every(Arc::new(func), period))
+    }
+
+    fn schedule(&self, at: Instant, task: Task) -> Result<TimerHandle, ExecuteError> {
+        let mut timer = lock(&self.timer);
+        if let TimerSlot::NotStarted = *timer {
+            match Timer::new(Arc::clone(&self.shared)) {
+                Ok(started) => *timer = TimerSlot::Running(started),
+                Err(e) => {
+                    error!("Failed to start the timer thread: {}", e);
+                    return Err(ExecuteError::NoTimer);
+                }
+            }
+        }
+
+        match &*timer {
+            TimerSlot::Running(timer) => Ok(timer.schedule(at, task)),
+            _ => Err(ExecuteError::ShuttingDown),
+        }
+    }
+
+    /// Sets what we do when a job panics. By default, we print the panic message.
+    ///
+    /// A panicking job never takes its worker down: the worker reports it here
+    /// and moves on to the next job.
+    pub fn set_panic_hook<F>(&self, hook: F)
+    where
+        F: Fn(usize, &str) + Send + Sync + 'static,
+    {
+        *self.shared.panic_hook.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(hook));
+    }
+
+    /// How many worker threads are alive right now.
+    pub fn live_workers(&self) -> usize {
+        self.shared.stats().workers
+    }
+
+    /// How many jobs are waiting for a worker right now.
+    pub fn queue_depth(&self) -> usize {
+        self.shared.queue.len()
+    }
+
+    /// How busy the pool is right now, and how many jobs it ran so far.
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
+
+    /// something that keeps giving us `stats`, for whoever can't hold on to the pool
+    pub(crate) fn stats_handle(&self) -> StatsHandle {
+        StatsHandle(Arc::clone(&self.shared))
+    }
+
+    /// A handle that queues jobs here too, for code that can't hold on to the pool, like async handlers.
+    pub fn handle(&self) -> PoolHandle {
+        PoolHandle(Arc::clone(&self.shared))
+    }
+}
+
+/// What the pool is up to, see `ThreadPool::stats`.
+///
+/// Jobs that `execute` runs on the caller's thread (see `QueuePolicy::CallerRuns`) are not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// worker threads alive
+    pub workers: usize,
+    /// workers running a job
+    pub busy: usize,
+    /// jobs waiting for a worker
+    pub queued: usize,
+    /// jobs the workers are done with, the ones that panicked too
+    pub completed: u64,
+    /// jobs that panicked
+    pub panicked: u64,
+}
+
+/// see `ThreadPool::stats_handle`
+pub(crate) struct StatsHandle(Arc<Shared>);
+
+impl StatsHandle {
+    pub(crate) fn stats(&self) -> PoolStats {
+        self.0.stats()
+    }
+}
+
+/// Queues jobs on a `ThreadPool`, see `ThreadPool::handle`.
+///
+/// It doesn't keep the workers around: once the pool shuts down, it gets
+/// `ExecuteError::ShuttingDown` like the pool would.
+#[derive(Clone)]
+pub struct PoolHandle(Arc<Shared>);
+
+impl PoolHandle {
+    /// Same as `ThreadPool::execute`.
+    pub fn execute<F>(&self, func: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.execute(Box::new(func))
+    }
+
+    /// Runs blocking work, like a `thread::sleep` or reading a file, for async code.
+    ///
+    /// Awaiting the task gives us what `func` returned, or its panic. Meanwhile, the
+    /// future that awaits it lets its thread sleep, see `block_on`.
+    pub fn spawn_blocking<F, T>(&self, func: F) -> Result<BlockingTask<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (task, done) = blocking_task();
+        self.execute(move || {
+            // the panic goes to the task, so it is not reported to the panic hook
+            done.complete(panic::catch_unwind(AssertUnwindSafe(func)));
+        })?;
+        Ok(task)
+    }
+}
+
+/// Configures a `ThreadPool`, see `ThreadPool::builder`.
+///
+/// By default, the pool has one thread per CPU, always, and an unbounded queue.
+#[derive(Debug, Clone)]
+pub struct ThreadPoolBuilder {
+    sizing: Sizing,
+    queue_capacity: Option<usize>,
+    queue_policy: QueuePolicy,
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> ThreadPoolBuilder {
+        let cpus = thread::available_parallelism().map_or(4, |n| n.get());
+        ThreadPoolBuilder {
+            sizing: Sizing {
+                min: cpus,
+                max: cpus,
+                keep_alive: Duration::from_secs(60),
+            },
+            queue_capacity: None,
+            queue_policy: QueuePolicy::Block,
+        }
+    }
+}
+
+impl ThreadPoolBuilder {
+    /// A fixed number of threads: the pool never grows or shrinks.
+    pub fn size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.sizing.min = size;
+        self.sizing.max = size;
+        self
+    }
+
+    /// The threads we keep even when there's nothing to do. They start with the pool.
+    pub fn min_threads(mut self, min: usize) -> ThreadPoolBuilder {
+        self.sizing.min = min;
+        self
+    }
+
+    /// The pool grows up to this many threads when jobs wait for a worker.
+    pub fn max_threads(mut self, max: usize) -> ThreadPoolBuilder {
+        self.sizing.max = max;
+        self
+    }
+
+    /// How long a thread above `min_threads` waits for a job before exiting. A minute by default.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.sizing.keep_alive = keep_alive;
+        self
+    }
+
+    /// At most this many jobs wait in the queue, the next ones get the `queue_policy`.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// What `execute` does when the queue is full. `Block` by default.
+    pub fn queue_policy(mut self, policy: QueuePolicy) -> ThreadPoolBuilder {
+        self.queue_policy = policy;
+        self
+    }
+
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        if self.sizing.max == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+        if self.sizing.min > self.sizing.max {
+            return Err(PoolCreationError::MinAboveMax);
+        }
+        if self.queue_capacity == Some(0) {
+            return Err(PoolCreationError::ZeroCapacity);
+        }
+
+        ThreadPool::start(self.sizing, JobQueue::new(self.queue_capacity, self.queue_policy))
+    }
+}
+
+/// how many threads a pool may have
+#[derive(Debug, Clone, Copy)]
+struct Sizing {
+    min: usize,
+    max: usize,
+    keep_alive: Duration,
+}
+
+/// Why `ThreadPool::build` failed.
+#[derive(Debug)]
+pub enum PoolCreationError {
+    ZeroSize,
+    /// `min_threads` can't be more than `max_threads`
+    MinAboveMax,
+    /// a bounded queue must fit at least one job
+    ZeroCapacity,
+    /// the OS refused to give us a thread
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => f.write_str("a thread pool needs at least one thread"),
+            PoolCreationError::MinAboveMax => f.write_str("a thread pool can't have more min threads than max threads"),
+            PoolCreationError::ZeroCapacity => f.write_str("a bounded job queue needs room for at least one job"),
+            PoolCreationError::Spawn(e) => write!(f, "failed to start a worker thread: {}", e),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::Spawn(e) => Some(e),
+            PoolCreationError::ZeroSize | PoolCreationError::MinAboveMax | PoolCreationError::ZeroCapacity => None,
+        }
+    }
+}
+
+/// Why the pool refused a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    ShuttingDown,
+    /// the queue is full and the pool's policy is `Reject`
+    QueueFull,
+    /// the OS refused to give us a thread for the jobs that run later
+    NoTimer,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::ShuttingDown => f.write_str("the thread pool is shutting down"),
+            ExecuteError::QueueFull => f.write_str("the thread pool's job queue is full"),
+            ExecuteError::NoTimer => f.write_str("failed to start the thread pool's timer"),
+        }
+    }
+}
+
+impl Error for ExecuteError {}
+
+/// Room for a job in the queue, from `ThreadPool::reserve`. Dropping it gives the room back.
+pub(crate) struct Reserved<'a> {
+    shared: &'a Arc<Shared>,
+    was_empty: bool,
+    filled: bool,
+}
+
+impl Reserved<'_> {
+    /// Queues the job in the room we have.
+    pub(crate) fn execute<F>(mut self, func: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.queue.fill(Box::new(func), self.was_empty);
+        self.filled = true;
+        self.shared.grow();
+    }
+}
+
+impl Drop for Reserved<'_> {
+    fn drop(&mut self) {
+        if !self.filled {
+            self.shared.queue.unreserve();
+        }
+    }
+}
+
+/// Lets us wait for the value returned by a job given to `ThreadPool::spawn`.
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<thread::Result<T>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Blocks until the job is over and gives us its return value, or its panic.
+    pub fn join(self) -> Result<T, JobError> {
+        match self.receiver.recv() {
+            Ok(result) => result.map_err(JobError::Panicked),
+            Err(_) => Err(JobError::Dropped),
+        }
+    }
+
+    /// Gives us the result if the job is over, or the handle back if it isn't yet.
+    pub fn try_join(self) -> Result<Result<T, JobError>, JobHandle<T>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Ok(result.map_err(JobError::Panicked)),
+            Err(mpsc::TryRecvError::Disconnected) => Ok(Err(JobError::Dropped)),
+            Err(mpsc::TryRecvError::Empty) => Err(self),
+        }
+    }
+}
+
+/// Why a job didn't give us a value.
+#[derive(Debug)]
+pub enum JobError {
+    /// the job panicked, here is what it panicked with
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// the job was thrown away without running
+    Dropped,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Panicked(payload) => write!(f, "job panicked: {}", panic_message(payload.as_ref())),
+            JobError::Dropped => f.write_str("job was dropped before it ran"),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+/// What happened to the workers when the pool was shut down.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// ids of the workers that finished all their work
+    pub finished: Vec<usize>,
+    /// ids of the workers that were still running a job when the deadline passed
+    pub busy: Vec<usize>,
+}
+
+impl ShutdownReport {
+    pub fn is_clean(&self) -> bool {
+        self.busy.is_empty()
+    }
+}
+
+impl ThreadPool {
+    /// Stops the pool, giving the workers up to `deadline` to finish their jobs.
+    ///
+    /// Jobs already in the queue still run. Workers that are still busy when
+    /// the deadline passes are left behind (their threads are detached) and
+    /// reported, so the caller can decide what to do, like exiting anyway.
+    pub fn shutdown(mut self, deadline: Duration) -> ShutdownReport {
+        self.terminate();
+
+        let start = Instant::now();
+        while lock(&self.shared.workers).iter().any(|w| !w.is_finished()) && start.elapsed() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut report = ShutdownReport::default();
+
+        // draining the workers leaves nothing for Drop to do
+        for mut worker in lock(&self.shared.workers).drain(..) {
+            if worker.is_finished() {
+                if let Some(thread) = worker.thread.take() {
+                    // a worker that died on a panic already said so, no need to panic again
+                    let _ = thread.join();
+                }
+                report.finished.push(worker.id);
+            } else {
+                warn!("Worker {} is still busy, leaving it behind.", worker.id);
+                report.busy.push(worker.id);
+            }
+        }
+
+        report
+    }
+
+    fn terminate(&mut self) {
+        // first the supervisor, so it doesn't bring back or remove workers we are stopping
+        if let Some(supervisor) = self.supervisor.take() {
+            supervisor.stop();
+        }
+
+        // then the timer, from now on the queue would refuse its jobs anyway
+        if let TimerSlot::Running(timer) = mem::replace(&mut *lock(&self.timer), TimerSlot::Stopped) {
+            timer.stop();
+        }
+
+        info!("Closing the job queue.");
+
+        // no new jobs from now on. Workers finish the ones in the queue, then stop
+        self.shared.queue.close();
+    }
+}
+
+/// when the pool is dropped, main thread join all workers to make sure they finish their work.
+/// unlike `shutdown`, there's no deadline here: we wait for as long as it takes.
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // nothing left to do if `shutdown` already took care of the workers
+        if self.supervisor.is_none() {
+            return;
+        }
+
+        self.terminate();
+
+        info!("Shutting down all workers.");
+
+        // we don't hold the lock while joining, a job may still want it, e.g. to read the `stats`
+        let workers = mem::take(&mut *lock(&self.shared.workers));
+        for mut worker in workers {
+            debug!("Shutting down worker {}", worker.id);
+
+            // we use 'take' to move the thread out of the Option, leaving None in the worker
+            if let Some(thread) = worker.thread.take() {
+                // this blocks untill worker finishes its job, then the worker finds the
+                // queue closed and empty, which breaks its loop (see Worker::new)
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// what the pool, its workers and the supervisor share
+struct Shared {
+    queue: JobQueue,
+    panic_hook: RwLock<Option<PanicHook>>,
+    workers: Mutex<Vec<Worker>>,
+    /// workers that are running or about to, it moves before `workers` does
+    threads: AtomicUsize,
+    /// ids are never reused, so a log line always points to a single thread
+    next_id: AtomicUsize,
+    /// workers running a job right now
+    busy: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    sizing: Sizing,
+    /// for the workers to talk to the supervisor
+    events: mpsc::Sender<Event>,
+}
+
+impl Shared {
+    fn execute(self: &Arc<Self>, job: Job) -> Result<(), ExecuteError> {
+        match self.queue.push(job)? {
+            Some(job) => job(),
+            None => self.grow(),
+        }
+        Ok(())
+    }
+
+    fn add_worker(self: &Arc<Self>) -> io::Result<usize> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let worker = Worker::new(id, Arc::clone(self))?;
+        lock(&self.workers).push(worker);
+        Ok(id)
+    }
+
+    /// Starts one more worker if nobody is free to take the job just queued,
+    /// and we are still under `max` threads.
+    fn grow(self: &Arc<Self>) {
+        if self.queue.idle_workers() > 0 {
+            return;
+        }
+
+        let grew = self.threads.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+            if n < self.sizing.max {
+                Some(n + 1)
+            } else {
+                None
+            }
+        });
+        if grew.is_err() {
+            return;
+        }
+
+        match self.add_worker() {
+            Ok(id) => info!("Jobs are waiting, started worker {}.", id),
+            Err(e) => {
+                self.threads.fetch_sub(1, Ordering::SeqCst);
+                error!("Failed to start a new worker: {}", e);
+            }
+        }
+    }
+
+    /// Lets an idle worker go, unless we are down to `min` threads.
+    fn retire(&self) -> bool {
+        self.threads
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n > self.sizing.min {
+                    Some(n - 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            workers: lock(&self.workers).iter().filter(|w| !w.is_finished()).count(),
+            busy: self.busy.load(Ordering::Relaxed),
+            queued: self.queue.len(),
+            completed: self.completed.load(Ordering::Relaxed),
+            panicked: self.panicked.load(Ordering::Relaxed),
+        }
+    }
+
+    fn report_panic(&self, worker_id: usize, payload: &(dyn Any + Send)) {
+        let message = panic_message(payload);
+
+        // we clone the hook so that we don't hold the lock while it runs
+        let hook = self.panic_hook.read().unwrap_or_else(PoisonError::into_inner).clone();
+        match hook {
+            Some(hook) => hook(worker_id, message),
+            None => error!("Job panicked: {}", message),
+        }
+    }
+}
+
+/// panic!("...") gives us a &str, panic!("{}", x) gives us a String
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
+    }
+}
+
+/// a thread that panics while holding a Mutex poisons it, but the data in
+/// our mutexes is always left in a good state, so we just keep using it
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct Worker {
+    id: usize, // that's the recommended type for indexing collections
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, shared: Arc<Shared>) -> io::Result<Worker> {
+        let builder = thread::Builder::new().name(format!("worker-{}", id));
+        let thread = builder.spawn(move || {
+            // from now on, everything logged from this thread says it's this worker
+            log::set_worker(id);
+
+            // tells the supervisor if this thread dies
+            let sentinel = Sentinel {
+                id,
+                events: shared.events.clone(),
+            };
+
+            // our own deque in the queue, where the jobs we queue go (see JobQueue)
+            let queue = shared.queue.register();
+
+            // with a fixed size, nobody ever retires, so there's no point in waking up
+            let sizing = shared.sizing;
+            let keep_alive = if sizing.min < sizing.max {
+                Some(sizing.keep_alive)
+            } else {
+                None
+            };
+
+            loop {
+                match queue.pop(keep_alive) {
+                    Pop::Job(job) => {
+                        debug!("Executing a job.");
+                        shared.busy.fetch_add(1, Ordering::Relaxed);
+
+                        // a panicking job unwinds up to here instead of killing the thread
+                        let result = panic::catch_unwind(AssertUnwindSafe(job));
+
+                        shared.busy.fetch_sub(1, Ordering::Relaxed);
+                        shared.completed.fetch_add(1, Ordering::Relaxed);
+                        if let Err(payload) = result {
+                            shared.panicked.fetch_add(1, Ordering::Relaxed);
+                            shared.report_panic(id, payload.as_ref());
+                        }
+                    }
+                    Pop::Idle => {
+                        if shared.retire() {
+                            info!("Idle for {:?}, stopping.", sizing.keep_alive);
+                            let _ = sentinel.events.send(Event::Retired(id));
+                            break;
+                        }
+                    }
+                    Pop::Closed => {
+                        debug!("Got terminate signal.");
+                        break;
+                    }
+                }
+            }
+        })?;
+
+        Ok(Worker {
+            id,
+            thread: Some(thread),
+        })
+    }
+
+    fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(|t| t.is_finished())
+    }
+}
+
+/// Lives in the worker thread. When the thread unwinds (something panicked
+/// outside of a job, like the panic hook itself), dropping it warns the supervisor.
+struct Sentinel {
+    id: usize,
+    events: mpsc::Sender<Event>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let _ = self.events.send(Event::Died(self.id));
+        }
+    }
+}
+
+enum Event {
+    Died(usize),
+    /// the worker was idle for too long and stopped
+    Retired(usize),
+    Stop,
+}
+
+/// A thread that cleans up after the workers that stop, and replaces the ones
+/// that die, so the pool keeps as many live threads as it counts.
+struct Supervisor {
+    events: mpsc::Sender<Event>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Supervisor {
+    fn new(shared: Arc<Shared>, receiver: mpsc::Receiver<Event>) -> io::Result<Supervisor> {
+        let events = shared.events.clone();
+
+        let thread = thread::Builder::new().name("supervisor".to_string()).spawn(move || {
+            for event in receiver {
+                let (id, died) = match event {
+                    Event::Died(id) => (id, true),
+                    Event::Retired(id) => (id, false),
+                    Event::Stop => break,
+                };
+
+                // we don't hold the lock while joining, the thread may still be unwinding
+                let worker = {
+                    let mut workers = lock(&shared.workers);
+                    let index = workers.iter().position(|w| w.id == id);
+                    index.map(|index| workers.swap_remove(index))
+                };
+                if let Some(thread) = worker.and_then(|mut w| w.thread.take()) {
+                    let _ = thread.join();
+                }
+
+                if died {
+                    match shared.add_worker() {
+                        Ok(new_id) => warn!("Worker {} died, started worker {} instead.", id, new_id),
+                        Err(e) => {
+                            shared.threads.fetch_sub(1, Ordering::SeqCst);
+                            error!("Worker {} died, failed to start a new one: {}", id, e);
+                        }
+                    }
+                }
+            }
+        })?;
+
+        Ok(Supervisor { events, thread })
+    }
+
+    fn stop(self) {
+        let _ = self.events.send(Event::Stop);
+        let _ = self.thread.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn shutdown_runs_queued_jobs() {
+        let pool = ThreadPool::new(2);
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..8 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(10));
+                done.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+
+        let report = pool.shutdown(Duration::from_secs(5));
+        assert!(report.is_clean());
+        assert_eq!(vec![0, 1], report.finished);
+        assert_eq!(8, done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn shutdown_reports_busy_workers() {
+        let pool = ThreadPool::new(2);
+        pool.execute(|| thread::sleep(Duration::from_millis(500))).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let report = pool.shutdown(Duration::from_millis(50));
+        assert_eq!(1, report.busy.len());
+        assert_eq!(1, report.finished.len());
+    }
+
+    #[test]
+    fn panicking_jobs_are_reported_and_workers_survive() {
+        let pool = ThreadPool::new(1);
+        let panics = Arc::new(Mutex::new(Vec::new()));
+        let reported = Arc::clone(&panics);
+        pool.set_panic_hook(move |id, message| reported.lock().unwrap().push((id, message.to_string())));
+
+        let done = Arc::new(AtomicUsize::new(0));
+        for i in 0..3 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                if i == 1 {
+                    panic!("job {} failed", i);
+                }
+                done.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+
+        assert!(pool.shutdown(Duration::from_secs(5)).is_clean());
+        assert_eq!(2, done.load(Ordering::SeqCst));
+        assert_eq!(vec![(0, "job 1 failed".to_string())], *panics.lock().unwrap());
+    }
+
+    #[test]
+    fn jobs_can_read_the_stats_while_the_pool_drops() {
+        let pool = ThreadPool::new(1);
+        let stats = pool.stats_handle();
+        let (send, recv) = mpsc::channel();
+        pool.execute(move || {
+            thread::sleep(Duration::from_millis(50));
+            let _ = send.send(stats.stats().workers);
+        })
+        .unwrap();
+
+        // Drop joins the worker while the job runs, this used to deadlock
+        drop(pool);
+        assert!(recv.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn stats() {
+        let pool = ThreadPool::new(2);
+        pool.set_panic_hook(|_, _| {});
+        assert_eq!(
+            PoolStats {
+                workers: 2,
+                ..PoolStats::default()
+            },
+            pool.stats()
+        );
+
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = blocked.recv();
+        })
+        .unwrap();
+        pool.execute(|| panic!("boom")).unwrap();
+        pool.execute(|| {}).unwrap();
+
+        let wait_for = |done: fn(PoolStats) -> bool| {
+            let start = Instant::now();
+            while !done(pool.stats()) && start.elapsed() < Duration::from_secs(5) {
+                thread::sleep(Duration::from_millis(5));
+            }
+            pool.stats()
+        };
+
+        let stats = wait_for(|stats| stats.completed == 2);
+        assert_eq!((1, 2, 1, 0), (stats.busy, stats.completed, stats.panicked, stats.queued));
+
+        drop(release);
+        let stats = wait_for(|stats| stats.completed == 3);
+        assert_eq!((0, 3, 1), (stats.busy, stats.completed, stats.panicked));
+    }
+
+    #[test]
+    fn dead_workers_are_replaced() {
+        let pool = ThreadPool::new(2);
+        // a panic outside of a job, like in the hook, does kill the worker
+        pool.set_panic_hook(|_, _| panic!("the hook panicked too"));
+        pool.execute(|| panic!("boom")).unwrap();
+
+        let start = Instant::now();
+        thread::sleep(Duration::from_millis(50));
+        while pool.live_workers() < 2 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(2, pool.live_workers());
+
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+        assert!(pool.shutdown(Duration::from_secs(5)).is_clean());
+        assert_eq!(4, done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn build_fails_on_zero_size() {
+        assert!(matches!(ThreadPool::build(0), Err(PoolCreationError::ZeroSize)));
+    }
+
+    #[test]
+    fn spawn_returns_values_and_panics() {
+        let pool = ThreadPool::build(2).unwrap();
+
+        let answer = pool.spawn(|| 6 * 7).unwrap();
+        assert_eq!(42, answer.join().unwrap());
+
+        let failed = pool.spawn(|| -> u32 { panic!("no answer") }).unwrap();
+        match failed.join() {
+            Err(e @ JobError::Panicked(_)) => assert_eq!("job panicked: no answer", e.to_string()),
+            other => panic!("expected a panic, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn try_join_does_not_block() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (tx, rx) = mpsc::channel::<()>();
+
+        let handle = pool.spawn(move || rx.recv().map(|_| "done")).unwrap();
+        let handle = match handle.try_join() {
+            Ok(_) => panic!("the job can't be over yet"),
+            Err(handle) => handle,
+        };
+
+        tx.send(()).unwrap();
+        assert_eq!(Ok("done"), handle.join().unwrap());
+    }
+
+    #[test]
+    fn no_jobs_once_shutting_down() {
+        let mut pool = ThreadPool::build(1).unwrap();
+        pool.terminate();
+
+        assert_eq!(Err(ExecuteError::ShuttingDown), pool.execute(|| {}));
+        assert!(pool.spawn(|| 1).is_err());
+    }
+
+    /// keeps the only worker busy until the returned sender is dropped
+    fn block_worker(pool: &ThreadPool) -> mpsc::Sender<()> {
+        let (tx, rx) = mpsc::channel::<()>();
+        let (started_tx, started_rx) = mpsc::channel();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = rx.recv();
+        })
+        .unwrap();
+        started_rx.recv().unwrap();
+        tx
+    }
+
+    fn bounded(policy: QueuePolicy) -> ThreadPool {
+        ThreadPool::builder()
+            .size(1)
+            .queue_capacity(2)
+            .queue_policy(policy)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn build_fails_on_zero_capacity() {
+        let built = ThreadPool::builder().size(1).queue_capacity(0).build();
+        assert!(matches!(built, Err(PoolCreationError::ZeroCapacity)));
+    }
+
+    #[test]
+    fn reject_fails_when_the_queue_is_full() {
+        let pool = bounded(QueuePolicy::Reject);
+        let busy = block_worker(&pool);
+
+        pool.execute(|| {}).unwrap();
+        pool.execute(|| {}).unwrap();
+        assert_eq!(2, pool.queue_depth());
+        assert_eq!(Err(ExecuteError::QueueFull), pool.execute(|| {}));
+
+        drop(busy);
+        assert!(pool.shutdown(Duration::from_secs(5)).is_clean());
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let pool = bounded(QueuePolicy::DropOldest);
+        let busy = block_worker(&pool);
+
+        let oldest = pool.spawn(|| 1).unwrap();
+        let middle = pool.spawn(|| 2).unwrap();
+        let newest = pool.spawn(|| 3).unwrap();
+        assert_eq!(2, pool.queue_depth());
+
+        drop(busy);
+        assert!(matches!(oldest.join(), Err(JobError::Dropped)));
+        assert_eq!(2, middle.join().unwrap());
+        assert_eq!(3, newest.join().unwrap());
+    }
+
+    #[test]
+    fn caller_runs_when_the_queue_is_full() {
+        let pool = bounded(QueuePolicy::CallerRuns);
+        let busy = block_worker(&pool);
+
+        pool.execute(|| {}).unwrap();
+        pool.execute(|| {}).unwrap();
+
+        let caller = thread::current().id();
+        let ran_on = pool.spawn(|| thread::current().id()).unwrap();
+        assert_eq!(caller, ran_on.join().unwrap());
+
+        drop(busy);
+        assert!(pool.shutdown(Duration::from_secs(5)).is_clean());
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let pool = Arc::new(bounded(QueuePolicy::Block));
+        let busy = block_worker(&pool);
+        pool.execute(|| {}).unwrap();
+        pool.execute(|| {}).unwrap();
+
+        let (queued_tx, queued_rx) = mpsc::channel();
+        let blocked = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                pool.execute(|| {}).unwrap();
+                queued_tx.send(()).unwrap();
+            })
+        };
+
+        assert!(queued_rx.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(busy);
+        queued_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        blocked.join().unwrap();
+    }
+
+    #[test]
+    fn reserve_never_waits() {
+        for policy in [QueuePolicy::Block, QueuePolicy::DropOldest, QueuePolicy::CallerRuns] {
+            let pool = bounded(policy);
+            let busy = block_worker(&pool);
+            pool.execute(|| {}).unwrap();
+            pool.reserve().unwrap().execute(|| {});
+            assert_eq!(Some(ExecuteError::QueueFull), pool.reserve().err());
+            assert_eq!(2, pool.queue_depth());
+
+            drop(busy);
+            assert!(pool.shutdown(Duration::from_secs(5)).is_clean());
+        }
+
+        // room we don't use goes back
+        let pool = bounded(QueuePolicy::Reject);
+        let busy = block_worker(&pool);
+        drop(pool.reserve().unwrap());
+        assert_eq!(0, pool.queue_depth());
+        drop(busy);
+    }
+
+    #[test]
+    fn jobs_queued_by_a_busy_worker_are_stolen() {
+        let pool = Arc::new(ThreadPool::build(2).unwrap());
+        let inner_pool = Arc::clone(&pool);
+
+        // the outer job waits for the inner one, which sits in the outer worker's
+        // own deque, so the only way it runs is the other worker stealing it
+        let outer = pool
+            .spawn(move || {
+                let inner = inner_pool.spawn(|| thread::current().id()).unwrap();
+                (thread::current().id(), inner.join().unwrap())
+            })
+            .unwrap();
+
+        let (outer_thread, inner_thread) = outer.join().unwrap();
+        assert_ne!(outer_thread, inner_thread);
+    }
+
+    #[test]
+    fn build_fails_on_min_above_max() {
+        let built = ThreadPool::builder().min_threads(3).max_threads(2).build();
+        assert!(matches!(built, Err(PoolCreationError::MinAboveMax)));
+    }
+
+    /// runs `n` jobs that can only finish once all of them are running at the
+    /// same time, and gives us the names of the threads that ran them
+    fn run_together(pool: &ThreadPool, n: usize) -> Vec<String> {
+        let barrier = Arc::new(std::sync::Barrier::new(n));
+        let handles: Vec<_> = (0..n)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                pool.spawn(move || {
+                    barrier.wait();
+                    thread::current().name().unwrap().to_string()
+                })
+                .unwrap()
+            })
+            .collect();
+
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    }
+
+    fn wait_for_live_workers(pool: &ThreadPool, expected: usize) {
+        let start = Instant::now();
+        while pool.live_workers() != expected && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(expected, pool.live_workers());
+    }
+
+    #[test]
+    fn grows_when_jobs_wait_and_shrinks_when_idle() {
+        let pool = ThreadPool::builder()
+            .min_threads(1)
+            .max_threads(3)
+            .keep_alive(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        assert_eq!(1, pool.live_workers());
+
+        let first = run_together(&pool, 3);
+        wait_for_live_workers(&pool, 1);
+
+        // the workers we start again get new ids, only the one that stayed is in both runs
+        let second = run_together(&pool, 3);
+        let again = second.iter().filter(|name| first.contains(name)).count();
+        assert!(again <= 1, "{:?} then {:?}", first, second);
+
+        wait_for_live_workers(&pool, 1);
+        assert!(pool.shutdown(Duration::from_secs(5)).is_clean());
+    }
+
+    #[test]
+    fn execute_after_waits_for_the_delay() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+
+        pool.execute_after(Duration::from_millis(100), move || tx.send(Instant::now()).unwrap())
+            .unwrap();
+
+        let ran_at = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(ran_at - start >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn cancelled_jobs_do_not_run() {
+        let pool = ThreadPool::new(1);
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        let counter = Arc::clone(&ran);
+        let handle = pool
+            .execute_after(Duration::from_millis(50), move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        handle.cancel();
+        assert!(handle.is_cancelled());
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(0, ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn execute_every_repeats_until_cancelled() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+
+        let handle = pool
+            .execute_every(Duration::from_millis(10), move || {
+                let _ = tx.send(());
+            })
+            .unwrap();
+
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        handle.cancel();
+
+        // a run may have been queued already, but nothing after it
+        thread::sleep(Duration::from_millis(50));
+        while rx.try_recv().is_ok() {}
+        thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn nothing_is_scheduled_once_shutting_down() {
+        let mut pool = ThreadPool::new(1);
+        pool.execute_after(Duration::from_secs(60), || {}).unwrap();
+        pool.terminate();
+
+        assert!(matches!(
+            pool.execute_after(Duration::ZERO, || {}),
+            Err(ExecuteError::ShuttingDown)
+        ));
+        assert!(pool.execute_every(Duration::from_secs(1), || {}).is_err());
+    }
+
+    #[test]
+    fn starts_with_no_threads_when_min_is_zero() {
+        let pool = ThreadPool::builder().min_threads(0).max_threads(2).build().unwrap();
+        assert_eq!(0, pool.live_workers());
+
+        assert_eq!(42, pool.spawn(|| 42).unwrap().join().unwrap());
+        assert_eq!(1, pool.live_workers());
+    }
+}
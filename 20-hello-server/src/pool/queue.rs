@@ -0,0 +1,401 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{lock, ExecuteError, Job};
+use crate::log::warn;
+
+/// What `execute` does when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// wait until a worker takes a job out of the queue
+    Block,
+    /// fail with `ExecuteError::QueueFull`
+    Reject,
+    /// throw away the job that has been waiting the longest to make room
+    DropOldest,
+    /// run the job right away, in the thread that called `execute`
+    CallerRuns,
+}
+
+/// The jobs waiting for a worker.
+///
+/// Instead of one queue that every worker fights over, each worker has its own
+/// deque, plus there's a shared one (the injector) for jobs coming from outside
+/// the pool. A job queued by a job goes to its worker's deque. A worker takes
+/// jobs from the back of its own deque, then from the injector, and when both
+/// are empty it steals from the front of the other workers' deques.
+///
+/// Each deque has its own Mutex, so workers only meet on the same lock when
+/// one of them is out of work.
+pub(super) struct JobQueue {
+    injector: Mutex<VecDeque<Job>>,
+    locals: RwLock<Vec<Arc<Local>>>,
+    /// jobs in the injector plus jobs in every local deque
+    len: AtomicUsize,
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+    closed: AtomicBool,
+    sleep: Mutex<Sleep>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    /// idle workers nobody woke up yet, so `push` can skip the lock when there are none
+    idle_workers: AtomicUsize,
+    blocked_callers: AtomicUsize,
+}
+
+/// the workers waiting for a job
+struct Sleep {
+    idle: usize,
+    /// wake ups sent that no idle worker got yet. Without it, a burst of jobs
+    /// would send a wake up per job to the same sleeping worker
+    wakeups: usize,
+}
+
+/// What a worker gets from `Registration::pop`.
+pub(super) enum Pop {
+    Job(Job),
+    /// nothing to do for the whole keep alive period
+    Idle,
+    /// the queue is closed and empty, time to stop
+    Closed,
+}
+
+/// A worker's own deque.
+struct Local {
+    jobs: Mutex<VecDeque<Job>>,
+}
+
+thread_local! {
+    /// the queue (by address) and the deque of the worker running on this thread
+    static CURRENT: RefCell<Option<(usize, Arc<Local>)>> = const { RefCell::new(None) };
+    /// where this thread starts looking for a job to steal
+    static STEAL_FROM: Cell<usize> = const { Cell::new(0) };
+}
+
+/// how many extra jobs a worker moves from the injector to its deque at once
+const BATCH: usize = 32;
+
+impl JobQueue {
+    pub(super) fn new(capacity: Option<usize>, policy: QueuePolicy) -> JobQueue {
+        JobQueue {
+            injector: Mutex::new(VecDeque::new()),
+            locals: RwLock::new(Vec::new()),
+            len: AtomicUsize::new(0),
+            capacity,
+            policy,
+            closed: AtomicBool::new(false),
+            sleep: Mutex::new(Sleep { idle: 0, wakeups: 0 }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            idle_workers: AtomicUsize::new(0),
+            blocked_callers: AtomicUsize::new(0),
+        }
+    }
+
+    /// Gives the calling thread, a worker, its own deque.
+    ///
+    /// When the returned `Registration` is dropped (the worker stops or dies),
+    /// the jobs left in the deque go back to the injector.
+    pub(super) fn register(&self) -> Registration<'_> {
+        let local = Arc::new(Local {
+            jobs: Mutex::new(VecDeque::new()),
+        });
+        self.locals
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Arc::clone(&local));
+        CURRENT.with(|current| *current.borrow_mut() = Some((self.id(), Arc::clone(&local))));
+
+        Registration { queue: self, local }
+    }
+
+    /// Queues a job, following the policy when the queue is full.
+    ///
+    /// With `CallerRuns`, a job that doesn't fit is handed back for the caller to run.
+    pub(super) fn push(&self, job: Job) -> Result<Option<Job>, ExecuteError> {
+        let was_empty = loop {
+            if let Some(was_empty) = self.reserve()? {
+                break was_empty;
+            }
+
+            match self.policy {
+                QueuePolicy::Block => self.wait_for_room(),
+                QueuePolicy::Reject => return Err(ExecuteError::QueueFull),
+                QueuePolicy::DropOldest => {
+                    // we take the place of the job we drop, so `len` stays the same
+                    if let Some(oldest) = self.steal(None) {
+                        warn!("Job queue is full, dropping the oldest job.");
+                        drop(oldest);
+                        break false;
+                    }
+                }
+                QueuePolicy::CallerRuns => return Ok(Some(job)),
+            }
+        };
+
+        self.fill(job, was_empty);
+        Ok(None)
+    }
+
+    /// Claims room for a job, whatever the policy, and says whether the queue was empty.
+    ///
+    /// `None` when the queue is full. The room has to be filled with `fill`, or given back with `unreserve`.
+    pub(super) fn reserve(&self) -> Result<Option<bool>, ExecuteError> {
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(ExecuteError::ShuttingDown);
+            }
+
+            let len = self.len.load(Ordering::SeqCst);
+            if self.capacity.is_some_and(|capacity| len >= capacity) {
+                return Ok(None);
+            }
+            // someone else may take the room we saw, so we claim it before using it
+            if self
+                .len
+                .compare_exchange(len, len + 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                continue;
+            }
+            // the queue may have been closed in between, and then nobody would run the job
+            if self.closed.load(Ordering::SeqCst) {
+                self.len.fetch_sub(1, Ordering::SeqCst);
+                self.wake_all();
+                return Err(ExecuteError::ShuttingDown);
+            }
+            return Ok(Some(len == 0));
+        }
+    }
+
+    /// puts a job in the room `reserve` claimed
+    pub(super) fn fill(&self, job: Job, was_empty: bool) {
+        self.enqueue(job);
+        // when the queue already had jobs, the worker that takes the next one wakes
+        // another worker up (see `pop`), so we don't pay for a wake up on every job
+        if was_empty {
+            self.wake_one();
+        }
+    }
+
+    /// gives back room `reserve` claimed that won't be filled
+    pub(super) fn unreserve(&self) {
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        // someone may be waiting for the room, or for the queue to empty out
+        self.wake_all();
+    }
+
+    /// Stops accepting jobs and wakes up everyone waiting on the queue.
+    pub(super) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.wake_all();
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    /// workers waiting for a job that nobody woke up yet
+    pub(super) fn idle_workers(&self) -> usize {
+        self.idle_workers.load(Ordering::SeqCst)
+    }
+
+    /// Takes the next job for the worker that owns `local`, waiting for one if needed,
+    /// but no longer than `keep_alive`.
+    fn pop(&self, local: &Local, keep_alive: Option<Duration>) -> Pop {
+        let deadline = keep_alive.map(|keep_alive| Instant::now() + keep_alive);
+
+        loop {
+            if let Some(job) = self.find_job(local) {
+                let left = self.len.fetch_sub(1, Ordering::SeqCst) - 1;
+                if left > 0 {
+                    self.wake_one();
+                }
+                if self.blocked_callers.load(Ordering::SeqCst) > 0 {
+                    let _guard = lock(&self.sleep);
+                    self.not_full.notify_one();
+                }
+                return Pop::Job(job);
+            }
+
+            let closed = self.closed.load(Ordering::SeqCst);
+            let len = self.len.load(Ordering::SeqCst);
+            if closed && len == 0 {
+                return Pop::Closed;
+            }
+            if len > 0 {
+                // a job is on its way into a deque, it will be there in a moment
+                thread::yield_now();
+                continue;
+            }
+
+            // we count ourselves idle before looking at `len` again, so a `push`
+            // either sees us idle and wakes us up, or we see its job
+            let mut sleep = lock(&self.sleep);
+            sleep.idle += 1;
+            self.idle_workers.fetch_add(1, Ordering::SeqCst);
+
+            let mut timed_out = false;
+            while sleep.wakeups == 0 && self.len.load(Ordering::SeqCst) == 0 && !self.closed.load(Ordering::SeqCst) {
+                sleep = match deadline {
+                    None => self.not_empty.wait(sleep).unwrap_or_else(PoisonError::into_inner),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            timed_out = true;
+                            break;
+                        }
+                        let (sleep, _) = self
+                            .not_empty
+                            .wait_timeout(sleep, deadline - now)
+                            .unwrap_or_else(PoisonError::into_inner);
+                        sleep
+                    }
+                };
+            }
+
+            sleep.idle -= 1;
+            if sleep.wakeups > 0 {
+                // whoever woke us up already took us out of `idle_workers`
+                sleep.wakeups -= 1;
+            } else {
+                self.idle_workers.fetch_sub(1, Ordering::SeqCst);
+            }
+
+            if timed_out {
+                return Pop::Idle;
+            }
+        }
+    }
+
+    /// our own jobs first, newest first since it's likely still in the CPU cache,
+    /// then the injector, then the other workers' jobs
+    fn find_job(&self, local: &Local) -> Option<Job> {
+        if let Some(job) = lock(&local.jobs).pop_back() {
+            return Some(job);
+        }
+
+        let mut injector = lock(&self.injector);
+        if let Some(job) = injector.pop_front() {
+            // taking a few more now saves us coming back to the shared lock for each of them
+            let extra = (injector.len() / 2).min(BATCH);
+            if extra > 0 {
+                lock(&local.jobs).extend(injector.drain(..extra));
+            }
+            return Some(job);
+        }
+        drop(injector);
+
+        self.steal(Some(local))
+    }
+
+    /// takes the oldest job from the injector, or from the deque of any worker but `skip`
+    fn steal(&self, skip: Option<&Local>) -> Option<Job> {
+        if let Some(job) = lock(&self.injector).pop_front() {
+            return Some(job);
+        }
+
+        let locals = self.locals.read().unwrap_or_else(PoisonError::into_inner);
+        // every thief starts at a different deque, so they don't all pile on the first one
+        let start = STEAL_FROM.with(|next| {
+            let start = next.get();
+            next.set(start.wrapping_add(1));
+            start
+        });
+
+        for i in 0..locals.len() {
+            let victim = &locals[(start + i) % locals.len()];
+            if skip.is_some_and(|local| ptr::eq(local, victim.as_ref())) {
+                continue;
+            }
+            if let Some(job) = lock(&victim.jobs).pop_front() {
+                return Some(job);
+            }
+        }
+        None
+    }
+
+    /// into the worker's deque when one of our workers calls us, into the injector otherwise
+    fn enqueue(&self, job: Job) {
+        let job = CURRENT.with(|current| match &*current.borrow() {
+            Some((queue, local)) if *queue == self.id() => {
+                lock(&local.jobs).push_back(job);
+                None
+            }
+            _ => Some(job),
+        });
+
+        if let Some(job) = job {
+            lock(&self.injector).push_back(job);
+        }
+    }
+
+    fn wait_for_room(&self) {
+        let guard = lock(&self.sleep);
+        self.blocked_callers.fetch_add(1, Ordering::SeqCst);
+        let full = self
+            .capacity
+            .is_some_and(|capacity| self.len.load(Ordering::SeqCst) >= capacity);
+        if full && !self.closed.load(Ordering::SeqCst) {
+            drop(self.not_full.wait(guard).unwrap_or_else(PoisonError::into_inner));
+        }
+        self.blocked_callers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn wake_one(&self) {
+        if self.idle_workers.load(Ordering::SeqCst) > 0 {
+            let mut sleep = lock(&self.sleep);
+            if sleep.idle > sleep.wakeups {
+                sleep.wakeups += 1;
+                self.idle_workers.fetch_sub(1, Ordering::SeqCst);
+                self.not_empty.notify_one();
+            }
+        }
+    }
+
+    fn wake_all(&self) {
+        let _guard = lock(&self.sleep);
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    fn id(&self) -> usize {
+        self as *const JobQueue as usize
+    }
+}
+
+/// A worker's place in the queue, see `JobQueue::register`.
+pub(super) struct Registration<'a> {
+    queue: &'a JobQueue,
+    local: Arc<Local>,
+}
+
+impl Registration<'_> {
+    pub(super) fn pop(&self, keep_alive: Option<Duration>) -> Pop {
+        self.queue.pop(&self.local, keep_alive)
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = None);
+        self.queue
+            .locals
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|local| !Arc::ptr_eq(local, &self.local));
+
+        // nobody would steal these anymore
+        let left = mem::take(&mut *lock(&self.local.jobs));
+        if !left.is_empty() {
+            lock(&self.queue.injector).extend(left);
+            self.queue.wake_all();
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{lock, panic_message, Job, Shared};
+use crate::log::{error, warn};
+
+/// Lets us cancel a job given to `ThreadPool::execute_after` or `ThreadPool::execute_every`.
+///
+/// Dropping the handle doesn't cancel the job, just like dropping a `JoinHandle`
+/// doesn't stop a thread.
+#[derive(Debug, Clone)]
+pub struct TimerHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TimerHandle {
+    /// Makes sure the job doesn't run again. A run that already started still finishes.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+pub(super) enum Task {
+    Once(Job),
+    Every(Arc<dyn Fn() + Send + Sync + 'static>, Duration),
+}
+
+/// A thread that sleeps until the next scheduled job is due, then queues it on the pool.
+///
+/// The jobs wait in a heap ordered by when they are due, so a single thread
+/// serves any number of them.
+pub(super) struct Timer {
+    state: Arc<(Mutex<State>, Condvar)>,
+    thread: thread::JoinHandle<()>,
+}
+
+struct State {
+    entries: BinaryHeap<Entry>,
+    /// tells apart entries due at the same instant, so they run in the order they were scheduled
+    next_seq: u64,
+    stopped: bool,
+}
+
+struct Entry {
+    at: Instant,
+    seq: u64,
+    task: Task,
+    cancelled: Arc<AtomicBool>,
+}
+
+// BinaryHeap gives us the biggest entry first, so the soonest one has to be the "biggest"
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> CmpOrdering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl Timer {
+    pub(super) fn new(shared: Arc<Shared>) -> io::Result<Timer> {
+        let state = Arc::new((
+            Mutex::new(State {
+                entries: BinaryHeap::new(),
+                next_seq: 0,
+                stopped: false,
+            }),
+            Condvar::new(),
+        ));
+        let timer_state = Arc::clone(&state);
+
+        let thread = thread::Builder::new().name("timer".to_string()).spawn(move || {
+            let (state, wake_up) = &*timer_state;
+            let mut guard = lock(state);
+
+            loop {
+                if guard.stopped {
+                    break;
+                }
+
+                let now = Instant::now();
+                let wait = match guard.entries.peek() {
+                    None => None,
+                    Some(entry) if entry.at > now => Some(entry.at - now),
+                    Some(_) => {
+                        let entry = guard.entries.pop().unwrap();
+                        // queueing may block (see QueuePolicy::Block), so not while holding the lock
+                        drop(guard);
+                        let next = run(&shared, entry);
+                        guard = lock(state);
+                        if let Some(next) = next {
+                            guard.entries.push(next);
+                        }
+                        continue;
+                    }
+                };
+
+                guard = match wait {
+                    None => wake_up.wait(guard).unwrap_or_else(PoisonError::into_inner),
+                    Some(wait) => {
+                        wake_up
+                            .wait_timeout(guard, wait)
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .0
+                    }
+                };
+            }
+        })?;
+
+        Ok(Timer { state, thread })
+    }
+
+    pub(super) fn schedule(&self, at: Instant, task: Task) -> TimerHandle {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (state, wake_up) = &*self.state;
+
+        let mut state = lock(state);
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.entries.push(Entry {
+            at,
+            seq,
+            task,
+            cancelled: Arc::clone(&cancelled),
+        });
+        // the new entry may be due before the one the timer is sleeping for
+        wake_up.notify_one();
+
+        TimerHandle { cancelled }
+    }
+
+    /// Stops the timer thread. Jobs that are not due yet never run.
+    pub(super) fn stop(self) {
+        let (state, wake_up) = &*self.state;
+        lock(state).stopped = true;
+        wake_up.notify_one();
+        let _ = self.thread.join();
+    }
+}
+
+/// queues a due entry on the pool, and gives it back when it has to run again
+fn run(shared: &Arc<Shared>, entry: Entry) -> Option<Entry> {
+    if entry.cancelled.load(Ordering::SeqCst) {
+        return None;
+    }
+
+    // it may be cancelled while it waits in the queue, so we check again right before running it
+    let cancelled = Arc::clone(&entry.cancelled);
+    let (job, next): (Job, _) = match entry.task {
+        Task::Once(job) => (
+            Box::new(move || {
+                if !cancelled.load(Ordering::SeqCst) {
+                    job();
+                }
+            }),
+            None,
+        ),
+        Task::Every(func, period) => {
+            let job_func = Arc::clone(&func);
+            let job = Box::new(move || {
+                if !cancelled.load(Ordering::SeqCst) {
+                    job_func();
+                }
+            });
+            // when we fall behind, we skip the runs we missed instead of running them all at once
+            let at = (entry.at + period).max(Instant::now());
+            let next = Entry {
+                at,
+                task: Task::Every(func, period),
+                ..entry
+            };
+            (job, Some(next))
+        }
+    };
+
+    match shared.queue.push(job) {
+        Ok(None) => shared.grow(),
+        // the queue is full and the policy is CallerRuns, the timer thread is the caller
+        Ok(Some(job)) => {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                error!("Scheduled job panicked: {}", panic_message(payload.as_ref()));
+            }
+        }
+        Err(e) => warn!("Skipping a scheduled job: {}", e),
+    }
+
+    next
+}
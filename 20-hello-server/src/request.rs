@@ -0,0 +1,1018 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::str::{self, FromStr};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::body::{BodyError, BodyLimits, FromForm, FromJson, Multipart};
+use crate::headers::Headers;
+use crate::response::StatusCode;
+use crate::router::Params;
+
+/// The request methods defined by HTTP/1.1 (RFC 7231 and RFC 5789).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+/// method names are case-sensitive, so "get" is not a valid method
+impl FromStr for Method {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Method, ParseError> {
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "CONNECT" => Ok(Method::Connect),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "PATCH" => Ok(Method::Patch),
+            _ => Err(ParseError::InvalidMethod),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A parsed HTTP request, body included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: Method,
+    /// the path part of the request target, e.g. "/users/42"
+    pub path: String,
+    /// whatever comes after the '?' in the request target, if there was one
+    pub query: Option<String>,
+    pub version: Version,
+    pub headers: Headers,
+    /// the decoded body; chunked bodies are already joined together.
+    /// it's empty on routes that stream it, see `Handler::stream_body`
+    pub body: Vec<u8>,
+    /// path parameters, filled in once a `Router` picks a route for this request
+    pub params: Params,
+    /// the body as it comes in, on routes that stream it
+    pub(crate) stream: Option<StreamedBody>,
+}
+
+impl Request {
+    /// Creates an HTTP/1.1 request without headers nor body.
+    ///
+    /// The target may carry a query string, like "/search?q=rust".
+    pub fn new(method: Method, target: &str) -> Request {
+        let (path, query) = split_target(target);
+
+        Request {
+            method,
+            path,
+            query,
+            version: Version::Http11,
+            headers: Headers::new(),
+            body: Vec::new(),
+            params: Params::new(),
+            stream: None,
+        }
+    }
+
+    /// Shortcut to the value of the first header with the given name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Shortcut to a path parameter, like `id` in "/users/:id".
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name)
+    }
+
+    /// Reads the body: straight from the client on routes that stream it (see `Handler::stream_body`),
+    /// from `body` on the others.
+    pub fn body_reader(&self) -> Box<dyn Read + '_> {
+        match &self.stream {
+            Some(stream) => Box::new(stream.clone()),
+            None => Box::new(&self.body[..]),
+        }
+    }
+
+    /// Decodes an `application/x-www-form-urlencoded` body with the default `BodyLimits`.
+    pub fn form<T: FromForm>(&self) -> Result<T, BodyError> {
+        BodyLimits::default().form(self)
+    }
+
+    /// Decodes a JSON body with the default `BodyLimits`.
+    pub fn json<T: FromJson>(&self) -> Result<T, BodyError> {
+        BodyLimits::default().json(self)
+    }
+
+    /// Decodes a `multipart/form-data` body with the default `BodyLimits`, so the files go to
+    /// the temp dir.
+    pub fn multipart(&self) -> Result<Multipart, BodyError> {
+        BodyLimits::default().multipart(self)
+    }
+}
+
+/// The body of a request on a route that streams it, shared with the server that has to
+/// skip whatever the handler doesn't read.
+#[derive(Clone)]
+pub(crate) struct StreamedBody(Arc<Mutex<dyn Read + Send>>);
+
+impl StreamedBody {
+    pub(crate) fn new(body: Arc<Mutex<dyn Read + Send>>) -> StreamedBody {
+        StreamedBody(body)
+    }
+}
+
+impl Read for StreamedBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).read(buf)
+    }
+}
+
+impl fmt::Debug for StreamedBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StreamedBody")
+    }
+}
+
+/// copies of a request share the same body
+impl PartialEq for StreamedBody {
+    fn eq(&self, other: &StreamedBody) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Limits that protect us from clients sending huge requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// max size of the request line plus all header lines
+    pub max_head_bytes: usize,
+    /// max number of header lines
+    pub max_headers: usize,
+    /// max size of the (decoded) body, except on routes that stream it
+    pub max_body_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_head_bytes: 8 * 1024,
+            max_headers: 100,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// How long a client gets to send each part of a request, so that slow
+/// clients can't keep us waiting forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// how long we wait for the next request to start
+    pub idle: Duration,
+    /// from the first byte of a request until the end of its headers
+    pub head: Duration,
+    /// from the end of the headers until the end of the body
+    pub body: Duration,
+}
+
+/// Everything that can go wrong while reading a request.
+#[derive(Debug)]
+pub enum ParseError {
+    Io(io::Error),
+    /// the stream ended in the middle of a request
+    UnexpectedEof,
+    HeadersTooLarge,
+    BodyTooLarge,
+    InvalidRequestLine,
+    InvalidMethod,
+    UnsupportedVersion,
+    InvalidHeader,
+    /// missing, repeated with different values, or sent along with Transfer-Encoding
+    InvalidContentLength,
+    InvalidChunk,
+    /// we only understand the "chunked" transfer coding
+    UnsupportedTransferEncoding,
+    /// the client started a request but took too long to send the rest, see `Timeouts`
+    Timeout,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "i/o error: {}", e),
+            ParseError::UnexpectedEof => f.write_str("connection closed in the middle of a request"),
+            ParseError::HeadersTooLarge => f.write_str("request headers are too large"),
+            ParseError::BodyTooLarge => f.write_str("request body is too large"),
+            ParseError::InvalidRequestLine => f.write_str("invalid request line"),
+            ParseError::InvalidMethod => f.write_str("invalid request method"),
+            ParseError::UnsupportedVersion => f.write_str("unsupported HTTP version"),
+            ParseError::InvalidHeader => f.write_str("invalid header line"),
+            ParseError::InvalidContentLength => f.write_str("invalid Content-Length"),
+            ParseError::InvalidChunk => f.write_str("invalid chunked body"),
+            ParseError::UnsupportedTransferEncoding => f.write_str("unsupported Transfer-Encoding"),
+            ParseError::Timeout => f.write_str("the client took too long to send the request"),
+        }
+    }
+}
+
+impl ParseError {
+    /// The status we should answer with when a request fails to parse.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ParseError::HeadersTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            ParseError::BodyTooLarge => StatusCode::PayloadTooLarge,
+            ParseError::UnsupportedVersion => StatusCode::HttpVersionNotSupported,
+            ParseError::UnsupportedTransferEncoding => StatusCode::NotImplemented,
+            ParseError::Timeout => StatusCode::RequestTimeout,
+            _ => StatusCode::BadRequest,
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        ParseError::Io(e)
+    }
+}
+
+const READ_CHUNK: usize = 4096;
+
+/// Reads requests, one after the other, from a stream of bytes.
+///
+/// Bytes are read in chunks, so whatever comes after a request (e.g. the next
+/// pipelined request) stays buffered for the next call to `read_request`.
+pub struct RequestReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    limits: Limits,
+    timeouts: Option<Timeouts>,
+    /// when the part of the request we're reading has to be in
+    deadline: Option<Instant>,
+    set_read_timeout: Option<fn(&R, Duration) -> io::Result<()>>,
+}
+
+impl<R: Read> RequestReader<R> {
+    pub fn new(inner: R) -> RequestReader<R> {
+        RequestReader::with_limits(inner, Limits::default())
+    }
+
+    pub fn with_limits(inner: R, limits: Limits) -> RequestReader<R> {
+        RequestReader {
+            inner,
+            buf: Vec::new(),
+            limits,
+            timeouts: None,
+            deadline: None,
+            set_read_timeout: None,
+        }
+    }
+
+    /// Gives up on clients that take longer than `timeouts` to send a request.
+    ///
+    /// The deadlines are checked between reads, so a client sending a byte now
+    /// and then can't keep us around. To also stop a read that blocks past a
+    /// deadline, `set_read_timeout` is called with the time left before each read,
+    /// e.g. `|stream, timeout| stream.set_read_timeout(Some(timeout))` for a `TcpStream`.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts, set_read_timeout: fn(&R, Duration) -> io::Result<()>) {
+        self.timeouts = Some(timeouts);
+        self.set_read_timeout = Some(set_read_timeout);
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Bytes already read from the stream but not consumed by any request yet.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Reads the next request.
+    ///
+    /// Returns `Ok(None)` when the stream ends cleanly before a new request starts,
+    /// or when the idle timeout goes by without one.
+    pub fn read_request(&mut self) -> Result<Option<Request>, ParseError> {
+        match self.read_head()? {
+            Some(mut request) => {
+                self.read_body(&mut request)?;
+                Ok(Some(request))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the next request up to its body, which is left for `read_body` or `into_body`.
+    pub fn read_head(&mut self) -> Result<Option<Request>, ParseError> {
+        self.deadline = self.timeouts.map(|t| Instant::now() + t.idle);
+        let mut started = false;
+
+        let head_end = loop {
+            self.skip_empty_lines();
+
+            // the head timeout counts from the first byte of the request
+            if !started && !self.buf.is_empty() {
+                started = true;
+                self.deadline = self.timeouts.map(|t| Instant::now() + t.head);
+            }
+
+            if let Some(pos) = find(&self.buf, b"\r\n\r\n") {
+                break pos + 4;
+            }
+            if self.buf.len() > self.limits.max_head_bytes {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            match self.fill() {
+                Ok(0) if self.buf.is_empty() => return Ok(None),
+                Ok(0) => return Err(ParseError::UnexpectedEof),
+                Ok(_) => {}
+                // no request on the way, so it's like the client left
+                Err(ParseError::Timeout) if !started => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        };
+
+        if head_end > self.limits.max_head_bytes {
+            return Err(ParseError::HeadersTooLarge);
+        }
+
+        let (method, target, version, headers) = self.parse_head(head_end)?;
+        self.buf.drain(..head_end);
+        // whoever reads the body shouldn't be the first to find out we can't
+        framing(&headers)?;
+
+        let (path, query) = split_target(&target);
+        self.deadline = self.timeouts.map(|t| Instant::now() + t.body);
+
+        Ok(Some(Request {
+            method,
+            path,
+            query,
+            version,
+            headers,
+            body: Vec::new(),
+            params: Params::new(),
+            stream: None,
+        }))
+    }
+
+    /// Reads the body of the request `read_head` just gave us into `request.body`.
+    pub fn read_body(&mut self, request: &mut Request) -> Result<(), ParseError> {
+        request.body = match framing(&request.headers)? {
+            Framing::Length(len) if len > self.limits.max_body_bytes => return Err(ParseError::BodyTooLarge),
+            Framing::Length(len) => self.take(len)?,
+            Framing::Chunked => self.read_chunked_body()?,
+        };
+        self.deadline = None;
+        Ok(())
+    }
+
+    /// Reads the body of the request `read_head` just gave us as the caller asks for it,
+    /// without `Limits::max_body_bytes`, see `Handler::stream_body`.
+    pub(crate) fn into_body(mut self, request: &Request) -> Result<BodyReader<R>, ParseError> {
+        // the whole body has to be in by then, however it's read. `read_head` already started
+        // the clock, unless the head came from somewhere else
+        if self.deadline.is_none() {
+            self.deadline = self.timeouts.map(|t| Instant::now() + t.body);
+        }
+        let state = match framing(&request.headers)? {
+            Framing::Length(len) => BodyState::Length(len),
+            Framing::Chunked => BodyState::Chunk(0),
+        };
+        Ok(BodyReader { reader: self, state })
+    }
+
+    /// Puts bytes that were read from the stream somewhere else in front of it.
+    pub(crate) fn unread(&mut self, bytes: &[u8]) {
+        self.buf.splice(..0, bytes.iter().copied());
+    }
+
+    fn parse_head(&self, head_end: usize) -> Result<(Method, String, Version, Headers), ParseError> {
+        // the head ends with an empty line, we don't need it
+        let head = str::from_utf8(&self.buf[..head_end - 4]).map_err(|_| ParseError::InvalidHeader)?;
+        let mut lines = head.split("\r\n");
+
+        let request_line = lines.next().unwrap_or("");
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v), None) if !m.is_empty() && !t.is_empty() => (m, t, v),
+            _ => return Err(ParseError::InvalidRequestLine),
+        };
+
+        let method: Method = method.parse()?;
+        let version = parse_version(version)?;
+        if !target.starts_with('/') && target != "*" && !target.contains("://") {
+            return Err(ParseError::InvalidRequestLine);
+        }
+
+        let mut headers = Headers::new();
+        for line in lines {
+            if headers.len() == self.limits.max_headers {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            let (name, value) = parse_header_line(line)?;
+            headers.append(name, value);
+        }
+
+        Ok((method, target.to_string(), version, headers))
+    }
+
+    fn read_chunked_body(&mut self) -> Result<Vec<u8>, ParseError> {
+        let mut body = Vec::new();
+
+        loop {
+            let size = chunk_size(&self.take_line()?)?;
+            if size == 0 {
+                break;
+            }
+
+            match body.len().checked_add(size) {
+                Some(total) if total <= self.limits.max_body_bytes => {}
+                _ => return Err(ParseError::BodyTooLarge),
+            }
+            let data = self.take(size)?;
+            body.extend_from_slice(&data);
+
+            if self.take(2)? != b"\r\n" {
+                return Err(ParseError::InvalidChunk);
+            }
+        }
+
+        self.skip_trailers()?;
+        Ok(body)
+    }
+
+    /// the trailer section ends with an empty line; we don't use trailer fields
+    fn skip_trailers(&mut self) -> Result<(), ParseError> {
+        let mut trailer_bytes = 0;
+        loop {
+            let line = self.take_line()?;
+            if line.is_empty() {
+                return Ok(());
+            }
+            trailer_bytes += line.len();
+            if trailer_bytes > self.limits.max_head_bytes {
+                return Err(ParseError::HeadersTooLarge);
+            }
+        }
+    }
+
+    /// RFC 7230 says we should ignore empty lines received before a request-line
+    fn skip_empty_lines(&mut self) {
+        let mut start = 0;
+        loop {
+            if self.buf[start..].starts_with(b"\r\n") {
+                start += 2;
+            } else if self.buf[start..].starts_with(b"\n") {
+                start += 1;
+            } else {
+                break;
+            }
+        }
+        self.buf.drain(..start);
+    }
+
+    /// consumes exactly `len` bytes, reading more from the stream if needed
+    fn take(&mut self, len: usize) -> Result<Vec<u8>, ParseError> {
+        while self.buf.len() < len {
+            if self.fill()? == 0 {
+                return Err(ParseError::UnexpectedEof);
+            }
+        }
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    /// consumes up to `buf.len()` bytes into `buf`, reading more from the stream if there are none
+    fn take_some(&mut self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        if self.buf.is_empty() && self.fill()? == 0 {
+            return Err(ParseError::UnexpectedEof);
+        }
+        let n = buf.len().min(self.buf.len());
+        buf[..n].copy_from_slice(&self.buf[..n]);
+        self.buf.drain(..n);
+        Ok(n)
+    }
+
+    /// consumes a CRLF terminated line and returns it without the CRLF
+    fn take_line(&mut self) -> Result<String, ParseError> {
+        let end = loop {
+            if let Some(pos) = find(&self.buf, b"\r\n") {
+                break pos;
+            }
+            if self.buf.len() > self.limits.max_head_bytes {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            if self.fill()? == 0 {
+                return Err(ParseError::UnexpectedEof);
+            }
+        };
+
+        let line: Vec<u8> = self.buf.drain(..end + 2).take(end).collect();
+        String::from_utf8(line).map_err(|_| ParseError::InvalidChunk)
+    }
+
+    /// reads whatever the stream has for us and returns how many bytes we got
+    fn fill(&mut self) -> Result<usize, ParseError> {
+        let mut chunk = [0; READ_CHUNK];
+        loop {
+            if let Some(deadline) = self.deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(ParseError::Timeout);
+                }
+                if let Some(set_read_timeout) = self.set_read_timeout {
+                    set_read_timeout(&self.inner, left)?;
+                }
+            }
+
+            match self.inner.read(&mut chunk) {
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // the read gave up at the deadline, the check above tells whether we do too
+                Err(e) if self.deadline.is_some() && is_timeout(&e) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// The body of a request, read from the stream as it's asked for, see `RequestReader::into_body`.
+pub(crate) struct BodyReader<R> {
+    reader: RequestReader<R>,
+    state: BodyState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyState {
+    /// this many bytes left
+    Length(usize),
+    /// this many bytes left in the current chunk, a chunk size line comes next when it's 0
+    Chunk(usize),
+    /// the last chunk and the trailers are in
+    Done,
+    /// a read failed, so there's no telling where the body ends anymore
+    Broken,
+}
+
+impl<R: Read> BodyReader<R> {
+    /// Skips whatever the caller didn't read, and gives the reader back for the next request.
+    ///
+    /// More than `Limits::max_body_bytes` left isn't worth reading, so that's an error, and
+    /// so is a body that broke: either way the connection can't go on.
+    pub(crate) fn finish(mut self) -> Result<RequestReader<R>, ParseError> {
+        let mut left = self.reader.limits.max_body_bytes;
+        let mut chunk = [0; READ_CHUNK];
+        loop {
+            match self.read_some(&mut chunk)? {
+                0 => break,
+                n => left = left.checked_sub(n).ok_or(ParseError::BodyTooLarge)?,
+            }
+        }
+        self.reader.deadline = None;
+        Ok(self.reader)
+    }
+
+    fn read_some(&mut self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        loop {
+            match self.state {
+                BodyState::Length(0) | BodyState::Done => return Ok(0),
+                BodyState::Broken => return Err(ParseError::Io(io::Error::other("the body broke off earlier"))),
+                _ if buf.is_empty() => return Ok(0),
+                BodyState::Length(left) => {
+                    let len = buf.len().min(left);
+                    let n = self.reader.take_some(&mut buf[..len])?;
+                    self.state = BodyState::Length(left - n);
+                    return Ok(n);
+                }
+                BodyState::Chunk(0) => match chunk_size(&self.reader.take_line()?)? {
+                    0 => {
+                        self.reader.skip_trailers()?;
+                        self.state = BodyState::Done;
+                    }
+                    size => self.state = BodyState::Chunk(size),
+                },
+                BodyState::Chunk(left) => {
+                    let len = buf.len().min(left);
+                    let n = self.reader.take_some(&mut buf[..len])?;
+                    if n == left && self.reader.take(2)? != b"\r\n" {
+                        return Err(ParseError::InvalidChunk);
+                    }
+                    self.state = BodyState::Chunk(left - n);
+                    return Ok(n);
+                }
+            }
+        }
+    }
+}
+
+/// errors from the client come out as `InvalidData`, `UnexpectedEof` or `TimedOut`
+impl<R: Read> Read for BodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_some(buf).map_err(|e| {
+            self.state = BodyState::Broken;
+            match e {
+                ParseError::Io(e) => e,
+                ParseError::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+                ParseError::Timeout => io::Error::new(io::ErrorKind::TimedOut, e),
+                e => io::Error::new(io::ErrorKind::InvalidData, e),
+            }
+        })
+    }
+}
+
+/// depending on the OS, a read timeout is either WouldBlock or TimedOut
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn parse_version(version: &str) -> Result<Version, ParseError> {
+    match version {
+        "HTTP/1.1" => Ok(Version::Http11),
+        "HTTP/1.0" => Ok(Version::Http10),
+        v if v.starts_with("HTTP/") => Err(ParseError::UnsupportedVersion),
+        _ => Err(ParseError::InvalidRequestLine),
+    }
+}
+
+fn parse_header_line(line: &str) -> Result<(&str, &str), ParseError> {
+    let (name, value) = match line.find(':') {
+        Some(pos) => (&line[..pos], &line[pos + 1..]),
+        None => return Err(ParseError::InvalidHeader),
+    };
+
+    // no whitespace allowed in names, which also rejects the obsolete line folding
+    let is_token = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+    if name.is_empty() || !name.bytes().all(is_token) {
+        return Err(ParseError::InvalidHeader);
+    }
+
+    Ok((name, value.trim_matches(|c| c == ' ' || c == '\t')))
+}
+
+/// how the body of a request ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    /// after this many bytes, 0 when there's no Content-Length
+    Length(usize),
+    Chunked,
+}
+
+pub(crate) fn framing(headers: &Headers) -> Result<Framing, ParseError> {
+    if headers.contains("Transfer-Encoding") {
+        // a message with both is a classic request smuggling trick, so we refuse it
+        if headers.contains("Content-Length") {
+            return Err(ParseError::InvalidContentLength);
+        }
+
+        let mut codings = headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|v| v.split(','))
+            .map(str::trim);
+        return match (codings.next(), codings.next()) {
+            (Some(c), None) if c.eq_ignore_ascii_case("chunked") => Ok(Framing::Chunked),
+            _ => Err(ParseError::UnsupportedTransferEncoding),
+        };
+    }
+
+    // requests without a length have no body at all
+    Ok(Framing::Length(content_length(headers)?.unwrap_or(0)))
+}
+
+/// the size from a chunk size line; chunk extensions come after a ';' and we don't care about them
+pub(crate) fn chunk_size(line: &str) -> Result<usize, ParseError> {
+    let size = line.split(';').next().unwrap_or("").trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ParseError::InvalidChunk);
+    }
+    usize::from_str_radix(size, 16).map_err(|_| ParseError::InvalidChunk)
+}
+
+fn content_length(headers: &Headers) -> Result<Option<usize>, ParseError> {
+    let mut length = None;
+
+    // "Content-Length: 5, 5" and repeated fields are fine, as long as they agree
+    for value in headers.get_all("Content-Length").flat_map(|v| v.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::InvalidContentLength);
+        }
+        let value: usize = value.parse().map_err(|_| ParseError::InvalidContentLength)?;
+
+        match length {
+            Some(previous) if previous != value => return Err(ParseError::InvalidContentLength),
+            _ => length = Some(value),
+        }
+    }
+
+    Ok(length)
+}
+
+/// splits "/path?query" into its parts; absolute targets like
+/// "http://host/path" (sent to proxies) are reduced to their path
+fn split_target(target: &str) -> (String, Option<String>) {
+    let target = match target.find("://") {
+        Some(scheme_end) => {
+            let rest = &target[scheme_end + 3..];
+            rest.find('/').map(|p| &rest[p..]).unwrap_or("/")
+        }
+        None => target,
+    };
+
+    match target.find('?') {
+        Some(pos) => (target[..pos].to_string(), Some(target[pos + 1..].to_string())),
+        None => (target.to_string(), None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a stream that gives us one byte per read, like a very slow client
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    /// a stream that gives us one byte per read, each one `delay` after the other
+    struct Slow<'a> {
+        bytes: &'a [u8],
+        delay: Duration,
+    }
+
+    impl Read for Slow<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(self.delay);
+            let mut trickle = Trickle(self.bytes);
+            let n = trickle.read(buf)?;
+            self.bytes = trickle.0;
+            Ok(n)
+        }
+    }
+
+    fn timeouts(idle: u64, head: u64, body: u64) -> Timeouts {
+        Timeouts {
+            idle: Duration::from_millis(idle),
+            head: Duration::from_millis(head),
+            body: Duration::from_millis(body),
+        }
+    }
+
+    fn parse(raw: &[u8]) -> Result<Option<Request>, ParseError> {
+        RequestReader::new(raw).read_request()
+    }
+
+    #[test]
+    fn request_line_and_headers() {
+        let request = parse(b"GET /search?q=rust&page=2 HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(Method::Get, request.method);
+        assert_eq!("/search", request.path);
+        assert_eq!(Some("q=rust&page=2"), request.query.as_deref());
+        assert_eq!(Version::Http11, request.version);
+        assert_eq!(Some("localhost"), request.header("host"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn content_length_body_split_across_reads() {
+        let raw = b"POST /users HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world";
+        let request = RequestReader::new(Trickle(raw)).read_request().unwrap().unwrap();
+
+        assert_eq!(Method::Post, request.method);
+        assert_eq!(b"hello world".to_vec(), request.body);
+    }
+
+    #[test]
+    fn chunked_body() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: yes\r\n\r\n";
+        let request = RequestReader::new(Trickle(raw)).read_request().unwrap().unwrap();
+
+        assert_eq!(b"hello world".to_vec(), request.body);
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let raw = b"GET /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET /c HTTP/1.0\r\n\r\n";
+        let mut reader = RequestReader::new(&raw[..]);
+
+        assert_eq!("/a", reader.read_request().unwrap().unwrap().path);
+        assert_eq!(b"hi".to_vec(), reader.read_request().unwrap().unwrap().body);
+        assert_eq!(Version::Http10, reader.read_request().unwrap().unwrap().version);
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_requests() {
+        assert!(matches!(parse(b"GET /\r\n\r\n"), Err(ParseError::InvalidRequestLine)));
+        assert!(matches!(parse(b"get / HTTP/1.1\r\n\r\n"), Err(ParseError::InvalidMethod)));
+        assert!(matches!(parse(b"GET / HTTP/2.0\r\n\r\n"), Err(ParseError::UnsupportedVersion)));
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\nBad Header: x\r\n\r\n"), Err(ParseError::InvalidHeader)));
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\nHost: x\r\n"), Err(ParseError::UnexpectedEof)));
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab"),
+            Err(ParseError::InvalidContentLength)
+        ));
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"),
+            Err(ParseError::UnsupportedTransferEncoding)
+        ));
+        assert!(matches!(
+            parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"),
+            Err(ParseError::InvalidChunk)
+        ));
+    }
+
+    #[test]
+    fn streamed_bodies() {
+        let limits = Limits {
+            max_body_bytes: 4,
+            ..Limits::default()
+        };
+        let head = |reader: &mut RequestReader<_>| reader.read_head().unwrap().unwrap();
+
+        // the body can go past max_body_bytes, and the next request is still there after it
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nhello\r\n6\r\n world\r\n0\r\n\r\nGET /next HTTP/1.1\r\n\r\n";
+        let mut reader = RequestReader::with_limits(Trickle(raw), limits);
+        let request = head(&mut reader);
+        assert!(request.body.is_empty());
+        let mut body = reader.into_body(&request).unwrap();
+        let mut out = String::new();
+        body.read_to_string(&mut out).unwrap();
+        assert_eq!("hello world", out);
+        let mut reader = body.finish().unwrap();
+        assert_eq!("/next", reader.read_request().unwrap().unwrap().path);
+
+        // what isn't read gets skipped, unless there's too much of it
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /next HTTP/1.1\r\n\r\n";
+        let mut reader = RequestReader::with_limits(Trickle(raw), limits);
+        let request = head(&mut reader);
+        let mut reader = reader.into_body(&request).unwrap().finish().unwrap();
+        assert_eq!("/next", reader.read_request().unwrap().unwrap().path);
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let mut reader = RequestReader::with_limits(Trickle(raw), limits);
+        let request = head(&mut reader);
+        assert!(matches!(reader.into_body(&request).unwrap().finish(), Err(ParseError::BodyTooLarge)));
+
+        // once it broke, there's no telling where the next request starts
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloXX";
+        let mut reader = RequestReader::new(Trickle(raw));
+        let request = head(&mut reader);
+        let mut body = reader.into_body(&request).unwrap();
+        let e = body.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        assert!(body.finish().is_err());
+    }
+
+    #[test]
+    fn limits() {
+        let limits = Limits {
+            max_head_bytes: 64,
+            max_headers: 2,
+            max_body_bytes: 4,
+        };
+        let read = |raw: &[u8]| RequestReader::with_limits(raw, limits).read_request();
+
+        let long_header = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(100));
+        assert!(matches!(read(long_header.as_bytes()), Err(ParseError::HeadersTooLarge)));
+        assert!(matches!(read(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"), Err(ParseError::HeadersTooLarge)));
+        assert!(matches!(read(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"), Err(ParseError::BodyTooLarge)));
+    }
+
+    #[test]
+    fn clean_eof_is_not_an_error() {
+        assert!(parse(b"").unwrap().is_none());
+        assert!(parse(b"\r\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn slow_clients_time_out() {
+        let no_socket = |_: &Slow<'_>, _| Ok(());
+        let read = |raw: &'static [u8], timeouts: Timeouts| {
+            let mut reader = RequestReader::new(Slow {
+                bytes: raw,
+                delay: Duration::from_millis(5),
+            });
+            reader.set_timeouts(timeouts, no_socket);
+            reader.read_request()
+        };
+
+        // a byte every 5ms never leaves the client idle, but the headers take too long
+        let head = b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n";
+        assert!(matches!(read(head, timeouts(20, 50, 1000)), Err(ParseError::Timeout)));
+        assert!(read(head, timeouts(20, 1000, 1000)).unwrap().is_some());
+
+        // the body has its own deadline, which starts once the headers are in
+        let body = b"POST / HTTP/1.1\r\nContent-Length: 20\r\n\r\n01234567890123456789";
+        assert!(matches!(read(body, timeouts(1000, 1000, 50)), Err(ParseError::Timeout)));
+        assert!(read(body, timeouts(1000, 1000, 1000)).unwrap().is_some());
+    }
+
+    #[test]
+    fn stalled_clients_time_out() {
+        /// sends its bytes, then nothing, like a socket whose reads keep timing out
+        struct Stalled<'a>(&'a [u8]);
+
+        impl Read for Stalled<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    std::thread::sleep(Duration::from_millis(5));
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                self.0.read(buf)
+            }
+        }
+
+        let read = |raw: &'static [u8]| {
+            let mut reader = RequestReader::new(Stalled(raw));
+            reader.set_timeouts(timeouts(20, 20, 20), |_, _| Ok(()));
+            reader.read_request()
+        };
+
+        // a client that never starts a request is just idle, and so is one that only sends empty lines
+        assert!(read(b"").unwrap().is_none());
+        assert!(read(b"\r\n").unwrap().is_none());
+        assert!(matches!(read(b"GET / HTTP/1.1\r\n"), Err(ParseError::Timeout)));
+        assert!(matches!(read(b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\n"), Err(ParseError::Timeout)));
+    }
+}
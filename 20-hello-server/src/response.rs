@@ -0,0 +1,509 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::{Mutex, PoisonError};
+
+use crate::headers::Headers;
+use crate::request::Version;
+use crate::server::Upgraded;
+
+/// The status codes we know how to answer with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatusCode {
+    SwitchingProtocols,
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    UpgradeRequired,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+    HttpVersionNotSupported,
+}
+
+impl StatusCode {
+    pub fn code(&self) -> u16 {
+        match self {
+            StatusCode::SwitchingProtocols => 101,
+            StatusCode::Ok => 200,
+            StatusCode::Created => 201,
+            StatusCode::Accepted => 202,
+            StatusCode::NoContent => 204,
+            StatusCode::PartialContent => 206,
+            StatusCode::MovedPermanently => 301,
+            StatusCode::Found => 302,
+            StatusCode::SeeOther => 303,
+            StatusCode::NotModified => 304,
+            StatusCode::TemporaryRedirect => 307,
+            StatusCode::PermanentRedirect => 308,
+            StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::UnsupportedMediaType => 415,
+            StatusCode::RangeNotSatisfiable => 416,
+            StatusCode::UpgradeRequired => 426,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::HttpVersionNotSupported => 505,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "Created",
+            StatusCode::Accepted => "Accepted",
+            StatusCode::NoContent => "No Content",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::Found => "Found",
+            StatusCode::SeeOther => "See Other",
+            StatusCode::NotModified => "Not Modified",
+            StatusCode::TemporaryRedirect => "Temporary Redirect",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
+            StatusCode::Forbidden => "Forbidden",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::PayloadTooLarge => "Payload Too Large",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::UpgradeRequired => "Upgrade Required",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::NotImplemented => "Not Implemented",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::HttpVersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+
+    /// 1xx, 204 and 304 responses never have a body, not even an empty one
+    pub fn allows_body(&self) -> bool {
+        let code = self.code();
+        code >= 200 && code != 204 && code != 304
+    }
+}
+
+/// formats as the status line wants it, e.g. "404 Not Found"
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+/// An HTTP response, ready to be written to the client.
+///
+/// Use it as a builder:
+///
+/// ```
+/// use hello_server::{Response, StatusCode};
+///
+/// let response = Response::new(StatusCode::Ok)
+///     .with_header("Content-Type", "text/plain")
+///     .with_body("hi!");
+/// ```
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// when set, it's sent instead of `body`, see `with_stream`
+    pub stream: Option<BodyStream>,
+    upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    /// Creates a response without headers nor body.
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Vec::new(),
+            stream: None,
+            upgrade: None,
+        }
+    }
+
+    pub fn html(status: StatusCode, body: impl Into<Vec<u8>>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body)
+    }
+
+    pub fn text(status: StatusCode, body: impl Into<String>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body.into())
+    }
+
+    pub fn redirect(status: StatusCode, location: &str) -> Response {
+        Response::new(status).with_header("Location", location)
+    }
+
+    /// Sets a header, replacing any previous value.
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Sends the body as it gets made, instead of all at once.
+    ///
+    /// Unless we set a `Content-Length`, it goes out with `Transfer-Encoding: chunked`,
+    /// or, to HTTP/1.0 clients, until we close the connection.
+    pub fn with_stream(mut self, stream: BodyStream) -> Response {
+        self.stream = Some(stream);
+        self
+    }
+
+    /// Hands the connection over to `upgrade` once this response is sent, so it can
+    /// speak another protocol on it (e.g. WebSocket).
+    ///
+    /// Only `101 Switching Protocols` responses are upgraded, others just drop it.
+    /// `upgrade` runs on the worker that served the request, and the connection is
+    /// closed when it returns.
+    pub fn with_upgrade(mut self, upgrade: impl FnOnce(Upgraded) + Send + 'static) -> Response {
+        self.upgrade = Some(Upgrade(Box::new(upgrade)));
+        self
+    }
+
+    /// what the server runs with the connection after sending this
+    pub(crate) fn take_upgrade(&mut self) -> Option<Box<dyn FnOnce(Upgraded) + Send>> {
+        match self.upgrade.take() {
+            Some(Upgrade(upgrade)) if self.status == StatusCode::SwitchingProtocols => Some(upgrade),
+            _ => None,
+        }
+    }
+
+    /// Writes the status line, headers and body.
+    ///
+    /// `Content-Length` is added for us, based on the body.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.send(Version::Http11, writer).map(|_| ())
+    }
+
+    /// Writes only the status line and headers, as we must answer HEAD requests.
+    pub fn write_head<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.send_head(Version::Http11, writer)
+    }
+
+    /// Writes the whole response the way a client speaking `version` understands it,
+    /// and returns how many bytes of body we sent.
+    ///
+    /// A streamed body sent to an HTTP/1.0 client without a `Content-Length`
+    /// only ends when the connection is closed, so it must be closed after it.
+    /// One sent with a `Content-Length` has to be just that long, or we stop with an
+    /// error, since the client can't tell where it ends anymore.
+    pub fn send<W: Write>(&self, version: Version, writer: &mut W) -> io::Result<usize> {
+        self.send_head(version, writer)?;
+        if !self.status.allows_body() {
+            return writer.flush().map(|_| 0);
+        }
+
+        let stream = match &self.stream {
+            Some(stream) => stream,
+            None => {
+                writer.write_all(&self.body)?;
+                return writer.flush().map(|_| self.body.len());
+            }
+        };
+
+        let chunked = self.is_chunked(version);
+        let length = match self.headers.get("Content-Length") {
+            Some(length) => Some(length.trim().parse::<usize>().map_err(|_| wrong_length("is not a number"))?),
+            None => None,
+        };
+        let mut sent = 0;
+        for chunk in &mut *stream.lock() {
+            let chunk = chunk?;
+            // an empty chunk would tell the client the body is over
+            if chunk.is_empty() {
+                continue;
+            }
+            if length.is_some_and(|length| sent + chunk.len() > length) {
+                return Err(wrong_length("is less than the stream sent"));
+            }
+            if chunked {
+                write!(writer, "{:x}\r\n", chunk.len())?;
+            }
+            writer.write_all(&chunk)?;
+            if chunked {
+                writer.write_all(b"\r\n")?;
+            }
+            // the client may be waiting for this one, like with server-sent events
+            writer.flush()?;
+            sent += chunk.len();
+        }
+        if chunked {
+            writer.write_all(b"0\r\n\r\n")?;
+        }
+        if length.is_some_and(|length| sent < length) {
+            return Err(wrong_length("is more than the stream sent"));
+        }
+        writer.flush().map(|_| sent)
+    }
+
+    /// `send`, minus the body.
+    pub fn send_head<W: Write>(&self, version: Version, writer: &mut W) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {}\r\n{}", self.status, self.headers);
+        if self.status.allows_body() && !self.headers.contains("Content-Length") {
+            if self.is_chunked(version) {
+                head.push_str("Transfer-Encoding: chunked\r\n");
+            } else if self.stream.is_none() {
+                head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+            }
+        }
+        head.push_str("\r\n");
+
+        writer.write_all(head.as_bytes())
+    }
+
+    fn is_chunked(&self, version: Version) -> bool {
+        self.stream.is_some() && version == Version::Http11 && !self.headers.contains("Content-Length")
+    }
+}
+
+/// a streamed body that doesn't match its Content-Length
+fn wrong_length(why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("the Content-Length of a streamed body {}", why))
+}
+
+/// a streamed body or an upgrade can't be compared, so responses with one are never equal
+impl PartialEq for Response {
+    fn eq(&self, other: &Response) -> bool {
+        let plain = |response: &Response| response.stream.is_none() && response.upgrade.is_none();
+        plain(self)
+            && plain(other)
+            && self.status == other.status
+            && self.headers == other.headers
+            && self.body == other.body
+    }
+}
+
+type Chunks = Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>;
+
+/// A body we send a chunk at a time, as it gets made, see `Response::with_stream`.
+pub struct BodyStream {
+    // a Mutex so that sending a response doesn't need a `&mut` to it
+    chunks: Mutex<Chunks>,
+}
+
+impl BodyStream {
+    /// Sends each chunk the iterator gives us, as soon as it does.
+    pub fn new<I, T>(chunks: I) -> BodyStream
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static,
+        T: Into<Vec<u8>> + 'static,
+    {
+        BodyStream::try_new(chunks.into_iter().map(Ok))
+    }
+
+    /// Like `new`, but the chunks may fail to be made, e.g. when they come from a file.
+    ///
+    /// It's too late to answer with an error status by then, so we close the connection
+    /// and the client sees a body that ends too soon.
+    pub fn try_new<I, T>(chunks: I) -> BodyStream
+    where
+        I: IntoIterator<Item = io::Result<T>>,
+        I::IntoIter: Send + 'static,
+        T: Into<Vec<u8>> + 'static,
+    {
+        let chunks = chunks.into_iter().map(|chunk| chunk.map(Into::into));
+        BodyStream {
+            chunks: Mutex::new(Box::new(chunks)),
+        }
+    }
+
+    /// Sends whatever we read from `reader`, until it ends.
+    pub fn from_reader(mut reader: impl Read + Send + 'static) -> BodyStream {
+        BodyStream::try_new(std::iter::from_fn(move || {
+            let mut chunk = vec![0; READ_CHUNK];
+            loop {
+                return match reader.read(&mut chunk) {
+                    Ok(0) => None,
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        Some(Ok(chunk))
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Some(Err(e)),
+                };
+            }
+        }))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Chunks> {
+        self.chunks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BodyStream")
+    }
+}
+
+/// see `Response::with_upgrade`
+struct Upgrade(Box<dyn FnOnce(Upgraded) + Send>);
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+/// how much we read from a `BodyStream::from_reader` at a time
+const READ_CHUNK: usize = 16 * 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(response: &Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn content_length_is_added() {
+        let response = Response::text(StatusCode::Ok, "hello");
+
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 5\r\n\r\nhello",
+            written(&response)
+        );
+    }
+
+    #[test]
+    fn binary_bodies() {
+        let response = Response::new(StatusCode::Ok).with_body(vec![0u8, 159, 146, 150]);
+
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        assert!(out.ends_with(b"Content-Length: 4\r\n\r\n\x00\x9f\x92\x96"));
+    }
+
+    #[test]
+    fn no_body_for_not_modified() {
+        let response = Response::new(StatusCode::NotModified).with_body("ignored");
+
+        assert_eq!("HTTP/1.1 304 Not Modified\r\n\r\n", written(&response));
+    }
+
+    #[test]
+    fn head_keeps_the_length_but_not_the_body() {
+        let response = Response::html(StatusCode::NotFound, "<h1>Oops</h1>");
+
+        let mut out = Vec::new();
+        response.write_head(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(out.ends_with("Content-Length: 13\r\n\r\n"));
+    }
+
+    #[test]
+    fn streams_are_chunked() {
+        let response =
+            Response::text(StatusCode::Ok, "ignored").with_stream(BodyStream::new(vec!["hello", "", " world"]));
+
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+            written(&response)
+        );
+    }
+
+    #[test]
+    fn streams_to_http10_end_with_the_connection() {
+        let response = Response::new(StatusCode::Ok).with_stream(BodyStream::new(vec!["hello", " world"]));
+
+        let mut out = Vec::new();
+        assert_eq!(11, response.send(Version::Http10, &mut out).unwrap());
+        assert_eq!(b"HTTP/1.1 200 OK\r\n\r\nhello world".to_vec(), out);
+    }
+
+    #[test]
+    fn streams_with_a_length_are_sent_as_they_are() {
+        let body = vec![7u8; 40_000];
+        let response = Response::new(StatusCode::Ok)
+            .with_header("Content-Length", "40000")
+            .with_stream(BodyStream::from_reader(io::Cursor::new(body.clone())));
+
+        let mut out = Vec::new();
+        assert_eq!(40_000, response.send(Version::Http11, &mut out).unwrap());
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 40000\r\n\r\n";
+        assert_eq!(&head[..], &out[..head.len()]);
+        assert_eq!(body, out[head.len()..]);
+    }
+
+    #[test]
+    fn streams_must_match_their_length() {
+        let send = |length: &str| {
+            let response = Response::new(StatusCode::Ok)
+                .with_header("Content-Length", length)
+                .with_stream(BodyStream::new(vec!["hello", " world"]));
+            let mut out = Vec::new();
+            let sent = response.send(Version::Http11, &mut out);
+            (sent, String::from_utf8(out).unwrap())
+        };
+
+        // the extra bytes would be read as the next response, so they're never sent
+        let (sent, out) = send("5");
+        assert_eq!(io::ErrorKind::InvalidData, sent.unwrap_err().kind());
+        assert!(out.ends_with("\r\n\r\nhello"));
+        // and a short body would take the start of the next response with it
+        assert!(send("20").0.is_err());
+        assert!(send("five").0.is_err());
+        assert_eq!(11, send("11").0.unwrap());
+    }
+
+    #[test]
+    fn only_plain_responses_are_equal() {
+        assert_eq!(Response::text(StatusCode::Ok, "hi"), Response::text(StatusCode::Ok, "hi"));
+        assert_ne!(Response::text(StatusCode::Ok, "hi"), Response::text(StatusCode::Ok, "ho"));
+        let streamed = || Response::new(StatusCode::Ok).with_stream(BodyStream::new(vec!["hi"]));
+        assert_ne!(streamed(), streamed());
+    }
+
+    #[test]
+    fn failing_streams_stop_the_response() {
+        let chunks = vec![Ok("hello"), Err(io::Error::other("disk on fire")), Ok("never sent")];
+        let response = Response::new(StatusCode::Ok).with_stream(BodyStream::try_new(chunks));
+
+        let mut out = Vec::new();
+        assert!(response.write_to(&mut out).is_err());
+        assert!(String::from_utf8(out).unwrap().ends_with("5\r\nhello\r\n"));
+    }
+}
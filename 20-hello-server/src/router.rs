@@ -0,0 +1,553 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+use crate::executor::block_on;
+use crate::middleware::{Middleware, Next};
+use crate::request::{Method, Request};
+use crate::response::{Response, StatusCode};
+
+/// Something that turns a request into a response.
+///
+/// Any `Fn(&Request) -> Response` that can be shared between threads converts into a `Handler`.
+#[derive(Clone)]
+pub struct Handler {
+    func: Arc<dyn Fn(&Request) -> Response + Send + Sync>,
+    streams_body: bool,
+}
+
+impl Handler {
+    pub fn new<F>(func: F) -> Handler
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        Handler {
+            func: Arc::new(func),
+            streams_body: false,
+        }
+    }
+
+    /// A handler written as an `async fn`, or anything else that returns a future.
+    ///
+    /// The future gets its own copy of the request, so it doesn't borrow anything, and runs
+    /// on the worker that got the request, see `block_on`.
+    pub fn from_async<F, Fut>(func: F) -> Handler
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response>,
+    {
+        Handler::new(move |request: &Request| block_on(func(request.clone())))
+    }
+
+    /// Lets the handler read the body from the client as it comes, with `Request::body_reader`,
+    /// instead of the server reading all of it first. That's how uploads get past
+    /// `Limits::max_body_bytes`, so the handler has to set its own limits, like `BodyLimits` does.
+    ///
+    /// `Request::body` stays empty, and whatever the handler doesn't read is skipped after it.
+    /// The whole body still has to be in within `ConnectionConfig::body_timeout`.
+    pub fn stream_body(mut self) -> Handler {
+        self.streams_body = true;
+        self
+    }
+
+    pub fn call(&self, request: &Request) -> Response {
+        (self.func)(request)
+    }
+}
+
+impl<F> From<F> for Handler
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    fn from(func: F) -> Handler {
+        Handler::new(func)
+    }
+}
+
+/// What to do when a path only matches a route once we add or remove its trailing slash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// "/users/" and "/users" are different paths
+    Strict,
+    /// "/users/" and "/users" are the same path
+    Ignore,
+    /// answer with a redirect to the path the route knows
+    Redirect,
+}
+
+/// Values taken from the request path by `:name` and `*name` segments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    entries: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn new() -> Params {
+        Params { entries: Vec::new() }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn push(&mut self, name: &str, value: String) {
+        self.entries.push((name.to_string(), value));
+    }
+}
+
+/// The handler picked for a request, along with the path parameters.
+pub struct Match<'a, H> {
+    pub handler: &'a H,
+    pub params: Params,
+}
+
+/// Why the router could not pick a handler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteError {
+    /// no route knows this path (404)
+    NotFound,
+    /// the path is known, but only for these methods (405)
+    MethodNotAllowed(Vec<Method>),
+    /// the path is known with(out) a trailing slash, so the client should go there instead
+    Redirect(String),
+}
+
+impl RouteError {
+    /// Value for the `Allow` header that must go along with a 405 response.
+    pub fn allow_header(&self) -> Option<String> {
+        match self {
+            RouteError::MethodNotAllowed(methods) => {
+                let methods: Vec<&str> = methods.iter().map(Method::as_str).collect();
+                Some(methods.join(", "))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::NotFound => f.write_str("no route for this path"),
+            RouteError::MethodNotAllowed(_) => f.write_str("method not allowed for this path"),
+            RouteError::Redirect(to) => write!(f, "path moved to {}", to),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Static(String),
+    Param(String),
+    /// matches all the remaining segments, so it can only be the last one
+    Wildcard(String),
+}
+
+impl Segment {
+    /// lower is more specific, so "/users/new" wins over "/users/:id"
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+}
+
+struct Route<H> {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: H,
+}
+
+/// Picks a handler based on the request method and path.
+///
+/// Patterns are made of `/` separated segments, which can be:
+/// - static text, like `/users`
+/// - a parameter, like `/users/:id`, matching exactly one segment
+/// - a wildcard, like `/static/*path`, matching everything until the end
+///
+/// `H` is whatever we want to get back for a request, usually a handler function.
+pub struct Router<H> {
+    routes: Vec<Route<H>>,
+    not_found: Option<H>,
+    trailing_slash: TrailingSlash,
+    /// only used by `Router<Handler>`, see `wrap`
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl<H> Router<H> {
+    /// Creates a router that redirects when the trailing slash doesn't match.
+    pub fn new() -> Router<H> {
+        Router {
+            routes: Vec::new(),
+            not_found: None,
+            trailing_slash: TrailingSlash::Redirect,
+            middleware: Vec::new(),
+        }
+    }
+
+    pub fn trailing_slash(&mut self, rule: TrailingSlash) -> &mut Self {
+        self.trailing_slash = rule;
+        self
+    }
+
+    /// Registers a handler for a method and path pattern.
+    ///
+    /// # Panics
+    ///
+    /// The `route` function will panic if the pattern does not start with '/',
+    /// has an unnamed parameter, or has a wildcard before its last segment.
+    pub fn route(&mut self, method: Method, pattern: &str, handler: impl Into<H>) -> &mut Self {
+        let segments = parse_pattern(pattern);
+        self.routes.push(Route {
+            method,
+            segments,
+            handler: handler.into(),
+        });
+        self
+    }
+
+    /// Sets what we use when no route knows the requested path.
+    pub fn not_found(&mut self, handler: impl Into<H>) -> &mut Self {
+        self.not_found = Some(handler.into());
+        self
+    }
+
+    pub fn get(&mut self, pattern: &str, handler: impl Into<H>) -> &mut Self {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post(&mut self, pattern: &str, handler: impl Into<H>) -> &mut Self {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put(&mut self, pattern: &str, handler: impl Into<H>) -> &mut Self {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn patch(&mut self, pattern: &str, handler: impl Into<H>) -> &mut Self {
+        self.route(Method::Patch, pattern, handler)
+    }
+
+    pub fn delete(&mut self, pattern: &str, handler: impl Into<H>) -> &mut Self {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Finds the handler for a request.
+    ///
+    /// HEAD requests fall back to the GET handler when there's no HEAD route.
+    pub fn find(&self, method: Method, path: &str) -> Result<Match<'_, H>, RouteError> {
+        let ignore_slash = self.trailing_slash == TrailingSlash::Ignore;
+
+        match self.find_exact(method, path, ignore_slash) {
+            Err(RouteError::NotFound) if self.trailing_slash == TrailingSlash::Redirect => {
+                let other = toggle_trailing_slash(path);
+                match self.find_exact(method, &other, false) {
+                    Err(RouteError::NotFound) => Err(RouteError::NotFound),
+                    _ => Err(RouteError::Redirect(other)),
+                }
+            }
+            result => result,
+        }
+    }
+
+    fn find_exact(&self, method: Method, path: &str, ignore_slash: bool) -> Result<Match<'_, H>, RouteError> {
+        let path = split_path(path, ignore_slash);
+        let mut best: Option<(Vec<u8>, &Route<H>, Params)> = None;
+        let mut allowed = Vec::new();
+
+        for route in &self.routes {
+            let params = match match_segments(&route.segments, &path, ignore_slash) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            allowed.push(route.method);
+            if route.method == Method::Get {
+                allowed.push(Method::Head);
+            }
+
+            let usable = route.method == method || (method == Method::Head && route.method == Method::Get);
+            if !usable {
+                continue;
+            }
+
+            // an explicit HEAD route wins over the GET fallback
+            let mut key: Vec<u8> = route.segments.iter().map(Segment::rank).collect();
+            key.push(if route.method == method { 0 } else { 1 });
+
+            let better = match &best {
+                Some((best_key, _, _)) => key < *best_key,
+                None => true,
+            };
+            if better {
+                best = Some((key, route, params));
+            }
+        }
+
+        match best {
+            Some((_, route, params)) => Ok(Match {
+                handler: &route.handler,
+                params,
+            }),
+            None if allowed.is_empty() => Err(RouteError::NotFound),
+            None => {
+                let mut methods = Vec::new();
+                for m in allowed {
+                    if !methods.contains(&m) {
+                        methods.push(m);
+                    }
+                }
+                Err(RouteError::MethodNotAllowed(methods))
+            }
+        }
+    }
+}
+
+impl Router<Handler> {
+    /// Adds a middleware around the handlers, inside the ones added before it.
+    ///
+    /// So the first one added is the first to get the request, and the last to get the response.
+    pub fn wrap(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Runs the middleware, then the handler picked for the request.
+    ///
+    /// When there's no such handler, we answer 404 (or call the `not_found` handler),
+    /// 405 with the `Allow` header, or redirect to the right trailing slash.
+    pub fn handle(&self, request: &mut Request) -> Response {
+        Next::new(&self.middleware, self).run(request)
+    }
+
+    /// whether the route for `request` reads the body itself, see `Handler::stream_body`
+    pub(crate) fn streams_body(&self, request: &Request) -> bool {
+        matches!(self.find(request.method, &request.path), Ok(found) if found.handler.streams_body)
+    }
+
+    /// what `handle` does once the middleware is done
+    pub(crate) fn dispatch(&self, request: &mut Request) -> Response {
+        match self.find(request.method, &request.path) {
+            Ok(found) => {
+                request.params = found.params;
+                found.handler.call(request)
+            }
+            Err(RouteError::NotFound) => match &self.not_found {
+                Some(handler) => handler.call(request),
+                None => Response::text(StatusCode::NotFound, "Not Found"),
+            },
+            Err(e @ RouteError::MethodNotAllowed(_)) => {
+                let allow = e.allow_header().unwrap_or_default();
+                Response::text(StatusCode::MethodNotAllowed, "Method Not Allowed").with_header("Allow", allow)
+            }
+            Err(RouteError::Redirect(path)) => {
+                let location = match &request.query {
+                    Some(query) => format!("{}?{}", path, query),
+                    None => path,
+                };
+                // 301 lets clients change POST into GET, 308 doesn't
+                let status = match request.method {
+                    Method::Get | Method::Head => StatusCode::MovedPermanently,
+                    _ => StatusCode::PermanentRedirect,
+                };
+                Response::redirect(status, &location)
+            }
+        }
+    }
+}
+
+impl<H> Default for Router<H> {
+    fn default() -> Router<H> {
+        Router::new()
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    assert!(pattern.starts_with('/'), "route pattern must start with '/': {}", pattern);
+
+    let parts: Vec<&str> = pattern[1..].split('/').collect();
+    let last = parts.len() - 1;
+
+    parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| {
+            if let Some(name) = part.strip_prefix(':') {
+                assert!(!name.is_empty(), "unnamed parameter in route pattern: {}", pattern);
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(i == last, "wildcard must be the last segment: {}", pattern);
+                Segment::Wildcard(if name.is_empty() { "*" } else { name }.to_string())
+            } else {
+                Segment::Static(part.to_string())
+            }
+        })
+        .collect()
+}
+
+/// "/users/42/" becomes ["users", "42", ""], so the trailing slash is an empty
+/// segment, unless we were told to ignore it
+fn split_path(path: &str, ignore_slash: bool) -> Vec<&str> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let mut segments: Vec<&str> = path.split('/').collect();
+
+    if ignore_slash && segments.len() > 1 && segments.last() == Some(&"") {
+        segments.pop();
+    }
+    segments
+}
+
+fn match_segments(pattern: &[Segment], path: &[&str], ignore_slash: bool) -> Option<Params> {
+    let mut pattern = pattern;
+    if ignore_slash && pattern.len() > 1 && pattern.last() == Some(&Segment::Static(String::new())) {
+        pattern = &pattern[..pattern.len() - 1];
+    }
+
+    let mut params = Params::new();
+    for (i, segment) in pattern.iter().enumerate() {
+        match segment {
+            Segment::Wildcard(name) => {
+                params.push(name, path.get(i..).unwrap_or(&[]).join("/"));
+                return Some(params);
+            }
+            Segment::Static(text) if path.get(i) != Some(&text.as_str()) => return None,
+            Segment::Static(_) => {}
+            Segment::Param(name) => match path.get(i) {
+                Some(value) if !value.is_empty() => params.push(name, value.to_string()),
+                _ => return None,
+            },
+        }
+    }
+
+    if pattern.len() == path.len() {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+fn toggle_trailing_slash(path: &str) -> String {
+    match path.strip_suffix('/') {
+        Some(stripped) if !stripped.is_empty() => stripped.to_string(),
+        Some(_) => path.to_string(),
+        None => format!("{}/", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router<&'static str> {
+        let mut router = Router::new();
+        router
+            .get("/", "home")
+            .get("/users/:id", "show user")
+            .get("/users/new", "new user form")
+            .delete("/users/:id", "delete user")
+            .get("/users/:id/posts/:post", "show post")
+            .get("/static/*path", "static file")
+            .get("/docs/", "docs");
+        router
+    }
+
+    #[test]
+    fn static_and_param_segments() {
+        let router = router();
+
+        assert_eq!("home", *router.find(Method::Get, "/").unwrap().handler);
+        assert_eq!("new user form", *router.find(Method::Get, "/users/new").unwrap().handler);
+
+        let found = router.find(Method::Get, "/users/42/posts/7").unwrap();
+        assert_eq!("show post", *found.handler);
+        assert_eq!(Some("42"), found.params.get("id"));
+        assert_eq!(Some("7"), found.params.get("post"));
+
+        let found = router.find(Method::Delete, "/users/new").unwrap();
+        assert_eq!("delete user", *found.handler);
+        assert_eq!(Some("new"), found.params.get("id"));
+    }
+
+    #[test]
+    fn wildcards() {
+        let router = router();
+        let found = router.find(Method::Get, "/static/css/site.css").unwrap();
+
+        assert_eq!("static file", *found.handler);
+        assert_eq!(Some("css/site.css"), found.params.get("path"));
+    }
+
+    #[test]
+    fn not_found_and_method_not_allowed() {
+        let router = router();
+
+        assert_eq!(Some(RouteError::NotFound), router.find(Method::Get, "/nope").err());
+        assert_eq!(Some(RouteError::NotFound), router.find(Method::Get, "/users//posts/1").err());
+
+        let err = router.find(Method::Post, "/users/42").err().unwrap();
+        assert_eq!(RouteError::MethodNotAllowed(vec![Method::Get, Method::Head, Method::Delete]), err);
+        assert_eq!(Some("GET, HEAD, DELETE".to_string()), err.allow_header());
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        assert_eq!("home", *router().find(Method::Head, "/").unwrap().handler);
+    }
+
+    #[test]
+    fn trailing_slash_rules() {
+        let mut router = router();
+        assert_eq!(Some(RouteError::Redirect("/users/42".to_string())), router.find(Method::Get, "/users/42/").err());
+        assert_eq!(Some(RouteError::Redirect("/docs/".to_string())), router.find(Method::Get, "/docs").err());
+
+        router.trailing_slash(TrailingSlash::Strict);
+        assert_eq!(Some(RouteError::NotFound), router.find(Method::Get, "/users/42/").err());
+
+        router.trailing_slash(TrailingSlash::Ignore);
+        assert_eq!("show user", *router.find(Method::Get, "/users/42/").unwrap().handler);
+        assert_eq!("docs", *router.find(Method::Get, "/docs").unwrap().handler);
+    }
+
+    #[test]
+    fn handle_answers_for_us() {
+        let mut router: Router<Handler> = Router::new();
+        router.get("/users/:id", |req: &Request| {
+            Response::text(StatusCode::Ok, format!("user {}", req.param("id").unwrap()))
+        });
+
+        let mut request = Request::new(Method::Get, "/users/42");
+        assert_eq!(b"user 42".to_vec(), router.handle(&mut request).body);
+
+        let response = router.handle(&mut Request::new(Method::Delete, "/users/42"));
+        assert_eq!(StatusCode::MethodNotAllowed, response.status);
+        assert_eq!(Some("GET, HEAD"), response.headers.get("Allow"));
+
+        let response = router.handle(&mut Request::new(Method::Get, "/users/42/?tab=posts"));
+        assert_eq!(StatusCode::MovedPermanently, response.status);
+        assert_eq!(Some("/users/42?tab=posts"), response.headers.get("Location"));
+
+        assert_eq!(StatusCode::NotFound, router.handle(&mut Request::new(Method::Get, "/")).status);
+        router.not_found(|_: &Request| Response::html(StatusCode::NotFound, "<h1>Oops!</h1>"));
+        let response = router.handle(&mut Request::new(Method::Get, "/"));
+        assert_eq!(b"<h1>Oops!</h1>".to_vec(), response.body);
+    }
+
+    #[test]
+    #[should_panic]
+    fn wildcard_must_be_last() {
+        Router::<()>::new().get("/files/*path/edit", ());
+    }
+}
@@ -0,0 +1,1086 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::log::{self, debug, info, warn};
+use crate::metrics::Metrics;
+use crate::pool::ThreadPool;
+use crate::request::{Limits, Method, ParseError, Request, RequestReader, StreamedBody, Timeouts, Version};
+use crate::response::{Response, StatusCode};
+use crate::router::{Handler, Router};
+#[cfg(feature = "tls")]
+use crate::tls::{TlsAcceptor, TlsStream};
+
+mod event_loop;
+
+/// How we treat each client connection.
+///
+/// A client that is too slow sending a request gets a 408 and the connection is
+/// closed, so it can't hold on to a worker for long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionConfig {
+    /// how long we wait for the next request before closing the connection
+    pub idle_timeout: Duration,
+    /// how long the client gets to send the request line and headers, once it started
+    pub header_timeout: Duration,
+    /// how long the client gets to send the body, once the headers are in
+    pub body_timeout: Duration,
+    /// how long a single write waits for a client that doesn't read what we send
+    pub write_timeout: Duration,
+    /// after this many requests we close the connection, even if the client wants more
+    pub max_requests: usize,
+    pub limits: Limits,
+}
+
+impl ConnectionConfig {
+    fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            idle: self.idle_timeout,
+            head: self.header_timeout,
+            body: self.body_timeout,
+        }
+    }
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            idle_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_requests: 100,
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// Serves every request a client sends on one connection.
+///
+/// HTTP/1.1 connections are persistent unless the client says `Connection: close`,
+/// while HTTP/1.0 ones are closed unless the client says `Connection: keep-alive`.
+/// Pipelined requests are answered in the order they arrive.
+pub fn serve_connection(stream: TcpStream, router: &Router<Handler>, config: &ConnectionConfig) -> io::Result<()> {
+    serve_until(Connection::Plain(stream), router, config, &AtomicBool::new(false), &Metrics::new())
+}
+
+/// same as `serve_connection`, but we stop keeping the connection alive once `stop` is set,
+/// and every response we send goes into `metrics`
+fn serve_until(
+    conn: Connection,
+    router: &Router<Handler>,
+    config: &ConnectionConfig,
+    stop: &AtomicBool,
+    metrics: &Metrics,
+) -> io::Result<()> {
+    conn.socket().set_write_timeout(Some(config.write_timeout))?;
+    // a response takes a few writes, and the last ones shouldn't wait for the client to ack the first
+    conn.socket().set_nodelay(true)?;
+
+    // reader and writer share the connection, since the reader goes along to the handler on
+    // routes that stream the body, see `with_streamed_body`
+    let conn = SharedConnection(Arc::new(Mutex::new(conn)));
+    let mut reader = RequestReader::with_limits(conn.clone(), config.limits);
+    reader.set_timeouts(config.timeouts(), |conn, timeout| conn.lock().socket().set_read_timeout(Some(timeout)));
+    let mut writer = conn.clone();
+    let mut served = 0;
+
+    loop {
+        // routes that stream the body get it as it comes, the others once it's all in
+        let read = reader.read_head().and_then(|head| match head {
+            Some(mut request) if !router.streams_body(&request) => reader.read_body(&mut request).map(|_| Some(request)),
+            head => Ok(head),
+        });
+        let mut request = match read {
+            Ok(Some(request)) => request,
+            // the client closed the connection between requests, or stayed quiet for too long
+            Ok(None) => return Ok(()),
+            Err(ParseError::Io(e)) => return Err(e),
+            Err(e) => {
+                warn!("Bad request: {}", e);
+
+                // we can't tell where the bad request ends, so the connection is done.
+                // that includes a request that timed out, which gets a 408
+                let response = Response::text(e.status(), e.to_string()).with_header("Connection", "close");
+                metrics.count(response.status.code());
+                return response.write_to(&mut writer);
+            }
+        };
+        served += 1;
+        let start = Instant::now();
+
+        let keep_alive =
+            wants_keep_alive(&request) && served < config.max_requests && !stop.load(Ordering::SeqCst);
+
+        let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        let (keep_alive, upgrade) = if router.streams_body(&request) {
+            let respond = |request: &mut Request| respond(id, request, &mut writer, router, keep_alive, metrics, start);
+            match with_streamed_body(&mut request, reader, respond)? {
+                (keep_alive, upgrade, Some(given_back)) => {
+                    reader = given_back;
+                    (keep_alive, upgrade)
+                }
+                (_, _, None) => return Ok(()),
+            }
+        } else {
+            respond(id, &mut request, &mut writer, router, keep_alive, metrics, start)?
+        };
+
+        if let Some(upgrade) = upgrade {
+            // the client may have sent more than the request already, and that's for the new protocol
+            let buffered = reader.buffered().to_vec();
+            drop((reader, writer));
+            let conn = conn.into_inner();
+            // it has its own idea of how long to wait for the client
+            conn.socket().set_read_timeout(None)?;
+            log::with_request(id, || upgrade(Upgraded::new(conn, buffered)));
+            return Ok(());
+        }
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Answers one request, and says whether the connection stays open, or who takes it over.
+///
+/// `keep_alive` is whether we'd keep it open, the response can still change our mind.
+fn respond(
+    id: u64,
+    request: &mut Request,
+    writer: &mut impl Write,
+    router: &Router<Handler>,
+    keep_alive: bool,
+    metrics: &Metrics,
+    start: Instant,
+) -> io::Result<(bool, Option<Upgrade>)> {
+    // whatever gets logged while we handle it says which request it was about
+    log::with_request(id, || {
+        let mut response = router.handle(request);
+        let upgrade = response.take_upgrade();
+
+        // without chunked encoding, the only way to tell where a streamed body ends is to close the connection
+        let close_delimited = request.version == Version::Http10
+            && response.stream.is_some()
+            && !response.headers.contains("Content-Length");
+        let keep_alive = keep_alive && !close_delimited;
+
+        if upgrade.is_some() {
+            // the Connection: Upgrade header is up to whoever takes the connection over
+        } else if !keep_alive {
+            response.headers.insert("Connection", "close");
+        } else if request.version == Version::Http10 {
+            // HTTP/1.0 clients need to be told the connection stays open
+            response.headers.insert("Connection", "keep-alive");
+        }
+
+        let bytes = if request.method == Method::Head {
+            response.send_head(request.version, writer)?;
+            0
+        } else {
+            response.send(request.version, writer)?
+        };
+
+        let status = response.status.code();
+        log::access(request.method.as_str(), &request.path, status, bytes, start.elapsed());
+        metrics.record(status, start.elapsed());
+        Ok((keep_alive, upgrade))
+    })
+}
+
+/// Runs `respond` for a request whose handler reads the body itself, see `Handler::stream_body`.
+///
+/// The reader goes along with the body, and comes back once whatever the handler didn't
+/// read is out of the way. When that can't be done, there's no reader, and the connection is done.
+fn with_streamed_body<R: Read + Send + 'static>(
+    request: &mut Request,
+    reader: RequestReader<R>,
+    respond: impl FnOnce(&mut Request) -> io::Result<(bool, Option<Upgrade>)>,
+) -> io::Result<(bool, Option<Upgrade>, Option<RequestReader<R>>)> {
+    let body = reader
+        .into_body(request)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let body = Arc::new(Mutex::new(body));
+    request.stream = Some(StreamedBody::new(body.clone()));
+    let answered = respond(request);
+    request.stream = None;
+    let (keep_alive, upgrade) = answered?;
+
+    // a handler that kept a copy of the request around still has the body
+    let finished = match Arc::try_unwrap(body) {
+        Ok(body) => body.into_inner().unwrap_or_else(PoisonError::into_inner).finish(),
+        Err(_) => return Ok((false, None, None)),
+    };
+    match finished {
+        Ok(reader) => Ok((keep_alive, upgrade, Some(reader))),
+        Err(e) => {
+            debug!("Closing the connection instead of reading the rest of the body: {}", e);
+            Ok((false, None, None))
+        }
+    }
+}
+
+/// what takes the connection over after a 101, see `Response::with_upgrade`
+type Upgrade = Box<dyn FnOnce(Upgraded) + Send>;
+
+/// every request we serve gets its own id, for the logs
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A client's connection, straight over TCP or inside TLS.
+///
+/// `&Connection` reads and writes, just like `&TcpStream` does.
+pub(crate) enum Connection {
+    Plain(TcpStream),
+    // boxed, a TLS session is much bigger than a socket
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl Connection {
+    /// the socket under it, e.g. to set timeouts on it
+    pub(crate) fn socket(&self) -> &TcpStream {
+        match self {
+            Connection::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.socket(),
+        }
+    }
+}
+
+/// a connection the reader can take along while the writer keeps using it, see `with_streamed_body`
+#[derive(Clone)]
+struct SharedConnection(Arc<Mutex<Connection>>);
+
+impl SharedConnection {
+    /// only one of them uses it at a time, so it's never held for long
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// the connection, once nobody else has it anymore
+    fn into_inner(self) -> Connection {
+        let conn = Arc::into_inner(self.0).expect("the connection is still shared");
+        conn.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Read for SharedConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.lock()).read(buf)
+    }
+}
+
+impl Write for SharedConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.lock()).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.lock()).flush()
+    }
+}
+
+impl Read for &Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => (&*stream).read(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => (&**stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => (&*stream).write(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => (&**stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => (&*stream).flush(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => (&**stream).flush(),
+        }
+    }
+}
+
+/// A connection that switched to another protocol, see `Response::with_upgrade`.
+///
+/// Reading starts with whatever the client sent right after the request, and
+/// then goes on with the connection.
+pub struct Upgraded {
+    conn: Connection,
+    buffered: io::Cursor<Vec<u8>>,
+}
+
+impl Upgraded {
+    pub(crate) fn new(conn: Connection, buffered: Vec<u8>) -> Upgraded {
+        Upgraded {
+            conn,
+            buffered: io::Cursor::new(buffered),
+        }
+    }
+
+    /// The socket, e.g. to set timeouts on it. Over TLS, reading or writing it directly breaks the connection.
+    pub fn get_ref(&self) -> &TcpStream {
+        self.conn.socket()
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.buffered.position() as usize) < self.buffered.get_ref().len() {
+            return self.buffered.read(buf);
+        }
+        (&self.conn).read(buf)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.conn).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.conn).flush()
+    }
+}
+
+/// Accepts connections and serves them on a `ThreadPool`.
+pub struct Server {
+    listener: TcpListener,
+    router: Arc<Router<Handler>>,
+    config: ConnectionConfig,
+    max_connections: Option<usize>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+    metrics: Metrics,
+    /// how many event loop threads, when the connections are served by those
+    event_loop: Option<usize>,
+    shutdown: ShutdownHandle,
+}
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs, router: Router<Handler>) -> io::Result<Server> {
+        let listener = TcpListener::bind(addr)?;
+        let shutdown = ShutdownHandle {
+            requested: Arc::new(AtomicBool::new(false)),
+            addr: listener.local_addr()?,
+        };
+
+        Ok(Server {
+            listener,
+            router: Arc::new(router),
+            config: ConnectionConfig::default(),
+            max_connections: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            metrics: Metrics::new(),
+            event_loop: None,
+            shutdown,
+        })
+    }
+
+    pub fn config(mut self, config: ConnectionConfig) -> Server {
+        self.config = config;
+        self
+    }
+
+    /// Clients that connect while `max` connections are open get a 503 and are closed right away.
+    ///
+    /// Without a limit, they wait in the pool queue for a worker.
+    pub fn max_connections(mut self, max: usize) -> Server {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Serves HTTPS instead of HTTP, with the certificate of `acceptor`.
+    ///
+    /// The TLS handshake happens on the worker, and counts against the idle timeout.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Server {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Counts the requests and connections into `metrics`, see `Metrics::handler` to serve them.
+    pub fn metrics(mut self, metrics: Metrics) -> Server {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Waits on the connections with `threads` event loops, instead of giving each one a worker.
+    ///
+    /// The event loops read the requests without blocking, and only the ones that are all
+    /// there go to the pool, so a client that keeps its connection open without sending
+    /// anything doesn't take a worker. Streamed responses and upgrades still keep theirs for
+    /// as long as they last. There's no TLS in this mode, `run` fails if it's set.
+    pub fn event_loop(mut self, threads: usize) -> Server {
+        self.event_loop = Some(threads.max(1));
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// A handle that other threads (or a signal handler) use to stop `run`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves every connection on the pool until a shutdown is requested.
+    ///
+    /// Connections being served when that happens get to finish the request they
+    /// are on, and are closed after it. Waiting for them is up to the pool, see
+    /// `ThreadPool::shutdown`.
+    pub fn run(self, pool: &ThreadPool) -> io::Result<()> {
+        if let Some(threads) = self.event_loop {
+            if self.is_tls() {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "the event loop doesn't serve TLS"));
+            }
+            return event_loop::run(self, pool, threads);
+        }
+
+        let open = self.metrics.open_connections();
+
+        for stream in self.listener.incoming() {
+            if self.shutdown.is_requested() {
+                break;
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    // e.g. the client gave up before we accepted it, no reason to stop serving others
+                    warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+
+            // the connection counts as open until the guard is dropped with the job
+            let guard = OpenConnection::new(&open);
+            if self.max_connections.is_some_and(|max| guard.count > max) {
+                warn!("Too many connections, turning one away.");
+                self.metrics.count(StatusCode::ServiceUnavailable.code());
+                // over TLS, we'd need a handshake before we could say why, so the client only sees it closed
+                if !self.is_tls() {
+                    let response = Response::text(StatusCode::ServiceUnavailable, "Too many connections")
+                        .with_header("Connection", "close");
+                    let _ = response.write_to(&mut &stream);
+                }
+                continue;
+            }
+
+            let router = Arc::clone(&self.router);
+            let stop = Arc::clone(&self.shutdown.requested);
+            let config = self.config;
+            let metrics = self.metrics.clone();
+            #[cfg(feature = "tls")]
+            let tls = self.tls.clone();
+
+            // the worker keeps serving this client until the connection is closed
+            let queued = pool.execute(move || {
+                let _guard = guard;
+                #[cfg(feature = "tls")]
+                let conn = match tls {
+                    Some(tls) => match tls.accept(stream) {
+                        Ok(stream) => Connection::Tls(Box::new(stream)),
+                        Err(e) => {
+                            warn!("Failed to start TLS: {}", e);
+                            return;
+                        }
+                    },
+                    None => Connection::Plain(stream),
+                };
+                #[cfg(not(feature = "tls"))]
+                let conn = Connection::Plain(stream);
+
+                if let Err(e) = serve_until(conn, &router, &config, &stop, &metrics) {
+                    warn!("Connection error: {}", e);
+                }
+            });
+
+            if let Err(e) = queued {
+                info!("Not accepting connections anymore: {}", e);
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        false
+    }
+}
+
+/// keeps count of the connections being served, or waiting for a worker
+struct OpenConnection {
+    open: Arc<AtomicUsize>,
+    /// how many are open, this one included
+    count: usize,
+}
+
+impl OpenConnection {
+    fn new(open: &Arc<AtomicUsize>) -> OpenConnection {
+        OpenConnection {
+            open: Arc::clone(open),
+            count: open.fetch_add(1, Ordering::SeqCst) + 1,
+        }
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Stops a running `Server`. Clones of a handle all stop the same server.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    addr: SocketAddr,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // the accept loop is blocked waiting for a client, so we become that
+        // client: it wakes up, sees the flag, and stops
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+fn wants_keep_alive(request: &Request) -> bool {
+    match request.version {
+        Version::Http11 => !request.headers.has_token("Connection", "close"),
+        Version::Http10 => request.headers.has_token("Connection", "keep-alive"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::{BodyStream, StatusCode};
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::Instant;
+
+    /// starts a server for a single connection and returns the client side of it
+    fn connect(config: ConnectionConfig) -> (TcpStream, thread::JoinHandle<io::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let mut router: Router<Handler> = Router::new();
+            router.get("/:name", |req: &Request| Response::text(StatusCode::Ok, req.param("name").unwrap()));
+            // more than the socket buffers hold, so writing it waits for the client to read
+            router.get("/big", |_: &Request| Response::new(StatusCode::Ok).with_body(vec![b'x'; 64 << 20]));
+
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(stream, &router, &config)
+        });
+
+        (TcpStream::connect(addr).unwrap(), server)
+    }
+
+    fn read_all(mut client: TcpStream) -> String {
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn pipelined_requests_on_one_connection() {
+        let (mut client, server) = connect(ConnectionConfig::default());
+
+        client
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let out = read_all(client);
+
+        assert_eq!(3, out.matches("HTTP/1.1 200 OK").count());
+        assert!(out.find("\r\n\r\na").unwrap() < out.find("\r\n\r\nb").unwrap());
+        assert!(out.ends_with("Connection: close\r\nContent-Length: 1\r\n\r\nc"));
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn http10_closes_unless_asked_not_to() {
+        let (mut client, server) = connect(ConnectionConfig::default());
+        client.write_all(b"GET /a HTTP/1.0\r\n\r\nGET /b HTTP/1.0\r\n\r\n").unwrap();
+
+        let out = read_all(client);
+        assert_eq!(1, out.matches("HTTP/1.1 200 OK").count());
+        server.join().unwrap().unwrap();
+
+        let (mut client, server) = connect(ConnectionConfig::default());
+        client
+            .write_all(b"GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /b HTTP/1.0\r\n\r\n")
+            .unwrap();
+
+        let out = read_all(client);
+        assert_eq!(2, out.matches("HTTP/1.1 200 OK").count());
+        assert!(out.contains("Connection: keep-alive"));
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn max_requests_per_connection() {
+        let config = ConnectionConfig {
+            max_requests: 2,
+            ..ConnectionConfig::default()
+        };
+        let (mut client, server) = connect(config);
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n").unwrap();
+
+        let out = read_all(client);
+        assert_eq!(2, out.matches("HTTP/1.1 200 OK").count());
+        assert!(out.ends_with("Connection: close\r\nContent-Length: 1\r\n\r\nb"));
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn idle_connections_are_closed() {
+        let config = ConnectionConfig {
+            idle_timeout: Duration::from_millis(100),
+            ..ConnectionConfig::default()
+        };
+        let (mut client, server) = connect(config);
+        let start = Instant::now();
+        client.write_all(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+
+        let out = read_all(client);
+        assert_eq!(1, out.matches("HTTP/1.1 200 OK").count());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        server.join().unwrap().unwrap();
+    }
+
+    fn slow_config() -> ConnectionConfig {
+        ConnectionConfig {
+            header_timeout: Duration::from_millis(100),
+            body_timeout: Duration::from_millis(100),
+            write_timeout: Duration::from_millis(100),
+            ..ConnectionConfig::default()
+        }
+    }
+
+    #[test]
+    fn stalled_headers_get_a_408() {
+        let (mut client, server) = connect(slow_config());
+        let start = Instant::now();
+        client.write_all(b"GET /a HTTP/1.1\r\nHost: loc").unwrap();
+
+        let out = read_all(client);
+        assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(out.contains("Connection: close"));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn stalled_bodies_get_a_408() {
+        let (mut client, server) = connect(slow_config());
+        client.write_all(b"POST /a HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").unwrap();
+
+        let out = read_all(client);
+        assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn trickling_clients_get_a_408() {
+        // one byte every 20ms is never idle for long, but the headers never end in time
+        let (client, server) = connect(slow_config());
+        let mut writer = client.try_clone().unwrap();
+        let trickle = thread::spawn(move || {
+            let header = format!("GET /a HTTP/1.1\r\nX-Slow: {}\r\n\r\n", "a".repeat(100));
+            for byte in header.bytes() {
+                // the server hangs up on us halfway
+                if writer.write_all(&[byte]).is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let start = Instant::now();
+        let out = read_all(client);
+        assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        server.join().unwrap().unwrap();
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn clients_that_dont_read_time_out() {
+        let (mut client, server) = connect(slow_config());
+        client.write_all(b"GET /big HTTP/1.1\r\n\r\n").unwrap();
+
+        let e = server.join().unwrap().unwrap_err();
+        assert!(matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn stalled_clients_dont_take_the_server_down() {
+        let mut router: Router<Handler> = Router::new();
+        router.get("/", |_: &Request| Response::text(StatusCode::Ok, "hi"));
+
+        let server = Server::bind("127.0.0.1:0", router).unwrap().config(slow_config());
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        // a single worker, which the stalled client takes first
+        let running = thread::spawn(move || server.run(&ThreadPool::new(1)));
+
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_all(client).starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(read_all(stalled).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn streamed_bodies_go_out_as_they_are_made() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (chunks, receiver) = std::sync::mpsc::channel::<String>();
+        let receiver = std::sync::Mutex::new(Some(receiver));
+
+        let server = thread::spawn(move || {
+            let mut router: Router<Handler> = Router::new();
+            router.get("/report", move |_: &Request| {
+                let chunks = receiver.lock().unwrap().take().unwrap();
+                Response::new(StatusCode::Ok).with_stream(BodyStream::new(chunks))
+            });
+
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(stream, &router, &ConnectionConfig::default())
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /report HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+
+        // the client gets the first line before we make the second one
+        chunks.send("first line\n".to_string()).unwrap();
+        let mut out = Vec::new();
+        let mut buf = [0; 256];
+        while !String::from_utf8_lossy(&out).contains("first line") {
+            let n = client.read(&mut buf).unwrap();
+            out.extend_from_slice(&buf[..n]);
+        }
+        chunks.send("second line\n".to_string()).unwrap();
+        drop(chunks);
+
+        let out = String::from_utf8_lossy(&out).into_owned() + &read_all(client);
+        assert!(out.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(out.ends_with("\r\n\r\nb\r\nfirst line\n\r\nc\r\nsecond line\n\r\n0\r\n\r\n"));
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn streamed_bodies_close_http10_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut router: Router<Handler> = Router::new();
+            router.get("/report", |_: &Request| {
+                Response::new(StatusCode::Ok).with_stream(BodyStream::new(vec!["a", "b"]))
+            });
+
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(stream, &router, &ConnectionConfig::default())
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        // it asks to keep the connection, but the connection closing is how it knows the body is over
+        client.write_all(b"GET /report HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+
+        let out = read_all(client);
+        assert!(out.contains("Connection: close\r\n"));
+        assert!(!out.contains("chunked"));
+        assert!(out.ends_with("\r\n\r\nab"));
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn shutdown_stops_the_accept_loop() {
+        let mut router: Router<Handler> = Router::new();
+        router.get("/", |_: &Request| Response::text(StatusCode::Ok, "hi"));
+
+        let server = Server::bind("127.0.0.1:0", router).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run(&ThreadPool::new(1)));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut buf = [0; 17];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(b"HTTP/1.1 200 OK\r\n", &buf);
+
+        handle.shutdown();
+
+        // the connection we kept open gets closed after its next request
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let out = read_all(client);
+        assert!(out.contains("Connection: close"));
+
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn clients_over_the_limit_are_turned_away() {
+        let mut router: Router<Handler> = Router::new();
+        router.get("/", |_: &Request| Response::text(StatusCode::Ok, "hi"));
+
+        let server = Server::bind("127.0.0.1:0", router).unwrap().max_connections(1);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run(&ThreadPool::new(2)));
+
+        // the first client keeps its connection open
+        let mut first = TcpStream::connect(addr).unwrap();
+        first.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut buf = [0; 17];
+        first.read_exact(&mut buf).unwrap();
+        assert_eq!(b"HTTP/1.1 200 OK\r\n", &buf);
+
+        let second = TcpStream::connect(addr).unwrap();
+        let out = read_all(second);
+        assert!(out.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(out.contains("Connection: close"));
+
+        handle.shutdown();
+        drop(first);
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn responses_are_counted_in_the_metrics() {
+        let mut router: Router<Handler> = Router::new();
+        router.get("/", |_: &Request| Response::text(StatusCode::Ok, "hi"));
+
+        let metrics = Metrics::new();
+        let server = Server::bind("127.0.0.1:0", router).unwrap().metrics(metrics.clone());
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run(&ThreadPool::new(2)));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\nGET /nope HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        read_all(client);
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nBroken header\r\n\r\n").unwrap();
+        read_all(client);
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+
+        let out = metrics.render();
+        assert!(out.contains("hello_requests_total{status=\"200\"} 1\n"));
+        assert!(out.contains("hello_requests_total{status=\"400\"} 1\n"));
+        assert!(out.contains("hello_requests_total{status=\"404\"} 1\n"));
+        // the bad request never got far enough to be timed
+        assert!(out.contains("hello_request_duration_seconds_count 2\n"));
+        assert!(out.contains("hello_connections_open 0\n"));
+    }
+
+    /// keeps the access log lines of one path
+    struct AccessLog {
+        path: &'static str,
+        lines: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl log::Logger for AccessLog {
+        fn enabled(&self, _: log::Level) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            let path = record.fields.iter().find(|(name, _)| *name == "path");
+            if record.target == "access" && path == Some(&("path", log::Value::Str(self.path))) {
+                let fields: Vec<_> = record.fields.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                let line = format!("{} {} {}", record.request.is_some(), record.message, fields.join(" "));
+                self.lines.lock().unwrap().push(line);
+            }
+        }
+    }
+
+    #[test]
+    fn requests_go_to_the_access_log() {
+        let access_log = Arc::new(AccessLog {
+            path: "/logged",
+            lines: Default::default(),
+        });
+        // the rest of the tests get their logger back once we're done
+        let previous = log::replace_logger(Some(access_log.clone()));
+
+        let (mut client, server) = connect(ConnectionConfig::default());
+        client.write_all(b"GET /logged HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        read_all(client);
+        let served = server.join().unwrap();
+        log::replace_logger(previous);
+        served.unwrap();
+
+        let lines = access_log.lines.lock().unwrap();
+        assert_eq!(1, lines.len());
+        assert!(lines[0].starts_with("true GET /logged method=GET path=/logged status=200 bytes=6 latency_us="));
+    }
+
+    #[test]
+    fn bad_requests_close_the_connection() {
+        let (mut client, server) = connect(ConnectionConfig::default());
+        client.write_all(b"GET /a HTTP/1.1\r\nBroken header\r\n\r\nGET /b HTTP/1.1\r\n\r\n").unwrap();
+
+        let out = read_all(client);
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(!out.contains("200 OK"));
+        server.join().unwrap().unwrap();
+    }
+
+    /// /upload streams its body, like in main.rs, and /skip streams it too, but doesn't read it
+    pub(super) fn upload_router() -> Router<Handler> {
+        let mut router: Router<Handler> = Router::new();
+        let upload = |request: &Request| match request.multipart() {
+            Ok(multipart) => Response::text(StatusCode::Ok, format!("{} bytes", multipart.files[0].size)),
+            Err(e) => e.into(),
+        };
+        router.post("/upload", Handler::new(upload).stream_body());
+        router.post("/skip", Handler::new(|_: &Request| Response::text(StatusCode::Ok, "skipped")).stream_body());
+        router
+    }
+
+    /// the multipart body of an upload with a file of `size` bytes
+    pub(super) fn upload_body(size: usize) -> Vec<u8> {
+        let mut body = b"--xyz\r\nContent-Disposition: form-data; name=\"file\"; filename=\"big.bin\"\r\n\r\n".to_vec();
+        body.resize(body.len() + size, b'x');
+        body.extend_from_slice(b"\r\n--xyz--\r\n");
+        body
+    }
+
+    pub(super) fn upload_limits() -> ConnectionConfig {
+        ConnectionConfig {
+            limits: Limits {
+                max_body_bytes: 1024,
+                ..Limits::default()
+            },
+            ..ConnectionConfig::default()
+        }
+    }
+
+    #[test]
+    fn uploads_stream_past_the_body_limit() {
+        let router = upload_router();
+        let server = Server::bind("127.0.0.1:0", router).unwrap().config(upload_limits());
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run(&ThreadPool::new(2)));
+
+        // a hundred times max_body_bytes, and the connection goes on after it
+        let body = upload_body(100 * 1024);
+        let mut client = TcpStream::connect(addr).unwrap();
+        let head = format!(
+            "POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=xyz\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        client.write_all(head.as_bytes()).unwrap();
+        client.write_all(&body).unwrap();
+        // what the handler doesn't read is skipped
+        client
+            .write_all(b"POST /skip HTTP/1.1\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello")
+            .unwrap();
+        let out = read_all(client);
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.contains("\r\n\r\n102400 bytes"));
+        assert!(out.ends_with("\r\n\r\nskipped"));
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+
+    /// sends an upload a byte at a time, each well within the body timeout of the last but all
+    /// of them way past it, and gives us the status line of the answer
+    pub(super) fn trickled_upload(addr: SocketAddr) -> String {
+        let body = upload_body(1000);
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let head = format!(
+            "POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=xyz\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        client.write_all(head.as_bytes()).unwrap();
+
+        let answered = Arc::new(AtomicBool::new(false));
+        let trickle = {
+            let mut client = client.try_clone().unwrap();
+            let answered = Arc::clone(&answered);
+            // true when we got to send all of it without an answer
+            thread::spawn(move || {
+                for byte in body.chunks(1).take(100) {
+                    if answered.load(Ordering::SeqCst) || client.write_all(byte).is_err() {
+                        return false;
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                }
+                true
+            })
+        };
+
+        let mut status = Vec::new();
+        while !status.ends_with(b"\r\n") {
+            let mut byte = [0];
+            client.read_exact(&mut byte).unwrap();
+            status.push(byte[0]);
+        }
+        answered.store(true, Ordering::SeqCst);
+        assert!(!trickle.join().unwrap(), "the upload was never cut off");
+        String::from_utf8(status).unwrap().trim_end().to_string()
+    }
+
+    #[test]
+    fn trickling_uploads_time_out() {
+        let config = ConnectionConfig {
+            body_timeout: Duration::from_millis(200),
+            ..upload_limits()
+        };
+        let server = Server::bind("127.0.0.1:0", upload_router()).unwrap().config(config);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run(&ThreadPool::new(2)));
+
+        assert_eq!("HTTP/1.1 408 Request Timeout", trickled_upload(addr));
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,912 @@
+//! The other way to run a `Server`, see `Server::event_loop`.
+//!
+//! A few threads wait on every connection at once with epoll (through mio), and read
+//! whatever comes in without blocking. Only once a request is all there, it goes to the
+//! pool, along with its connection. The worker answers it the same way `serve_until`
+//! does, and gives the connection back to the event loop to wait for the next one.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+use std::str;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::net::{TcpListener as MioListener, TcpStream as MioStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use super::{
+    respond, wants_keep_alive, with_streamed_body, Connection, ConnectionConfig, OpenConnection, Server, Upgrade,
+    Upgraded, NEXT_REQUEST_ID,
+};
+use crate::log::{self, info, warn};
+use crate::metrics::Metrics;
+use crate::pool::{ExecuteError, ThreadPool};
+use crate::request::{chunk_size, find, framing, Framing, Limits, ParseError, Request, RequestReader};
+use crate::response::{Response, StatusCode};
+use crate::router::{Handler, Router};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+/// how often we look for connections that went past their timeouts
+const TICK: Duration = Duration::from_millis(100);
+
+const READ_CHUNK: usize = 4096;
+
+pub(super) fn run(server: Server, pool: &ThreadPool, threads: usize) -> io::Result<()> {
+    server.listener.set_nonblocking(true)?;
+
+    // each event loop waits on its own copy of the listener, whichever wakes up first accepts
+    let loops = (0..threads)
+        .map(|_| EventLoop::new(&server, server.listener.try_clone()?))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    thread::scope(|scope| {
+        let running: Vec<_> = loops
+            .into_iter()
+            .map(|event_loop| scope.spawn(|| event_loop.run(pool)))
+            .collect();
+        for event_loop in running {
+            event_loop.join().expect("an event loop panicked")?;
+        }
+        Ok(())
+    })
+}
+
+/// a connection waiting for its next request
+struct Conn {
+    stream: MioStream,
+    /// what the client sent that's not part of a request we answered yet
+    buf: Vec<u8>,
+    served: usize,
+    /// how far along the next request is, which says which timeout applies
+    stage: Stage,
+    /// where the next request ends, once we know
+    end: RequestEnd,
+    deadline: Instant,
+    _open: OpenConnection,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// nothing of the next request yet
+    Idle,
+    Head,
+    Body,
+}
+
+impl Conn {
+    /// moves on to the next stage, and its timeout, when the request got that far
+    fn update_stage(&mut self, config: &ConnectionConfig) {
+        let stage = if self.buf.is_empty() {
+            Stage::Idle
+        } else if !self.end.head_done() {
+            Stage::Head
+        } else {
+            Stage::Body
+        };
+
+        if stage != self.stage {
+            let timeout = match stage {
+                Stage::Idle => config.idle_timeout,
+                Stage::Head => config.header_timeout,
+                Stage::Body => config.body_timeout,
+            };
+            self.stage = stage;
+            self.deadline = Instant::now() + timeout;
+        }
+    }
+
+    /// answers a request we can't serve, and the connection is done after it
+    fn refuse(self, e: ParseError, metrics: &Metrics) {
+        warn!("Bad request: {}", e);
+        self.close_with(Response::text(e.status(), e.to_string()), metrics);
+    }
+
+    /// sends `response` and closes the connection
+    fn close_with(mut self, response: Response, metrics: &Metrics) {
+        let response = response.with_header("Connection", "close");
+        metrics.count(response.status.code());
+        let mut out = Vec::new();
+        // it's short enough for the socket buffer, and if the client doesn't read it, that's on them
+        if response.write_to(&mut out).is_ok() {
+            let _ = self.stream.write(&out);
+        }
+    }
+}
+
+/// Keeps track of how much of a request is in, so that a big body coming in a bit at a
+/// time isn't parsed over and over: the head is parsed once it's all there, and the body
+/// once that is too.
+#[derive(Debug, Default)]
+struct RequestEnd {
+    /// `None` until the head is all there
+    head: Option<Head>,
+}
+
+#[derive(Debug)]
+struct Head {
+    request: Request,
+    /// how many bytes of the buffer it took
+    len: usize,
+    body: BodyEnd,
+}
+
+/// where the body ends, as far as we know yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyEnd {
+    /// the request ends after this many bytes, head and body
+    Length(usize),
+    /// a chunked body, where the next chunk size line starts
+    Chunked(usize),
+}
+
+/// longest chunk size line we wait for, anything longer is broken anyway
+const MAX_CHUNK_LINE: usize = 1024;
+
+impl RequestEnd {
+    fn head_done(&self) -> bool {
+        self.head.is_some()
+    }
+
+    /// the request at the start of the buffer, without its body, once its head is in
+    fn head(&self) -> Option<&Request> {
+        self.head.as_ref().map(|head| &head.request)
+    }
+
+    /// takes the request out, along with how many bytes its head took, e.g. for its handler to read the body
+    fn take_head(&mut self) -> Option<(Request, usize)> {
+        self.head.take().map(|head| (head.request, head.len))
+    }
+
+    /// parses the head of the request at the start of `buf`, if it's all there and we didn't already
+    fn parse_head(&mut self, buf: &[u8], limits: Limits) -> Result<(), ParseError> {
+        if self.head.is_some() {
+            return Ok(());
+        }
+        // empty lines before a request are skipped, see `RequestReader`. a head that's too big goes to
+        // the parser too, so it can refuse it
+        let start = buf.iter().take_while(|&&b| b == b'\r' || b == b'\n').count();
+        if find(&buf[start..], b"\r\n\r\n").is_none() && buf.len() <= limits.max_head_bytes {
+            return Ok(());
+        }
+
+        let mut reader = RequestReader::with_limits(buf, limits);
+        let request = match reader.read_head() {
+            Ok(Some(request)) => request,
+            Ok(None) | Err(ParseError::UnexpectedEof) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let len = buf.len() - reader.get_ref().len() - reader.buffered().len();
+        let body = match framing(&request.headers)? {
+            Framing::Length(body) => BodyEnd::Length(len.saturating_add(body)),
+            Framing::Chunked => BodyEnd::Chunked(len),
+        };
+        self.head = Some(Head { request, len, body });
+        Ok(())
+    }
+
+    /// The whole request and how many bytes it took, once its body is all there too.
+    fn parse_body(&mut self, buf: &[u8], limits: Limits) -> Result<Option<(Request, usize)>, ParseError> {
+        let head = match &mut self.head {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        if !head.body_done(buf, &limits) {
+            return Ok(None);
+        }
+
+        let body = &buf[head.len..];
+        let mut reader = RequestReader::with_limits(body, limits);
+        match reader.read_body(&mut head.request) {
+            Ok(()) => {}
+            // the chunk sizes said otherwise, it's not all there yet
+            Err(ParseError::UnexpectedEof) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let used = buf.len() - reader.get_ref().len() - reader.buffered().len();
+        Ok(self.take_head().map(|(request, _)| (request, used)))
+    }
+}
+
+impl Head {
+    /// whether the body may be all there, so it's worth parsing. too big or broken bodies
+    /// are worth it too, the parser refuses them
+    fn body_done(&mut self, buf: &[u8], limits: &Limits) -> bool {
+        match self.body {
+            BodyEnd::Length(total) => buf.len() >= total || total - self.len > limits.max_body_bytes,
+            BodyEnd::Chunked(mut pos) => loop {
+                // only what we haven't seen yet is looked at
+                let line_end = match find(&buf[pos.min(buf.len())..], b"\r\n") {
+                    Some(len) => pos + len,
+                    None => {
+                        self.body = BodyEnd::Chunked(pos);
+                        return buf.len() - pos.min(buf.len()) > MAX_CHUNK_LINE;
+                    }
+                };
+                let size = str::from_utf8(&buf[pos..line_end]).ok().map(chunk_size);
+                match size {
+                    // then the trailers, up to an empty line
+                    Some(Ok(0)) => {
+                        self.body = BodyEnd::Chunked(pos);
+                        let trailers = &buf[line_end..];
+                        return trailers.len() > limits.max_head_bytes || find(trailers, b"\r\n\r\n").is_some();
+                    }
+                    Some(Ok(size)) if size <= limits.max_body_bytes => pos = line_end + 2 + size + 2,
+                    _ => return true,
+                }
+            },
+        }
+    }
+}
+
+/// a connection a worker is done with, and its token
+type Returned = (Token, Conn);
+
+/// one of the threads waiting on connections
+struct EventLoop {
+    poll: Poll,
+    listener: MioListener,
+    waker: Arc<Waker>,
+    /// the connections that aren't with a worker right now
+    conns: HashMap<Token, Conn>,
+    next_token: usize,
+    /// workers send connections back through here when they're done with a request
+    returned: (mpsc::Sender<Returned>, mpsc::Receiver<Returned>),
+
+    router: Arc<Router<Handler>>,
+    config: ConnectionConfig,
+    max_connections: Option<usize>,
+    stop: Arc<AtomicBool>,
+    metrics: Metrics,
+    open: Arc<AtomicUsize>,
+}
+
+impl EventLoop {
+    fn new(server: &Server, listener: TcpListener) -> io::Result<EventLoop> {
+        let poll = Poll::new()?;
+        let mut listener = MioListener::from_std(listener);
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+        Ok(EventLoop {
+            poll,
+            listener,
+            waker,
+            conns: HashMap::new(),
+            next_token: WAKER.0 + 1,
+            returned: mpsc::channel(),
+            router: Arc::clone(&server.router),
+            config: server.config,
+            max_connections: server.max_connections,
+            stop: Arc::clone(&server.shutdown.requested),
+            metrics: server.metrics.clone(),
+            open: server.metrics.open_connections(),
+        })
+    }
+
+    fn run(mut self, pool: &ThreadPool) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        let mut last_tick = Instant::now();
+
+        // the connection `ShutdownHandle::shutdown` makes wakes us up, and the tick catches the
+        // loops it didn't. connections with a worker are closed by it after their request
+        while !self.stop.load(Ordering::SeqCst) {
+            if let Err(e) = self.poll.poll(&mut events, Some(TICK)) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => self.take_back(pool),
+                    token => self.read(token, pool),
+                }
+            }
+
+            if last_tick.elapsed() >= TICK {
+                last_tick = Instant::now();
+                self.time_out();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let mut stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    // e.g. the client gave up before we accepted it, the next one wakes us up again
+                    warn!("Failed to accept a connection: {}", e);
+                    return;
+                }
+            };
+
+            let open = OpenConnection::new(&self.open);
+            if self.max_connections.is_some_and(|max| open.count > max) {
+                warn!("Too many connections, turning one away.");
+                self.metrics.count(StatusCode::ServiceUnavailable.code());
+                let response = Response::text(StatusCode::ServiceUnavailable, "Too many connections")
+                    .with_header("Connection", "close");
+                let mut out = Vec::new();
+                if response.write_to(&mut out).is_ok() {
+                    let _ = stream.write(&out);
+                }
+                continue;
+            }
+
+            // same as in `serve_until`, a response takes a few writes
+            if let Err(e) = stream.set_nodelay(true) {
+                warn!("Failed to set TCP_NODELAY: {}", e);
+            }
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            if let Err(e) = self.poll.registry().register(&mut stream, token, Interest::READABLE) {
+                warn!("Failed to watch a connection: {}", e);
+                continue;
+            }
+            let conn = Conn {
+                stream,
+                buf: Vec::new(),
+                served: 0,
+                stage: Stage::Idle,
+                end: RequestEnd::default(),
+                deadline: Instant::now() + self.config.idle_timeout,
+                _open: open,
+            };
+            self.conns.insert(token, conn);
+        }
+    }
+
+    /// reads whatever the client sent, and hands the request to the pool once it's all there
+    fn read(&mut self, token: Token, pool: &ThreadPool) {
+        let limits = self.config.limits;
+        let conn = match self.conns.get_mut(&token) {
+            Some(conn) => conn,
+            // gone already, or with a worker
+            None => return,
+        };
+
+        // we're told when more comes in, not while there's some left, so we read all there is.
+        // except when it's more than any request can be, then we stop and it gets refused below
+        let mut closed = false;
+        let mut chunk = [0; READ_CHUNK];
+        while conn.buf.len() <= max_request(&limits) {
+            match conn.stream.read(&mut chunk) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(n) => conn.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // e.g. the client reset the connection, there's no one to answer
+                Err(_) => {
+                    closed = true;
+                    break;
+                }
+            }
+        }
+        let head_was_done = conn.end.head_done();
+        let head = conn.end.parse_head(&conn.buf, limits);
+        conn.update_stage(&self.config);
+
+        // routes that stream the body get the request as soon as the head is in, the worker reads the rest
+        let router = &self.router;
+        let streamed = !head_was_done && conn.end.head().is_some_and(|request| router.streams_body(request));
+        if streamed {
+            let (request, used) = conn.end.take_head().unwrap();
+            conn.buf.drain(..used);
+            self.dispatch(token, request, true, pool);
+            return;
+        }
+
+        match head.and_then(|()| conn.end.parse_body(&conn.buf, limits)) {
+            Ok(Some((request, used))) => {
+                conn.buf.drain(..used);
+                self.dispatch(token, request, false, pool);
+            }
+            Ok(None) if closed => {
+                self.conns.remove(&token);
+            }
+            Ok(None) if conn.buf.len() > max_request(&limits) => {
+                let conn = self.conns.remove(&token).unwrap();
+                conn.refuse(ParseError::BodyTooLarge, &self.metrics);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                let conn = self.conns.remove(&token).unwrap();
+                conn.refuse(e, &self.metrics);
+            }
+        }
+    }
+
+    /// hands the request to a worker, `streamed` when its body is still on the way for the handler to read
+    fn dispatch(&mut self, token: Token, mut request: Request, streamed: bool, pool: &ThreadPool) {
+        let mut conn = self.conns.remove(&token).unwrap();
+        // waiting for room in the queue, or running the request here, would hold up every other connection
+        let room = match pool.reserve() {
+            Ok(room) => room,
+            Err(ExecuteError::QueueFull) => {
+                warn!("Every worker is busy, turning a request away.");
+                conn.close_with(
+                    Response::text(StatusCode::ServiceUnavailable, "Too busy, try again later"),
+                    &self.metrics,
+                );
+                return;
+            }
+            Err(e) => {
+                info!("Not accepting connections anymore: {}", e);
+                return;
+            }
+        };
+        // we don't hear about it while it's with a worker, the worker reads and writes it blocking
+        if let Err(e) = self.poll.registry().deregister(&mut conn.stream) {
+            warn!("Failed to stop watching a connection: {}", e);
+            return;
+        }
+
+        conn.served += 1;
+        let start = Instant::now();
+        let keep_alive =
+            wants_keep_alive(&request) && conn.served < self.config.max_requests && !self.stop.load(Ordering::SeqCst);
+
+        let router = Arc::clone(&self.router);
+        let config = self.config;
+        let metrics = self.metrics.clone();
+        let returned = self.returned.0.clone();
+        let waker = Arc::clone(&self.waker);
+
+        room.execute(move || {
+            let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+            let socket = TcpStream::from(conn.stream);
+            let respond =
+                |request: &mut Request| respond(id, request, &mut &socket, &router, keep_alive, &metrics, start);
+            let buf = &mut conn.buf;
+            let answered = blocking(&socket, &config).and_then(|_| {
+                if streamed {
+                    with_body_from(&socket, buf, &config, &mut request, respond)
+                } else {
+                    respond(&mut request)
+                }
+            });
+            let (keep_alive, upgrade) = match answered {
+                Ok(answered) => answered,
+                Err(e) => {
+                    warn!("Connection error: {}", e);
+                    return;
+                }
+            };
+
+            if let Some(upgrade) = upgrade {
+                // the worker keeps it from now on, same as in `serve_until`
+                let buffered = mem::take(&mut conn.buf);
+                log::with_request(id, || upgrade(Upgraded::new(Connection::Plain(socket), buffered)));
+                return;
+            }
+            if !keep_alive {
+                return;
+            }
+            if let Err(e) = socket.set_nonblocking(true) {
+                warn!("Connection error: {}", e);
+                return;
+            }
+            conn.stream = MioStream::from_std(socket);
+            // once the server stopped, there's no event loop to take it, and it's closed
+            if returned.send((token, conn)).is_ok() {
+                let _ = waker.wake();
+            }
+        });
+    }
+
+    /// watches the connections the workers are done with again
+    fn take_back(&mut self, pool: &ThreadPool) {
+        while let Ok((token, mut conn)) = self.returned.1.try_recv() {
+            // registering tells us if the client sent anything while we weren't watching
+            if let Err(e) = self
+                .poll
+                .registry()
+                .register(&mut conn.stream, token, Interest::READABLE)
+            {
+                warn!("Failed to watch a connection: {}", e);
+                continue;
+            }
+            conn.stage = Stage::Idle;
+            conn.deadline = Instant::now() + self.config.idle_timeout;
+            self.conns.insert(token, conn);
+
+            // the next request may be in already, pipelined behind the last one
+            self.read(token, pool);
+        }
+    }
+
+    /// closes the connections whose client took too long
+    fn time_out(&mut self) {
+        let now = Instant::now();
+        let late: Vec<Token> = self
+            .conns
+            .iter()
+            .filter(|(_, conn)| conn.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in late {
+            let conn = self.conns.remove(&token).unwrap();
+            // a client that didn't start a request just leaves, like in `serve_until`
+            if conn.stage != Stage::Idle {
+                conn.refuse(ParseError::Timeout, &self.metrics);
+            }
+        }
+    }
+}
+
+/// the most a client can send before a request is done, we refuse anything bigger.
+/// chunked bodies take a few more bytes for the chunk sizes, so there's room for those
+fn max_request(limits: &Limits) -> usize {
+    limits.max_head_bytes + limits.max_body_bytes * 2
+}
+
+/// puts a socket the event loop gave us back in the blocking mode the worker code expects
+fn blocking(socket: &TcpStream, config: &ConnectionConfig) -> io::Result<()> {
+    socket.set_nonblocking(false)?;
+    socket.set_write_timeout(Some(config.write_timeout))
+}
+
+/// answers a request the handler reads the body of from `socket`, after what's already in `buf`.
+/// whatever the client sent after the body ends up in `buf`
+fn with_body_from(
+    socket: &TcpStream,
+    buf: &mut Vec<u8>,
+    config: &ConnectionConfig,
+    request: &mut Request,
+    respond: impl FnOnce(&mut Request) -> io::Result<(bool, Option<Upgrade>)>,
+) -> io::Result<(bool, Option<Upgrade>)> {
+    let mut reader = RequestReader::with_limits(socket.try_clone()?, config.limits);
+    reader.unread(buf);
+    reader.set_timeouts(config.timeouts(), |socket, timeout| {
+        socket.set_read_timeout(Some(timeout))
+    });
+
+    let (keep_alive, upgrade, reader) = with_streamed_body(request, reader, respond)?;
+    buf.clear();
+    match reader {
+        Some(reader) => buf.extend_from_slice(reader.buffered()),
+        None => return Ok((false, None)),
+    }
+    // nothing else reads it with a timeout
+    socket.set_read_timeout(None)?;
+    Ok((keep_alive, upgrade))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Method;
+    use std::sync::Mutex;
+
+    fn start(
+        router: Router<Handler>,
+        config: ConnectionConfig,
+        workers: usize,
+    ) -> (std::net::SocketAddr, impl FnOnce()) {
+        start_with(router, config, Arc::new(ThreadPool::new(workers)))
+    }
+
+    fn start_with(
+        router: Router<Handler>,
+        config: ConnectionConfig,
+        pool: Arc<ThreadPool>,
+    ) -> (std::net::SocketAddr, impl FnOnce()) {
+        let server = Server::bind("127.0.0.1:0", router)
+            .unwrap()
+            .config(config)
+            .event_loop(2);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || server.run(&pool));
+
+        let stop = move || {
+            handle.shutdown();
+            running.join().unwrap().unwrap();
+        };
+        (addr, stop)
+    }
+
+    fn hello() -> Router<Handler> {
+        let mut router: Router<Handler> = Router::new();
+        router.get("/:name", |req: &Request| {
+            Response::text(StatusCode::Ok, req.param("name").unwrap())
+        });
+        router
+    }
+
+    fn read_all(mut client: TcpStream) -> String {
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    /// reads until what we got ends with `end`, and leaves the connection open
+    fn read_until(client: &mut TcpStream, end: &str) -> String {
+        let mut out = Vec::new();
+        let mut buf = [0; 256];
+        while !String::from_utf8_lossy(&out).ends_with(end) {
+            let n = client.read(&mut buf).unwrap();
+            assert!(n > 0, "closed early: {:?}", String::from_utf8_lossy(&out));
+            out.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn idle_connections_dont_take_a_worker() {
+        let (addr, stop) = start(hello(), ConnectionConfig::default(), 1);
+
+        // with a single worker, the threads mode would serve only the first of these
+        let mut clients: Vec<TcpStream> = (0..20).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for (i, client) in clients.iter_mut().enumerate() {
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            client
+                .write_all(format!("GET /c{} HTTP/1.1\r\n\r\n", i).as_bytes())
+                .unwrap();
+            let out = read_until(client, &format!("\r\n\r\nc{}", i));
+            assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        }
+        // and they're all still open, pipelining works too
+        for client in &mut clients {
+            client
+                .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+        }
+        for client in clients {
+            let out = read_all(client);
+            assert_eq!(2, out.matches("HTTP/1.1 200 OK").count());
+            assert!(out.ends_with("\r\n\r\nb"));
+        }
+
+        stop();
+    }
+
+    #[test]
+    fn a_full_queue_gets_a_503() {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (go_tx, go_rx) = mpsc::channel::<()>();
+        let (started_tx, go_rx) = (Mutex::new(started_tx), Mutex::new(go_rx));
+        let mut router: Router<Handler> = Router::new();
+        router.get("/slow", move |_: &Request| {
+            started_tx.lock().unwrap().send(()).unwrap();
+            go_rx.lock().unwrap().recv().unwrap();
+            Response::text(StatusCode::Ok, "done")
+        });
+        // the default policy blocks, which the event loop must not do
+        let pool = Arc::new(ThreadPool::builder().size(1).queue_capacity(1).build().unwrap());
+        let (addr, stop) = start_with(router, ConnectionConfig::default(), Arc::clone(&pool));
+
+        let send = || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            client
+        };
+        let running = send();
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let queued = send();
+        while pool.queue_depth() == 0 {
+            thread::yield_now();
+        }
+
+        let out = read_all(send());
+        assert!(out.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", out);
+
+        go_tx.send(()).unwrap();
+        go_tx.send(()).unwrap();
+        assert!(read_all(running).ends_with("\r\n\r\ndone"));
+        assert!(read_all(queued).ends_with("\r\n\r\ndone"));
+        stop();
+    }
+
+    #[test]
+    fn uploads_stream_past_the_body_limit() {
+        use crate::server::tests::{upload_body, upload_limits, upload_router};
+        let (addr, stop) = start(upload_router(), upload_limits(), 1);
+
+        // the head on its own, so the worker gets the request before the body
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=xyz\r\nTransfer-Encoding: chunked\r\n\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(20));
+        for chunk in upload_body(100 * 1024).chunks(1000) {
+            client.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).unwrap();
+            client.write_all(chunk).unwrap();
+            client.write_all(b"\r\n").unwrap();
+        }
+        client.write_all(b"0\r\n\r\n").unwrap();
+        // back with the event loop, and what the handler doesn't read is skipped
+        client
+            .write_all(b"POST /skip HTTP/1.1\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello")
+            .unwrap();
+        let out = read_all(client);
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.contains("\r\n\r\n102400 bytes"));
+        assert!(out.ends_with("\r\n\r\nskipped"));
+
+        stop();
+    }
+
+    #[test]
+    fn trickling_uploads_time_out() {
+        use crate::server::tests::{trickled_upload, upload_limits, upload_router};
+        let config = ConnectionConfig {
+            body_timeout: Duration::from_millis(200),
+            ..upload_limits()
+        };
+        let (addr, stop) = start(upload_router(), config, 1);
+
+        assert_eq!("HTTP/1.1 408 Request Timeout", trickled_upload(addr));
+
+        stop();
+    }
+
+    #[test]
+    fn requests_that_come_in_pieces() {
+        let mut router: Router<Handler> = Router::new();
+        router.post("/echo", |req: &Request| {
+            Response::new(StatusCode::Ok).with_body(req.body.clone())
+        });
+        let (addr, stop) = start(router, ConnectionConfig::default(), 1);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        for piece in [
+            "POST /echo HTTP/1.1\r\nContent-",
+            "Length: 5\r\nConnection: close\r\n",
+            "\r\nhel",
+            "lo",
+        ] {
+            client.write_all(piece.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        let out = read_all(client);
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.ends_with("\r\n\r\nhello"));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        for piece in [
+            "POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n3\r\nhel",
+            "\r\n2\r",
+            "\nlo\r\n0\r\n",
+            "\r\n",
+        ] {
+            client.write_all(piece.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(read_all(client).ends_with("\r\n\r\nhello"));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /a HTTP/1.1\r\nBroken header\r\n\r\n").unwrap();
+        assert!(read_all(client).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        stop();
+    }
+
+    #[test]
+    fn slow_clients_time_out() {
+        let config = ConnectionConfig {
+            idle_timeout: Duration::from_millis(300),
+            header_timeout: Duration::from_millis(300),
+            ..ConnectionConfig::default()
+        };
+        let (addr, stop) = start(hello(), config, 1);
+
+        let idle = TcpStream::connect(addr).unwrap();
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.write_all(b"GET /a HTTP/1.1\r\n").unwrap();
+
+        let started = Instant::now();
+        assert_eq!("", read_all(idle));
+        assert!(read_all(stalled).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(started.elapsed() < Duration::from_secs(3));
+
+        stop();
+    }
+
+    #[test]
+    fn upgrades_keep_the_worker() {
+        let mut router: Router<Handler> = Router::new();
+        router.get("/upgrade", |_: &Request| {
+            Response::new(StatusCode::SwitchingProtocols)
+                .with_header("Connection", "Upgrade")
+                .with_header("Upgrade", "shout")
+                .with_upgrade(|mut upgraded: Upgraded| {
+                    let mut buf = [0; 5];
+                    upgraded.read_exact(&mut buf).unwrap();
+                    upgraded.write_all(&buf.to_ascii_uppercase()).unwrap();
+                })
+        });
+        let (addr, stop) = start(router, ConnectionConfig::default(), 1);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        // the first bytes of the new protocol come along with the request
+        client
+            .write_all(b"GET /upgrade HTTP/1.1\r\nUpgrade: shout\r\n\r\nhel")
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b"lo").unwrap();
+        let out = read_all(client);
+        assert!(out.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(out.ends_with("\r\n\r\nHELLO"));
+
+        stop();
+    }
+
+    #[test]
+    fn requests_are_parsed_once_they_are_all_there() {
+        let limits = Limits::default();
+        let parse =
+            |end: &mut RequestEnd, raw: &[u8]| end.parse_head(raw, limits).and_then(|()| end.parse_body(raw, limits));
+        let only_at_the_end = |raw: &[u8]| {
+            let mut end = RequestEnd::default();
+            // the same request, a byte more each time, like it would come in
+            let used: Vec<_> = (1..=raw.len())
+                .map(|len| parse(&mut end, &raw[..len]).unwrap().map(|(_, used)| used))
+                .collect();
+            let mut expected = vec![None; raw.len()];
+            expected[raw.len() - 1] = Some(raw.len());
+            assert_eq!(expected, used, "{:?}", String::from_utf8_lossy(raw));
+        };
+
+        only_at_the_end(b"GET / HTTP/1.1\r\n\r\n");
+        only_at_the_end(b"\r\nPOST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+        only_at_the_end(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext\r\nhello\r\n1\r\n!\r\n0\r\n\r\n");
+        only_at_the_end(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nTrailer: x\r\n\r\n");
+
+        // broken framing is refused right away, without waiting for a body
+        let refused = |raw: &[u8]| parse(&mut RequestEnd::default(), raw).unwrap_err();
+        assert!(matches!(
+            refused(b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n"),
+            ParseError::InvalidContentLength
+        ));
+        assert!(matches!(
+            refused(b"POST / HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            ParseError::InvalidContentLength
+        ));
+        assert!(matches!(
+            refused(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"),
+            ParseError::InvalidChunk
+        ));
+        assert!(matches!(
+            refused(b"POST / HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n"),
+            ParseError::BodyTooLarge
+        ));
+        assert!(refused(b"BAD\r\n\r\n").to_string().contains("request line"));
+    }
+
+    #[test]
+    fn parsing_says_how_much_it_used() {
+        let limits = Limits::default();
+        let buf = b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n";
+        let mut end = RequestEnd::default();
+        end.parse_head(buf, limits).unwrap();
+        assert_eq!(Some("/a"), end.head().map(|request| request.path.as_str()));
+        let (request, used) = end.parse_body(buf, limits).unwrap().unwrap();
+        assert_eq!((Method::Get, "/a"), (request.method, request.path.as_str()));
+        assert_eq!(19, used);
+        assert!(!end.head_done());
+
+        // the next one isn't all there yet
+        end.parse_head(&buf[used..], limits).unwrap();
+        assert!(end.parse_body(&buf[used..], limits).unwrap().is_none());
+    }
+}
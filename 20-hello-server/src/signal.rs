@@ -0,0 +1,117 @@
+//! Turns SIGINT (ctrl+c) and SIGTERM into a graceful server shutdown.
+
+use std::io;
+
+use crate::server::ShutdownHandle;
+
+/// Shuts the server down when the process gets SIGINT (ctrl+c) or SIGTERM.
+///
+/// A second signal, while we are still shutting down, exits the process right away.
+/// It can only be called once per process.
+#[cfg(unix)]
+pub fn shutdown_on_signal(handle: ShutdownHandle) -> io::Result<()> {
+    unix::install(handle)
+}
+
+#[cfg(not(unix))]
+pub fn shutdown_on_signal(_handle: ShutdownHandle) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "signals are only supported on unix"))
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::unix::io::FromRawFd;
+    use std::process;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::thread;
+
+    use crate::log::info;
+    use crate::server::ShutdownHandle;
+
+    // a signal handler can do almost nothing safely, so it just writes a byte to
+    // a pipe (the "self-pipe trick") and a regular thread does the real work
+    static PIPE_WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn on_signal(_: libc::c_int) {
+        let fd = PIPE_WRITE_FD.load(Ordering::SeqCst);
+        if fd >= 0 {
+            // write is async-signal-safe; if the pipe is full, a byte is already waiting anyway
+            unsafe {
+                libc::write(fd, [1u8].as_ptr() as *const libc::c_void, 1);
+            }
+        }
+    }
+
+    pub fn install(handle: ShutdownHandle) -> io::Result<()> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // from now on the File closes the read end for us
+        let mut signals = unsafe { File::from_raw_fd(fds[0]) };
+
+        if PIPE_WRITE_FD.compare_exchange(-1, fds[1], Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            unsafe { libc::close(fds[1]) };
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "signal handler already installed"));
+        }
+
+        thread::spawn(move || {
+            let mut byte = [0];
+            let mut received = 0;
+
+            loop {
+                match signals.read(&mut byte) {
+                    Ok(1) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    _ => return,
+                }
+
+                received += 1;
+                if received == 1 {
+                    info!("Shutting down, send the signal again to exit right away.");
+                    handle.shutdown();
+                } else {
+                    process::exit(130);
+                }
+            }
+        });
+
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::router::{Handler, Router};
+    use crate::server::Server;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn sigterm_requests_a_shutdown() {
+        let server = Server::bind("127.0.0.1:0", Router::<Handler>::new()).unwrap();
+        let handle = server.shutdown_handle();
+        shutdown_on_signal(handle.clone()).unwrap();
+
+        unsafe { libc::raise(libc::SIGTERM) };
+
+        for _ in 0..100 {
+            if handle.is_requested() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("SIGTERM did not request a shutdown");
+    }
+}
@@ -0,0 +1,358 @@
+//! Server-sent events: a response that never ends, where we push events to the
+//! browser as they happen (see `EventSource` on the browser side).
+//!
+//! The connection keeps its worker busy for as long as the client listens.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::request::Request;
+use crate::response::{BodyStream, Response, StatusCode};
+use crate::router::Handler;
+
+/// One message we push to the client.
+///
+/// ```
+/// use hello_server::Event;
+///
+/// let event = Event::new("50%").with_event("progress").with_id("7");
+/// assert_eq!("id: 7\nevent: progress\ndata: 50%\n\n", event.to_string());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    /// An event of the default "message" type. The data may have many lines.
+    pub fn new(data: impl Into<String>) -> Event {
+        Event {
+            id: None,
+            event: None,
+            data: data.into(),
+            retry: None,
+        }
+    }
+
+    /// The id the client sends back in `Last-Event-ID` when it reconnects.
+    pub fn with_id(mut self, id: impl Into<String>) -> Event {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// The event type, what the client listens to with `addEventListener`.
+    pub fn with_event(mut self, event: impl Into<String>) -> Event {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// How long the client waits before reconnecting, when the connection is lost.
+    pub fn with_retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+}
+
+/// Formats the event the way it goes on the wire.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // a line break would end the field, and let whoever chose the id or type make up new fields
+        let one_line = |s: &str| s.replace(['\r', '\n'], " ");
+
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", one_line(id))?;
+        }
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", one_line(event))?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        // each line of data gets its own field, and the client joins them back with '\n'
+        for line in self.data.replace("\r\n", "\n").split(['\n', '\r']) {
+            writeln!(f, "data: {}", line)?;
+        }
+        writeln!(f)
+    }
+}
+
+/// The response that sends the client every event we get from a channel, until it's closed.
+///
+/// When no event comes for a while, we send a comment instead, so that proxies
+/// don't think the connection is dead, and so that we find out when the client is.
+pub struct EventStream {
+    events: Receiver<Event>,
+    heartbeat: Duration,
+}
+
+impl EventStream {
+    /// Sends a heartbeat after 15 seconds without events.
+    pub fn new(events: Receiver<Event>) -> EventStream {
+        EventStream {
+            events,
+            heartbeat: Duration::from_secs(15),
+        }
+    }
+
+    pub fn heartbeat(mut self, every: Duration) -> EventStream {
+        self.heartbeat = every;
+        self
+    }
+}
+
+impl From<EventStream> for Response {
+    fn from(stream: EventStream) -> Response {
+        let EventStream { events, heartbeat } = stream;
+        let chunks = std::iter::from_fn(move || match events.recv_timeout(heartbeat) {
+            Ok(event) => Some(event.to_string()),
+            // lines starting with ':' are comments, the client ignores them
+            Err(RecvTimeoutError::Timeout) => Some(":\n\n".to_string()),
+            Err(RecvTimeoutError::Disconnected) => None,
+        });
+
+        Response::new(StatusCode::Ok)
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache")
+            .with_stream(BodyStream::new(chunks))
+    }
+}
+
+/// Sends every event published to all of the clients subscribed to it.
+///
+/// The last events are kept around, so that a client that lost its connection
+/// gets the ones it missed when it comes back with `Last-Event-ID`. Clones of a
+/// hub all share the same subscribers.
+#[derive(Clone)]
+pub struct Hub {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    subscribers: Vec<SyncSender<Event>>,
+    backlog: VecDeque<Event>,
+    backlog_size: usize,
+    next_id: u64,
+}
+
+/// how many events a subscriber may fall behind before we give up on it
+const SUBSCRIBER_BUFFER: usize = 64;
+
+impl Hub {
+    /// Keeps the last `backlog_size` events, for the clients that come back.
+    pub fn new(backlog_size: usize) -> Hub {
+        Hub {
+            state: Arc::new(Mutex::new(State {
+                subscribers: Vec::new(),
+                backlog: VecDeque::with_capacity(backlog_size),
+                backlog_size,
+                next_id: 1,
+            })),
+        }
+    }
+
+    /// Sends the event to every subscriber, and returns its id.
+    ///
+    /// Events without an id get one from us, otherwise clients couldn't resume after them.
+    /// A subscriber that fell too far behind is dropped: its stream ends, and its client
+    /// reconnects and gets the events it missed from the backlog.
+    pub fn publish(&self, event: Event) -> String {
+        let mut state = self.lock();
+        let event = match event.id {
+            Some(_) => event,
+            None => {
+                let id = state.next_id.to_string();
+                state.next_id += 1;
+                event.with_id(id)
+            }
+        };
+
+        state
+            .subscribers
+            .retain(|subscriber| match subscriber.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+            });
+
+        if state.backlog.len() == state.backlog_size {
+            state.backlog.pop_front();
+        }
+        if state.backlog_size > 0 {
+            state.backlog.push_back(event.clone());
+        }
+        event.id.unwrap_or_default()
+    }
+
+    /// A stream of the events published from now on.
+    ///
+    /// With the `Last-Event-ID` of a client that reconnects, it starts with the events
+    /// published after that one. When we don't know that id anymore, it starts with
+    /// every event we kept.
+    pub fn subscribe(&self, last_event_id: Option<&str>) -> EventStream {
+        let mut state = self.lock();
+
+        let missed: Vec<Event> = match last_event_id {
+            None => Vec::new(),
+            Some(last) => {
+                let seen = state.backlog.iter().position(|event| event.id() == Some(last));
+                let start = seen.map_or(0, |i| i + 1);
+                state.backlog.iter().skip(start).cloned().collect()
+            }
+        };
+
+        // we hold the lock until the sender is in the list, so no event is missed or sent twice
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BUFFER + missed.len());
+        for event in missed {
+            let _ = sender.try_send(event);
+        }
+        state.subscribers.push(sender);
+
+        EventStream::new(receiver)
+    }
+
+    /// Turns this into a handler that subscribes each request, resuming from its `Last-Event-ID`.
+    pub fn handler(self) -> Handler {
+        Handler::new(move |request: &Request| self.subscribe(request.header("Last-Event-ID")).into())
+    }
+
+    /// How many clients we're sending events to.
+    pub fn subscribers(&self) -> usize {
+        self.lock().subscribers.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Method;
+    use std::thread;
+
+    /// sends the response of an event stream, until the events are over
+    fn written(stream: EventStream) -> String {
+        let response: Response = stream.into();
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn wire_format() {
+        let event = Event::new("line one\nline two\r\nline three")
+            .with_id("42")
+            .with_event("update\ndata: sneaky")
+            .with_retry(Duration::from_secs(3));
+
+        assert_eq!(
+            "id: 42\nevent: update data: sneaky\nretry: 3000\n\
+             data: line one\ndata: line two\ndata: line three\n\n",
+            event.to_string()
+        );
+        assert_eq!("data: \n\n", Event::new("").to_string());
+    }
+
+    #[test]
+    fn events_then_heartbeats_until_the_channel_closes() {
+        let (sender, receiver) = mpsc::channel();
+        sender.send(Event::new("hi")).unwrap();
+        let closer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(sender);
+        });
+
+        let out = written(EventStream::new(receiver).heartbeat(Duration::from_millis(20)));
+        closer.join().unwrap();
+
+        assert!(out.contains("Content-Type: text/event-stream\r\n"));
+        assert!(out.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(out.contains("\r\na\r\ndata: hi\n\n\r\n"));
+        // a heartbeat is an empty comment
+        assert!(out.contains("\r\n3\r\n:\n\n\r\n"));
+        assert!(out.ends_with("0\r\n\r\n"));
+    }
+
+    #[test]
+    fn every_subscriber_gets_every_event() {
+        let hub = Hub::new(10);
+        let first = hub.subscribe(None);
+        let second = hub.subscribe(None);
+        assert_eq!(2, hub.subscribers());
+
+        assert_eq!("1", hub.publish(Event::new("a")));
+        assert_eq!("mine", hub.publish(Event::new("b").with_id("mine")));
+
+        for stream in [first, second] {
+            let events: Vec<Event> = stream.events.try_iter().collect();
+            assert_eq!(
+                vec![Event::new("a").with_id("1"), Event::new("b").with_id("mine")],
+                events
+            );
+        }
+
+        // their streams are gone, so they're dropped on the next publish
+        hub.publish(Event::new("c"));
+        assert_eq!(0, hub.subscribers());
+    }
+
+    #[test]
+    fn resuming_with_last_event_id() {
+        let hub = Hub::new(3);
+        for data in ["a", "b", "c", "d"] {
+            hub.publish(Event::new(data));
+        }
+        let data = |stream: EventStream| stream.events.try_iter().map(|e| e.data).collect::<Vec<_>>();
+
+        // "a" (id 1) fell out of the backlog, so they get all we have
+        assert_eq!(vec!["c", "d"], data(hub.subscribe(Some("2"))));
+        assert_eq!(vec!["b", "c", "d"], data(hub.subscribe(Some("1"))));
+        assert_eq!(Vec::<String>::new(), data(hub.subscribe(Some("4"))));
+        assert_eq!(Vec::<String>::new(), data(hub.subscribe(None)));
+    }
+
+    #[test]
+    fn subscribers_that_fall_behind_are_dropped() {
+        let hub = Hub::new(0);
+        let _slow = hub.subscribe(None);
+
+        for i in 0..SUBSCRIBER_BUFFER {
+            hub.publish(Event::new(i.to_string()));
+        }
+        assert_eq!(1, hub.subscribers());
+        hub.publish(Event::new("one too many"));
+        assert_eq!(0, hub.subscribers());
+    }
+
+    #[test]
+    fn handler_resumes_from_the_request() {
+        let hub = Hub::new(10);
+        hub.publish(Event::new("a"));
+        hub.publish(Event::new("b"));
+        let handler = hub.clone().handler();
+
+        let mut request = Request::new(Method::Get, "/events");
+        request.headers.insert("Last-Event-ID", "1");
+        let response = handler.call(&request);
+        assert_eq!(Some("text/event-stream"), response.headers.get("Content-Type"));
+
+        // with the hub gone no more events can come, so the stream ends after the ones missed
+        drop(handler);
+        drop(hub);
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("id: 2\ndata: b\n\n"));
+        assert!(!out.contains("data: a"));
+    }
+}
@@ -0,0 +1,333 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::httpdate;
+use crate::request::Request;
+use crate::response::{Response, StatusCode};
+use crate::router::Handler;
+use crate::url;
+
+/// Serves the files found under a root directory.
+///
+/// Requests can never reach files outside of the root: `..` segments are
+/// rejected and so are symlinks that point elsewhere.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Turns this into a handler for a route like "/static/*path", where the
+    /// given parameter holds the file path. Without the parameter, the whole
+    /// request path is used.
+    pub fn handler(self, param: &'static str) -> Handler {
+        Handler::new(move |request: &Request| {
+            let path = request.param(param).unwrap_or(&request.path);
+            self.serve(request, path)
+        })
+    }
+
+    /// Answers the request with the file at `path`, relative to the root.
+    ///
+    /// Directories are served by their "index.html" file. Conditional
+    /// (`If-None-Match`, `If-Modified-Since`) and `Range` requests are supported.
+    pub fn serve(&self, request: &Request, path: &str) -> Response {
+        let file_path = match self.resolve(path) {
+            Ok(file_path) => file_path,
+            Err(status) => return Response::text(status, status.reason()),
+        };
+
+        match self.serve_file(request, &file_path) {
+            Ok(response) => response,
+            Err(e) => {
+                let status = error_status(&e);
+                Response::text(status, status.reason())
+            }
+        }
+    }
+
+    /// maps the request path into a path under the root, or tells why it can't
+    fn resolve(&self, path: &str) -> Result<PathBuf, StatusCode> {
+        let decoded = url::percent_decode(path).ok_or(StatusCode::BadRequest)?;
+        let mut file_path = self.root.clone();
+
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return Err(StatusCode::Forbidden),
+                s if s.contains('\\') || s.contains('\0') || s.contains(':') => return Err(StatusCode::Forbidden),
+                s => file_path.push(s),
+            }
+        }
+
+        if file_path.is_dir() {
+            file_path.push("index.html");
+        }
+
+        // a symlink may still take us out of the root, so we compare the real paths
+        let real_root = fs::canonicalize(&self.root).map_err(|e| error_status(&e))?;
+        let real_path = fs::canonicalize(&file_path).map_err(|e| error_status(&e))?;
+        if !real_path.starts_with(&real_root) {
+            return Err(StatusCode::Forbidden);
+        }
+
+        Ok(real_path)
+    }
+
+    fn serve_file(&self, request: &Request, path: &Path) -> io::Result<Response> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+
+        let len = metadata.len();
+        let modified = metadata.modified()?;
+        let mtime = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let etag = format!("\"{:x}-{:x}\"", len, mtime);
+        let last_modified = httpdate::format(modified);
+
+        let response = Response::new(StatusCode::Ok)
+            .with_header("Content-Type", content_type(path))
+            .with_header("Accept-Ranges", "bytes")
+            .with_header("ETag", etag.as_str())
+            .with_header("Last-Modified", last_modified.as_str());
+
+        if is_not_modified(request, &etag, mtime) {
+            let mut response = response;
+            response.status = StatusCode::NotModified;
+            return Ok(response);
+        }
+
+        let range = match request.header("Range") {
+            Some(range) if if_range_matches(request, &etag, &last_modified) => parse_range(range, len),
+            _ => None,
+        };
+
+        match range {
+            Some(Ok((start, end))) => {
+                let mut body = Vec::with_capacity((end - start + 1) as usize);
+                file.seek(SeekFrom::Start(start))?;
+                file.take(end - start + 1).read_to_end(&mut body)?;
+
+                let mut response = response
+                    .with_header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+                    .with_body(body);
+                response.status = StatusCode::PartialContent;
+                Ok(response)
+            }
+            Some(Err(())) => Ok(Response::new(StatusCode::RangeNotSatisfiable)
+                .with_header("Content-Range", format!("bytes */{}", len))),
+            None => {
+                let mut body = Vec::with_capacity(len as usize);
+                file.read_to_end(&mut body)?;
+                Ok(response.with_body(body))
+            }
+        }
+    }
+}
+
+/// If-None-Match wins over If-Modified-Since when the client sends both
+fn is_not_modified(request: &Request, etag: &str, mtime: u64) -> bool {
+    if let Some(tags) = request.header("If-None-Match") {
+        return tags
+            .split(',')
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == etag);
+    }
+
+    match request.header("If-Modified-Since").and_then(httpdate::parse) {
+        Some(since) => mtime <= since.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        None => false,
+    }
+}
+
+/// a Range is only honored if the file didn't change since the client got its first part
+fn if_range_matches(request: &Request, etag: &str, last_modified: &str) -> bool {
+    match request.header("If-Range") {
+        Some(validator) => validator == etag || validator == last_modified,
+        None => true,
+    }
+}
+
+/// Parses "bytes=start-end", "bytes=start-" and "bytes=-suffix_len" into an
+/// inclusive range of the file.
+///
+/// `None` means we ignore the header (and serve the whole file), which is what
+/// we do with ranges we don't understand, like multiple ranges.
+/// `Some(Err(()))` means the range is outside of the file.
+fn parse_range(header: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+
+    let dash = spec.find('-')?;
+    let (start, end) = (spec[..dash].trim(), spec[dash + 1..].trim());
+
+    let range = if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end: u64 = if end.is_empty() { u64::MAX } else { end.parse().ok()? };
+        if end < start {
+            return None;
+        }
+        if start >= len {
+            return Some(Err(()));
+        }
+        (start, end.min(len - 1))
+    };
+
+    Some(Ok(range))
+}
+
+/// Guesses the Content-Type from the file extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+fn error_status(e: &io::Error) -> StatusCode {
+    match e.kind() {
+        io::ErrorKind::NotFound => StatusCode::NotFound,
+        io::ErrorKind::PermissionDenied => StatusCode::Forbidden,
+        _ => StatusCode::InternalServerError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Method;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// creates a fresh directory with a couple of files for each test
+    fn root() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNT.fetch_add(1, Ordering::SeqCst);
+        let root = std::env::temp_dir().join(format!("hello_server_static_{}_{}", std::process::id(), n));
+
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("hello.txt"), "hello world").unwrap();
+        fs::write(root.join("docs/index.html"), "<h1>docs</h1>").unwrap();
+        fs::write(root.join("pixel.png"), [0x89, b'P', b'N', b'G', 0, 0xff]).unwrap();
+        root
+    }
+
+    fn get(files: &StaticFiles, path: &str, headers: &[(&str, &str)]) -> Response {
+        let mut request = Request::new(Method::Get, path);
+        for (name, value) in headers {
+            request.headers.append(*name, *value);
+        }
+        files.serve(&request, path)
+    }
+
+    #[test]
+    fn serves_files_with_their_type() {
+        let files = StaticFiles::new(root());
+
+        let response = get(&files, "hello.txt", &[]);
+        assert_eq!(StatusCode::Ok, response.status);
+        assert_eq!(Some("text/plain; charset=utf-8"), response.headers.get("Content-Type"));
+        assert_eq!(b"hello world".to_vec(), response.body);
+
+        let response = get(&files, "pixel.png", &[]);
+        assert_eq!(Some("image/png"), response.headers.get("Content-Type"));
+        assert_eq!(vec![0x89, b'P', b'N', b'G', 0, 0xff], response.body);
+
+        assert_eq!(b"<h1>docs</h1>".to_vec(), get(&files, "docs/", &[]).body);
+    }
+
+    #[test]
+    fn missing_files_and_traversal() {
+        let files = StaticFiles::new(root().join("docs"));
+
+        assert_eq!(StatusCode::NotFound, get(&files, "nope.html", &[]).status);
+        assert_eq!(StatusCode::Forbidden, get(&files, "../hello.txt", &[]).status);
+        assert_eq!(StatusCode::Forbidden, get(&files, "%2e%2e/hello.txt", &[]).status);
+        assert_eq!(StatusCode::BadRequest, get(&files, "%zz", &[]).status);
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let files = StaticFiles::new(root());
+        let response = get(&files, "hello.txt", &[]);
+        let etag = response.headers.get("ETag").unwrap();
+        let last_modified = response.headers.get("Last-Modified").unwrap();
+
+        let response = get(&files, "hello.txt", &[("If-None-Match", etag)]);
+        assert_eq!(StatusCode::NotModified, response.status);
+        assert!(response.body.is_empty());
+
+        assert_eq!(StatusCode::NotModified, get(&files, "hello.txt", &[("If-Modified-Since", last_modified)]).status);
+        assert_eq!(StatusCode::Ok, get(&files, "hello.txt", &[("If-None-Match", "\"other\"")]).status);
+        assert_eq!(
+            StatusCode::Ok,
+            get(&files, "hello.txt", &[("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")]).status
+        );
+    }
+
+    #[test]
+    fn range_requests() {
+        let files = StaticFiles::new(root());
+
+        let response = get(&files, "hello.txt", &[("Range", "bytes=0-4")]);
+        assert_eq!(StatusCode::PartialContent, response.status);
+        assert_eq!(Some("bytes 0-4/11"), response.headers.get("Content-Range"));
+        assert_eq!(b"hello".to_vec(), response.body);
+
+        assert_eq!(b"world".to_vec(), get(&files, "hello.txt", &[("Range", "bytes=-5")]).body);
+        assert_eq!(b"world".to_vec(), get(&files, "hello.txt", &[("Range", "bytes=6-")]).body);
+
+        let response = get(&files, "hello.txt", &[("Range", "bytes=20-30")]);
+        assert_eq!(StatusCode::RangeNotSatisfiable, response.status);
+        assert_eq!(Some("bytes */11"), response.headers.get("Content-Range"));
+
+        // we don't do multiple ranges, nor ranges over a file that changed
+        assert_eq!(StatusCode::Ok, get(&files, "hello.txt", &[("Range", "bytes=0-1,3-4")]).status);
+        assert_eq!(StatusCode::Ok, get(&files, "hello.txt", &[("Range", "bytes=0-1"), ("If-Range", "\"old\"")]).status);
+    }
+}
@@ -0,0 +1,209 @@
+//! HTTPS: the same HTTP, inside a TLS session (only with the `tls` feature).
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection};
+
+/// Starts a TLS session on each connection, with our certificate, see `Server::tls`.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    /// `cert` is the certificate chain in PEM, ours first, and `key` its private key.
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> io::Result<TlsAcceptor> {
+        let invalid = |what: &str, e: &dyn std::fmt::Display| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", what, e))
+        };
+
+        let chain = CertificateDer::pem_slice_iter(cert)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid("bad certificate", &e))?;
+        if chain.is_empty() {
+            return Err(invalid("bad certificate", &"no certificate in the PEM"));
+        }
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(|e| invalid("bad private key", &e))?;
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key))
+            .map_err(|e| invalid("can't use the certificate", &e))?;
+        // we only speak HTTP/1.1, this tells clients that ask so they don't try h2
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(TlsAcceptor {
+            config: Arc::new(config),
+        })
+    }
+
+    pub fn from_pem_files(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> io::Result<TlsAcceptor> {
+        TlsAcceptor::from_pem(&fs::read(cert)?, &fs::read(key)?)
+    }
+
+    /// Wraps the socket; the handshake happens with the first read.
+    pub(crate) fn accept(&self, socket: TcpStream) -> io::Result<TlsStream> {
+        let session = ServerConnection::new(Arc::clone(&self.config)).map_err(io::Error::other)?;
+        Ok(TlsStream {
+            session: RefCell::new(session),
+            socket,
+        })
+    }
+}
+
+/// A connection over TLS.
+///
+/// Like `&TcpStream`, `&TlsStream` reads and writes, so that one connection has a
+/// reader and a writer at the same time. It's only used from one thread, and a
+/// read never happens in the middle of a write, so the RefCell is never borrowed twice.
+pub(crate) struct TlsStream {
+    session: RefCell<ServerConnection>,
+    socket: TcpStream,
+}
+
+impl TlsStream {
+    /// the socket under it, e.g. to set timeouts on it
+    pub(crate) fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut session = self.session.borrow_mut();
+        let mut socket = &self.socket;
+        match rustls::Stream::new(&mut *session, &mut socket).read(buf) {
+            // the client closed the TCP connection without saying goodbye first, which
+            // lots of them do. we always know how long a request is, so nothing's lost
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.borrow_mut();
+        let mut socket = &self.socket;
+        rustls::Stream::new(&mut *session, &mut socket).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.borrow_mut();
+        let mut socket = &self.socket;
+        rustls::Stream::new(&mut *session, &mut socket).flush()
+    }
+}
+
+impl Drop for TlsStream {
+    /// tells the client we're done on purpose, so it knows nothing was cut off
+    fn drop(&mut self) {
+        let session = self.session.get_mut();
+        session.send_close_notify();
+        let mut socket = &self.socket;
+        while session.wants_write() {
+            if session.write_tls(&mut socket).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::ThreadPool;
+    use crate::request::Request;
+    use crate::response::{Response, StatusCode};
+    use crate::router::{Handler, Router};
+    use crate::server::Server;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use std::convert::TryFrom;
+    use std::thread;
+    use std::time::Duration;
+
+    /// a certificate for "localhost", signed by itself, and its key
+    fn self_signed() -> (String, String) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        (certified.cert.pem(), certified.signing_key.serialize_pem())
+    }
+
+    /// a client that trusts only `cert`
+    fn client(cert: &str, addr: std::net::SocketAddr) -> StreamOwned<ClientConnection, TcpStream> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(cert.as_bytes()).unwrap())
+            .unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let session = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let socket = TcpStream::connect(addr).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        StreamOwned::new(session, socket)
+    }
+
+    #[test]
+    fn serves_https() {
+        let (cert, key) = self_signed();
+        let mut router: Router<Handler> = Router::new();
+        router.get("/:name", |request: &Request| {
+            Response::text(StatusCode::Ok, format!("hello, {}", request.param("name").unwrap()))
+        });
+
+        let server = Server::bind("127.0.0.1:0", router)
+            .unwrap()
+            .tls(TlsAcceptor::from_pem(cert.as_bytes(), key.as_bytes()).unwrap());
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let running = thread::spawn(move || {
+            let pool = ThreadPool::new(2);
+            server.run(&pool).unwrap();
+        });
+
+        let mut tls = client(&cert, addr);
+        tls.write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut out = String::new();
+        tls.read_to_string(&mut out).unwrap();
+        assert_eq!(2, out.matches("HTTP/1.1 200 OK").count());
+        assert!(out.ends_with("\r\n\r\nhello, b"));
+
+        // plain HTTP gets no answer we could read
+        let mut plain = TcpStream::connect(addr).unwrap();
+        plain.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        plain.write_all(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+        let mut out = Vec::new();
+        let _ = plain.read_to_end(&mut out);
+        assert!(!String::from_utf8_lossy(&out).contains("hello"));
+
+        shutdown.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn bad_pem() {
+        let (cert, key) = self_signed();
+        let error = |cert: &str, key: &str| TlsAcceptor::from_pem(cert.as_bytes(), key.as_bytes()).err().unwrap();
+
+        assert!(error("", &key).to_string().starts_with("bad certificate"));
+        assert!(error(&cert, "").to_string().starts_with("bad private key"));
+        // a key that isn't the certificate's
+        let (_, other_key) = self_signed();
+        assert!(error(&cert, &other_key)
+            .to_string()
+            .starts_with("can't use the certificate"));
+    }
+}
@@ -0,0 +1,45 @@
+//! Helpers for the parts of a URL we receive in the request target.
+
+/// Decodes `%XX` escapes, e.g. "my%20file.txt" becomes "my file.txt".
+///
+/// Returns `None` for broken escapes or when the result is not valid UTF-8.
+pub fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(Some("my file.txt".to_string()), percent_decode("my%20file.txt"));
+        assert_eq!(Some("../etc".to_string()), percent_decode("%2e%2E/etc"));
+        assert_eq!(Some("ação".to_string()), percent_decode("a%C3%A7%C3%A3o"));
+    }
+
+    #[test]
+    fn broken_escapes() {
+        assert_eq!(None, percent_decode("100%"));
+        assert_eq!(None, percent_decode("%zz"));
+        assert_eq!(None, percent_decode("%ff"));
+    }
+}
@@ -0,0 +1,739 @@
+//! WebSocket (RFC 6455): after a handshake over HTTP, the connection carries
+//! messages both ways, whenever either side has something to say.
+//!
+//! Like server-sent events, a connection keeps its worker busy for as long as it's open.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::base64;
+use crate::log::debug;
+use crate::request::{is_timeout, Method, Request, Version};
+use crate::response::{Response, StatusCode};
+use crate::router::Handler;
+use crate::server::Upgraded;
+use crate::sha1::sha1;
+
+mod frame;
+
+use frame::{Frame, FrameError, OpCode};
+
+/// what we add to the client's key before hashing it, the same for everybody (RFC 6455, section 1.3)
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// how long `close` waits for the client to answer with its own close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how long a client may send nothing at all before we close the connection, unless told otherwise
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const READ_CHUNK: usize = 4096;
+
+/// A whole message, however many fragments it came in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Message {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Message {
+        Message::Text(text)
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(bytes: Vec<u8>) -> Message {
+        Message::Binary(bytes)
+    }
+}
+
+/// Why a connection was closed, as sent in close frames (RFC 6455, section 7.4.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /// 1000, we're done
+    Normal,
+    /// 1001, the server is going down, or the browser is leaving the page
+    GoingAway,
+    /// 1002
+    ProtocolError,
+    /// 1003, e.g. a binary message to an endpoint that only understands text
+    Unsupported,
+    /// 1005, the close frame didn't say. It's never sent
+    NoStatus,
+    /// 1007, e.g. a text message that isn't UTF-8
+    InvalidPayload,
+    /// 1008
+    PolicyViolation,
+    /// 1009
+    MessageTooBig,
+    /// 1011, something went wrong on our side
+    InternalError,
+    /// any other code, like the 4000-4999 ones that applications make up
+    Other(u16),
+}
+
+impl CloseCode {
+    pub fn code(&self) -> u16 {
+        match self {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::Unsupported => 1003,
+            CloseCode::NoStatus => 1005,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::InternalError => 1011,
+            CloseCode::Other(code) => *code,
+        }
+    }
+
+    /// whether a close frame may carry this code; some are only for telling what happened locally
+    fn is_sendable(code: u16) -> bool {
+        matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> CloseCode {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::Unsupported,
+            1005 => CloseCode::NoStatus,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1011 => CloseCode::InternalError,
+            code => CloseCode::Other(code),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    Io(io::Error),
+    /// the client broke the protocol, or went quiet for too long, so we closed the connection with this code
+    Protocol(CloseCode, &'static str),
+    /// `recv_timeout` ran out of time; the connection is still fine
+    Timeout,
+    /// the connection was closed already, nothing more can be sent or received
+    Closed,
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebSocketError::Io(e) => write!(f, "i/o error: {}", e),
+            WebSocketError::Protocol(code, reason) => write!(f, "{} (closed with {})", reason, code.code()),
+            WebSocketError::Timeout => f.write_str("no message came in time"),
+            WebSocketError::Closed => f.write_str("the connection is closed"),
+        }
+    }
+}
+
+impl Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WebSocketError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(e: io::Error) -> WebSocketError {
+        WebSocketError::Io(e)
+    }
+}
+
+/// One client's connection, once the handshake is over.
+///
+/// Pings from the client are answered for us, and so is a close frame, after
+/// which `recv` returns `Ok(None)`. A client that sends nothing for a minute
+/// is closed with `CloseCode::GoingAway`, so it doesn't keep a worker busy
+/// forever. When the handler is done with it, the connection is closed with
+/// `CloseCode::Normal` if it's still open.
+///
+/// ```no_run
+/// use hello_server::{Handler, Router, WebSocket};
+///
+/// let mut router: Router<Handler> = Router::new();
+/// router.get(
+///     "/echo",
+///     WebSocket::handler(|mut ws| {
+///         while let Ok(Some(message)) = ws.recv() {
+///             if ws.send(message).is_err() {
+///                 break;
+///             }
+///         }
+///     }),
+/// );
+/// ```
+pub struct WebSocket {
+    conn: Upgraded,
+    /// what we read but didn't make into a frame yet
+    buf: Vec<u8>,
+    /// the message being put together from its fragments
+    fragments: Option<(OpCode, Vec<u8>)>,
+    max_message_size: usize,
+    max_frame_size: usize,
+    idle_timeout: Option<Duration>,
+    sent_close: bool,
+    received_close: Option<CloseCode>,
+}
+
+impl WebSocket {
+    /// Answers a WebSocket handshake, and runs `handler` with the connection after it.
+    ///
+    /// Requests that aren't a valid handshake get a 400, or a 426 when they don't ask
+    /// for a WebSocket at all, or for a version we don't speak. `handler` runs on the
+    /// worker that served the request.
+    pub fn upgrade(request: &Request, handler: impl FnOnce(WebSocket) + Send + 'static) -> Response {
+        let key = match handshake(request) {
+            Ok(key) => key,
+            Err(response) => return response,
+        };
+
+        Response::new(StatusCode::SwitchingProtocols)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Accept", accept_key(key))
+            .with_upgrade(move |conn| handler(WebSocket::new(conn)))
+    }
+
+    /// A handler that upgrades every request, and runs `handler` with each connection.
+    pub fn handler(handler: impl Fn(WebSocket) + Send + Sync + 'static) -> Handler {
+        let handler = Arc::new(handler);
+        Handler::new(move |request: &Request| {
+            let handler = Arc::clone(&handler);
+            WebSocket::upgrade(request, move |ws| handler(ws))
+        })
+    }
+
+    fn new(conn: Upgraded) -> WebSocket {
+        WebSocket {
+            conn,
+            buf: Vec::new(),
+            fragments: None,
+            max_message_size: 16 * 1024 * 1024,
+            max_frame_size: 64 * 1024,
+            idle_timeout: Some(IDLE_TIMEOUT),
+            sent_close: false,
+            received_close: None,
+        }
+    }
+
+    /// The biggest message we take, 16MB by default. A bigger one closes the connection with `MessageTooBig`.
+    pub fn set_max_message_size(&mut self, bytes: usize) {
+        self.max_message_size = bytes;
+    }
+
+    /// Messages we send that are bigger than this go out in fragments, 64KB by default.
+    pub fn set_max_frame_size(&mut self, bytes: usize) {
+        self.max_frame_size = bytes.max(1);
+    }
+
+    /// How long the client may send nothing, not even a ping, before we close the connection
+    /// with `GoingAway`; a minute by default. `None` waits for it forever.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Waits for the next message.
+    ///
+    /// Returns `Ok(None)` once the client closed the connection.
+    pub fn recv(&mut self) -> Result<Option<Message>, WebSocketError> {
+        self.recv_until(None)
+    }
+
+    /// Like `recv`, but gives up with `WebSocketError::Timeout` when no message came in time,
+    /// e.g. to send the client some news every now and then while listening to it.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Message>, WebSocketError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    pub fn send(&mut self, message: impl Into<Message>) -> Result<(), WebSocketError> {
+        if self.sent_close {
+            return Err(WebSocketError::Closed);
+        }
+        let message = message.into();
+        let (opcode, payload) = match &message {
+            Message::Text(text) => (OpCode::Text, text.as_bytes()),
+            Message::Binary(bytes) => (OpCode::Binary, &bytes[..]),
+        };
+
+        // only the first fragment says what kind of message it is
+        let fragments = payload.len().div_ceil(self.max_frame_size).max(1);
+        for i in 0..fragments {
+            let start = i * self.max_frame_size;
+            let end = payload.len().min(start + self.max_frame_size);
+            let opcode = if i == 0 { opcode } else { OpCode::Continuation };
+            frame::write(&mut self.conn, i == fragments - 1, opcode, &payload[start..end])?;
+        }
+        Ok(())
+    }
+
+    /// Sends a ping, which the client answers with a pong. Only the first 125 bytes of `payload` are sent.
+    pub fn ping(&mut self, payload: &[u8]) -> Result<(), WebSocketError> {
+        if self.sent_close {
+            return Err(WebSocketError::Closed);
+        }
+        frame::write(&mut self.conn, true, OpCode::Ping, &payload[..payload.len().min(125)])?;
+        Ok(())
+    }
+
+    /// Closes the connection, waiting a little for the client to agree.
+    ///
+    /// Whatever the client sends in the meantime is dropped.
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), WebSocketError> {
+        if self.sent_close {
+            return Ok(());
+        }
+        self.send_close(code, reason)?;
+
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while self.received_close.is_none() {
+            let frame = match self.next_frame(Some(deadline)) {
+                Ok(frame) => frame,
+                // it's going away either way
+                Err(WebSocketError::Timeout) => break,
+                Err(e) => return Err(e),
+            };
+            if frame.opcode == OpCode::Close {
+                self.received_close = Some(self.close_code_in(&frame.payload)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// The code the client closed the connection with, once it did.
+    pub fn close_code(&self) -> Option<CloseCode> {
+        self.received_close
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<Option<Message>, WebSocketError> {
+        if self.sent_close || self.received_close.is_some() {
+            return Err(WebSocketError::Closed);
+        }
+
+        loop {
+            let frame = self.next_frame(deadline)?;
+            match frame.opcode {
+                OpCode::Ping => frame::write(&mut self.conn, true, OpCode::Pong, &frame.payload)?,
+                // we don't wait for the answers to our pings, and clients may send them unasked
+                OpCode::Pong => {}
+                OpCode::Close => {
+                    let code = self.close_code_in(&frame.payload)?;
+                    self.received_close = Some(code);
+                    // we answer with the same code, then the server closes the TCP connection
+                    self.send_close(code, "")?;
+                    return Ok(None);
+                }
+                OpCode::Text | OpCode::Binary => {
+                    if self.fragments.is_some() {
+                        return Err(self.fail(CloseCode::ProtocolError, "a new message before the last one ended"));
+                    }
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+                OpCode::Continuation => {
+                    let too_big = match &mut self.fragments {
+                        None => return Err(self.fail(CloseCode::ProtocolError, "a fragment of no message")),
+                        Some((_, data)) => {
+                            data.extend_from_slice(&frame.payload);
+                            data.len() > self.max_message_size
+                        }
+                    };
+                    if too_big {
+                        return Err(self.fail(CloseCode::MessageTooBig, "the message is too big"));
+                    }
+                }
+            }
+
+            if frame.fin && !frame.opcode.is_control() {
+                let (opcode, data) = self.fragments.take().unwrap();
+                return match opcode {
+                    OpCode::Text => match String::from_utf8(data) {
+                        Ok(text) => Ok(Some(Message::Text(text))),
+                        Err(_) => Err(self.fail(CloseCode::InvalidPayload, "a text message that isn't UTF-8")),
+                    },
+                    _ => Ok(Some(Message::Binary(data))),
+                };
+            }
+        }
+    }
+
+    /// reads until there's a whole frame, keeping what we have when the deadline goes by
+    fn next_frame(&mut self, deadline: Option<Instant>) -> Result<Frame, WebSocketError> {
+        let mut last_read = Instant::now();
+        loop {
+            match frame::parse(&self.buf, self.max_message_size) {
+                Ok(Some((frame, used))) => {
+                    self.buf.drain(..used);
+                    return Ok(frame);
+                }
+                Ok(None) => {}
+                Err(FrameError { code, reason }) => return Err(self.fail(code, reason)),
+            }
+
+            let now = Instant::now();
+            let mut timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(now) {
+                    Some(left) if !left.is_zero() => Some(left),
+                    _ => return Err(WebSocketError::Timeout),
+                },
+                None => None,
+            };
+            // once we sent a close, we only wait for the answer, and `close` has its own deadline for that
+            if let Some(idle) = self.idle_timeout.filter(|_| !self.sent_close) {
+                match (last_read + idle).checked_duration_since(now) {
+                    Some(left) if !left.is_zero() => timeout = Some(timeout.map_or(left, |t| t.min(left))),
+                    _ => return Err(self.fail(CloseCode::GoingAway, "the client was quiet for too long")),
+                }
+            }
+            self.conn.get_ref().set_read_timeout(timeout)?;
+
+            let mut chunk = [0; READ_CHUNK];
+            match self.conn.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    last_read = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // the deadlines are checked on the next round
+                Err(e) if timeout.is_some() && is_timeout(&e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// the code in the payload of a close frame, which may also have a UTF-8 reason after it
+    fn close_code_in(&mut self, payload: &[u8]) -> Result<CloseCode, WebSocketError> {
+        match payload {
+            [] => Ok(CloseCode::NoStatus),
+            [_] => Err(self.fail(CloseCode::ProtocolError, "a close frame with half a code")),
+            [high, low, reason @ ..] => {
+                let code = u16::from_be_bytes([*high, *low]);
+                if !CloseCode::is_sendable(code) {
+                    return Err(self.fail(CloseCode::ProtocolError, "a close frame with an invalid code"));
+                }
+                if std::str::from_utf8(reason).is_err() {
+                    return Err(self.fail(CloseCode::InvalidPayload, "a close reason that isn't UTF-8"));
+                }
+                Ok(CloseCode::from(code))
+            }
+        }
+    }
+
+    fn send_close(&mut self, code: CloseCode, reason: &str) -> Result<(), WebSocketError> {
+        self.sent_close = true;
+
+        let mut payload = Vec::new();
+        if code != CloseCode::NoStatus {
+            payload.extend_from_slice(&code.code().to_be_bytes());
+            // control frames are 125 bytes at most, and we can't cut a character in half
+            let mut end = reason.len().min(123);
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            payload.extend_from_slice(&reason.as_bytes()[..end]);
+        }
+        frame::write(&mut self.conn, true, OpCode::Close, &payload)?;
+        Ok(())
+    }
+
+    /// closes the connection because the client broke the protocol
+    fn fail(&mut self, code: CloseCode, reason: &'static str) -> WebSocketError {
+        debug!("Closing a WebSocket: {}", reason);
+        if !self.sent_close {
+            // the client may be gone already, there's nothing else to tell it anyway
+            let _ = self.send_close(code, reason);
+        }
+        WebSocketError::Protocol(code, reason)
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        if !self.sent_close {
+            let _ = self.send_close(CloseCode::Normal, "");
+        }
+    }
+}
+
+/// checks the handshake request, and returns its key
+fn handshake(request: &Request) -> Result<&str, Response> {
+    let headers = &request.headers;
+    if !headers.has_token("Upgrade", "websocket") {
+        return Err(
+            Response::text(StatusCode::UpgradeRequired, "This is a WebSocket endpoint.")
+                .with_header("Upgrade", "websocket")
+                .with_header("Connection", "Upgrade"),
+        );
+    }
+
+    let bad_request = |why: &str| Err(Response::text(StatusCode::BadRequest, why));
+    if request.method != Method::Get || request.version != Version::Http11 {
+        return bad_request("The WebSocket handshake is an HTTP/1.1 GET.");
+    }
+    if !headers.has_token("Connection", "Upgrade") {
+        return bad_request("The WebSocket handshake needs Connection: Upgrade.");
+    }
+    if headers.get("Sec-WebSocket-Version") != Some("13") {
+        return Err(
+            Response::text(StatusCode::UpgradeRequired, "Unsupported WebSocket version.")
+                .with_header("Sec-WebSocket-Version", "13"),
+        );
+    }
+    // the key is 16 random bytes, in base64
+    match headers.get("Sec-WebSocket-Key") {
+        Some(key) if base64::decode(key).is_some_and(|bytes| bytes.len() == 16) => Ok(key),
+        _ => bad_request("Missing or invalid Sec-WebSocket-Key."),
+    }
+}
+
+/// proves to the client that we understood its handshake
+fn accept_key(key: &str) -> String {
+    base64::encode(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+    use crate::server::{serve_connection, Connection, ConnectionConfig};
+    use frame::masked;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// a WebSocket and the client's end of its connection
+    fn connected() -> (WebSocket, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (WebSocket::new(Upgraded::new(Connection::Plain(server), Vec::new())), client)
+    }
+
+    /// reads one (unmasked) frame from the server, as its first byte and payload
+    fn read_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).unwrap();
+        let len = match head[1] {
+            126 => {
+                let mut len = [0; 2];
+                client.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        client.read_exact(&mut payload).unwrap();
+        (head[0], payload)
+    }
+
+    fn handshake_request(key: &str) -> Request {
+        let mut request = Request::new(Method::Get, "/ws");
+        request.headers.insert("Upgrade", "websocket");
+        request.headers.insert("Connection", "keep-alive, Upgrade");
+        request.headers.insert("Sec-WebSocket-Version", "13");
+        request.headers.insert("Sec-WebSocket-Key", key);
+        request
+    }
+
+    #[test]
+    fn accepts_the_handshake() {
+        // the example in RFC 6455, section 1.3
+        let response = WebSocket::upgrade(&handshake_request("dGhlIHNhbXBsZSBub25jZQ=="), |_| {});
+        assert_eq!(StatusCode::SwitchingProtocols, response.status);
+        assert_eq!(
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+            response.headers.get("Sec-WebSocket-Accept")
+        );
+        assert_eq!(Some("websocket"), response.headers.get("Upgrade"));
+    }
+
+    #[test]
+    fn refuses_bad_handshakes() {
+        let status = |request: &Request| WebSocket::upgrade(request, |_| {}).status;
+
+        assert_eq!(StatusCode::UpgradeRequired, status(&Request::new(Method::Get, "/ws")));
+        assert_eq!(StatusCode::BadRequest, status(&handshake_request("too short")));
+
+        let mut request = handshake_request("dGhlIHNhbXBsZSBub25jZQ==");
+        request.headers.insert("Sec-WebSocket-Version", "8");
+        let response = WebSocket::upgrade(&request, |_| {});
+        assert_eq!(StatusCode::UpgradeRequired, response.status);
+        assert_eq!(Some("13"), response.headers.get("Sec-WebSocket-Version"));
+
+        let mut request = handshake_request("dGhlIHNhbXBsZSBub25jZQ==");
+        request.method = Method::Post;
+        assert_eq!(StatusCode::BadRequest, status(&request));
+    }
+
+    #[test]
+    fn fragments_pings_and_closing() {
+        let (mut ws, mut client) = connected();
+
+        // a ping may come between the fragments of a message
+        let mut sent = masked(false, OpCode::Text, b"hello, ");
+        sent.extend(masked(true, OpCode::Ping, b"are you there?"));
+        sent.extend(masked(false, OpCode::Continuation, "wörld".as_bytes()));
+        sent.extend(masked(true, OpCode::Continuation, b"!"));
+        sent.extend(masked(true, OpCode::Binary, &[1, 2, 3]));
+        sent.extend(masked(true, OpCode::Close, b"\x03\xe8bye"));
+        client.write_all(&sent).unwrap();
+
+        assert_eq!(Some(Message::Text("hello, wörld!".to_string())), ws.recv().unwrap());
+        assert_eq!((0x8A, b"are you there?".to_vec()), read_frame(&mut client));
+        assert_eq!(Some(Message::Binary(vec![1, 2, 3])), ws.recv().unwrap());
+        assert_eq!(None, ws.recv().unwrap());
+        assert_eq!(Some(CloseCode::Normal), ws.close_code());
+        // we answer the close with the same code
+        assert_eq!((0x88, vec![0x03, 0xe8]), read_frame(&mut client));
+        assert!(matches!(ws.send("too late"), Err(WebSocketError::Closed)));
+    }
+
+    #[test]
+    fn sends_big_messages_in_fragments() {
+        let (mut ws, mut client) = connected();
+        ws.set_max_frame_size(4);
+
+        ws.send("hello world").unwrap();
+        assert_eq!((0x01, b"hell".to_vec()), read_frame(&mut client));
+        assert_eq!((0x00, b"o wo".to_vec()), read_frame(&mut client));
+        assert_eq!((0x80, b"rld".to_vec()), read_frame(&mut client));
+
+        ws.send(vec![]).unwrap();
+        assert_eq!((0x82, vec![]), read_frame(&mut client));
+        ws.send(vec![0; 300]).unwrap();
+        assert_eq!(75, (0..75).map(|_| read_frame(&mut client)).count());
+
+        // dropping it closes the connection
+        drop(ws);
+        assert_eq!((0x88, vec![0x03, 0xe8]), read_frame(&mut client));
+    }
+
+    #[test]
+    fn protocol_errors_close_the_connection() {
+        let cases: [(Vec<u8>, u16); 5] = [
+            (masked(true, OpCode::Continuation, b"x"), 1002),
+            (
+                [masked(false, OpCode::Text, b"a"), masked(true, OpCode::Text, b"b")].concat(),
+                1002,
+            ),
+            (masked(true, OpCode::Text, &[0xff, 0xfe]), 1007),
+            (masked(true, OpCode::Binary, &[0; 100]), 1009),
+            (masked(true, OpCode::Close, &[0x03, 0xed]), 1002),
+        ];
+
+        for (sent, code) in cases {
+            let (mut ws, mut client) = connected();
+            ws.set_max_message_size(64);
+            client.write_all(&sent).unwrap();
+
+            match ws.recv() {
+                Err(WebSocketError::Protocol(closed_with, _)) => assert_eq!(code, closed_with.code()),
+                other => panic!("expected a protocol error, got {:?}", other),
+            }
+            let (first, payload) = read_frame(&mut client);
+            assert_eq!(0x88, first);
+            assert_eq!(code.to_be_bytes(), payload[..2]);
+        }
+    }
+
+    #[test]
+    fn recv_timeout_keeps_partial_frames() {
+        let (mut ws, mut client) = connected();
+        let frame = masked(true, OpCode::Text, b"slowly");
+
+        client.write_all(&frame[..4]).unwrap();
+        assert!(matches!(
+            ws.recv_timeout(Duration::from_millis(50)),
+            Err(WebSocketError::Timeout)
+        ));
+        client.write_all(&frame[4..]).unwrap();
+        assert_eq!(
+            Some(Message::from("slowly")),
+            ws.recv_timeout(Duration::from_secs(5)).unwrap()
+        );
+    }
+
+    #[test]
+    fn quiet_clients_are_closed() {
+        let (mut ws, mut client) = connected();
+        ws.set_idle_timeout(Some(Duration::from_millis(50)));
+
+        // a message, then nothing
+        client.write_all(&masked(true, OpCode::Text, b"hi")).unwrap();
+        assert_eq!(Some(Message::from("hi")), ws.recv().unwrap());
+        match ws.recv() {
+            Err(WebSocketError::Protocol(CloseCode::GoingAway, _)) => {}
+            other => panic!("expected to go away, got {:?}", other),
+        }
+        let (first, payload) = read_frame(&mut client);
+        assert_eq!(0x88, first);
+        assert_eq!(1001u16.to_be_bytes(), payload[..2]);
+    }
+
+    #[test]
+    fn echo_over_the_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut router: Router<Handler> = Router::new();
+        router.get(
+            "/echo",
+            WebSocket::handler(|mut ws| {
+                while let Ok(Some(message)) = ws.recv() {
+                    ws.send(message).unwrap();
+                }
+            }),
+        );
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(stream, &router, &ConnectionConfig::default()).unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // the first frame comes along with the handshake, before we know it was accepted
+        let mut sent = b"GET /echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+            .to_vec();
+        sent.extend(masked(true, OpCode::Text, b"hi"));
+        client.write_all(&sent).unwrap();
+
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            client.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(head.contains("Connection: Upgrade\r\n"));
+        assert!(!head.contains("Content-Length"));
+
+        assert_eq!((0x81, b"hi".to_vec()), read_frame(&mut client));
+        client.write_all(&masked(true, OpCode::Close, b"")).unwrap();
+        assert_eq!((0x88, vec![]), read_frame(&mut client));
+
+        // then the server closes the connection
+        server.join().unwrap();
+        assert_eq!(0, client.read(&mut [0]).unwrap());
+    }
+}
@@ -0,0 +1,239 @@
+//! How messages are cut into frames on the wire (RFC 6455, section 5.2).
+
+use std::convert::TryInto;
+use std::io::{self, Write};
+
+use super::CloseCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum OpCode {
+    /// the next fragment of a message started by a Text or Binary frame
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    fn from_u8(value: u8) -> Option<OpCode> {
+        match value {
+            0x0 => Some(OpCode::Continuation),
+            0x1 => Some(OpCode::Text),
+            0x2 => Some(OpCode::Binary),
+            0x8 => Some(OpCode::Close),
+            0x9 => Some(OpCode::Ping),
+            0xA => Some(OpCode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+        }
+    }
+
+    /// control frames may come between the fragments of a message
+    pub(super) fn is_control(self) -> bool {
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct Frame {
+    /// the last fragment of its message
+    pub(super) fin: bool,
+    pub(super) opcode: OpCode,
+    pub(super) payload: Vec<u8>,
+}
+
+/// A frame that breaks the protocol; the connection has to be closed with `code`.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct FrameError {
+    pub(super) code: CloseCode,
+    pub(super) reason: &'static str,
+}
+
+fn protocol_error(reason: &'static str) -> FrameError {
+    FrameError {
+        code: CloseCode::ProtocolError,
+        reason,
+    }
+}
+
+/// Takes the first frame a client sent out of `buf`, and says how many bytes it was.
+///
+/// Returns `Ok(None)` while the frame isn't all there yet. Client frames are always
+/// masked, the payload we return is unmasked already. Frames with a payload over
+/// `max_payload` bytes are refused as soon as we see their length.
+pub(super) fn parse(buf: &[u8], max_payload: usize) -> Result<Option<(Frame, usize)>, FrameError> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buf[0] & 0x80 != 0;
+    // the bits for extensions, and we didn't agree on any
+    if buf[0] & 0x70 != 0 {
+        return Err(protocol_error("reserved bits set without an extension"));
+    }
+    let opcode = OpCode::from_u8(buf[0] & 0x0f).ok_or_else(|| protocol_error("unknown opcode"))?;
+    if buf[1] & 0x80 == 0 {
+        return Err(protocol_error("client frames must be masked"));
+    }
+
+    // lengths under 126 fit in the 7 bits, otherwise they take the next 2 or 8 bytes
+    let (len, mut at) = match buf[1] & 0x7f {
+        126 => match buf.get(2..4) {
+            Some(bytes) => (u16::from_be_bytes([bytes[0], bytes[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10) {
+            Some(bytes) => {
+                let len = u64::from_be_bytes(bytes.try_into().unwrap());
+                if len >> 63 != 0 {
+                    return Err(protocol_error("frame length with the most significant bit set"));
+                }
+                (len, 10)
+            }
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+
+    if opcode.is_control() && (len > 125 || !fin) {
+        return Err(protocol_error("control frames must be short and not fragmented"));
+    }
+    if len > max_payload as u64 {
+        return Err(FrameError {
+            code: CloseCode::MessageTooBig,
+            reason: "the message is too big",
+        });
+    }
+    let len = len as usize;
+
+    let mask = match buf.get(at..at + 4) {
+        Some(mask) => [mask[0], mask[1], mask[2], mask[3]],
+        None => return Ok(None),
+    };
+    at += 4;
+    let mut payload = match buf.get(at..at + len) {
+        Some(payload) => payload.to_vec(),
+        None => return Ok(None),
+    };
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Some((Frame { fin, opcode, payload }, at + len)))
+}
+
+/// Writes a frame the way a server does, without a mask.
+pub(super) fn write(out: &mut impl Write, fin: bool, opcode: OpCode, payload: &[u8]) -> io::Result<()> {
+    let mut head = Vec::with_capacity(10);
+    head.push(if fin { 0x80 } else { 0 } | opcode.as_u8());
+    match payload.len() {
+        len if len < 126 => head.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            head.push(126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    out.write_all(&head)?;
+    out.write_all(payload)?;
+    out.flush()
+}
+
+/// A frame the way a client sends it, masked.
+#[cfg(test)]
+pub(super) fn masked(fin: bool, opcode: OpCode, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::new();
+    write(&mut frame, fin, opcode, payload).unwrap();
+
+    let mask = [0x37, 0xfa, 0x21, 0x3d];
+    let at = frame.len() - payload.len();
+    frame[1] |= 0x80;
+    for (i, byte) in frame[at..].iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    frame.splice(at..at, mask);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unmasks_the_rfc_example() {
+        // a single-frame masked text message containing "Hello" (RFC 6455, section 5.7)
+        let buf = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let frame = Frame {
+            fin: true,
+            opcode: OpCode::Text,
+            payload: b"Hello".to_vec(),
+        };
+        assert_eq!(Ok(Some((frame, 11))), parse(&buf, 1024));
+        assert_eq!(buf.to_vec(), masked(true, OpCode::Text, b"Hello"));
+
+        // not all there yet
+        for end in 0..buf.len() {
+            assert_eq!(Ok(None), parse(&buf[..end], 1024));
+        }
+    }
+
+    #[test]
+    fn lengths_of_7_16_and_64_bits() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let payload = vec![b'x'; len];
+            let buf = masked(false, OpCode::Binary, &payload);
+            let (frame, used) = parse(&buf, 1 << 20).unwrap().unwrap();
+            assert_eq!(buf.len(), used);
+            assert_eq!(payload, frame.payload);
+            assert!(!frame.fin);
+        }
+
+        let mut out = Vec::new();
+        write(&mut out, true, OpCode::Binary, &[0; 300]).unwrap();
+        assert_eq!([0x82, 126, 0x01, 0x2c], out[..4]);
+    }
+
+    #[test]
+    fn broken_frames() {
+        let error = |buf: &[u8]| parse(buf, 1024).unwrap_err();
+
+        let mut unmasked = Vec::new();
+        write(&mut unmasked, true, OpCode::Text, b"hi").unwrap();
+        assert_eq!(protocol_error("client frames must be masked"), error(&unmasked));
+
+        let mut reserved = masked(true, OpCode::Text, b"hi");
+        reserved[0] |= 0x40;
+        assert_eq!(CloseCode::ProtocolError, error(&reserved).code);
+
+        let mut unknown = masked(true, OpCode::Text, b"hi");
+        unknown[0] = 0x83;
+        assert_eq!(protocol_error("unknown opcode"), error(&unknown));
+
+        assert_eq!(CloseCode::ProtocolError, error(&masked(false, OpCode::Ping, b"")).code);
+        assert_eq!(
+            CloseCode::ProtocolError,
+            error(&masked(true, OpCode::Ping, &[0; 126])).code
+        );
+        // refused before the payload comes in
+        assert_eq!(
+            CloseCode::MessageTooBig,
+            error(&masked(true, OpCode::Binary, &[0; 2000])[..4]).code
+        );
+    }
+}